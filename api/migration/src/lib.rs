@@ -1,9 +1,30 @@
 pub use sea_orm_migration::prelude::*;
 
+mod m10_create_messages_thread_index;
+mod m11_add_message_search;
+mod m12_create_message_reactions_table;
+mod m13_create_read_markers_table;
+mod m14_add_users_last_seen;
+mod m15_create_ws_events_table;
+mod m16_create_login_failures_table;
+mod m17_create_audit_log_table;
+mod m18_create_totp_tables;
+mod m19_restrict_directory_parent_delete;
 mod m1_create_users_table;
+mod m20_add_directory_position;
+mod m21_create_attachments_table;
+mod m22_create_mentions_table;
+mod m23_create_ws_connections_table;
+mod m24_add_sessions_previous_refresh_token_hash;
 mod m2_create_directory_table;
 mod m3_create_messages_table;
+mod m4_create_sessions_table;
+mod m5_create_roles_tables;
+mod m6_create_directory_members_table;
+mod m7_add_dm_directory_type;
+mod m8_create_message_revisions_table;
 mod m99_seed;
+mod m9_add_message_tombstones;
 
 pub struct Migrator;
 
@@ -14,6 +35,27 @@ impl MigratorTrait for Migrator {
 			Box::new(m1_create_users_table::Migration),
 			Box::new(m2_create_directory_table::Migration),
 			Box::new(m3_create_messages_table::Migration),
+			Box::new(m4_create_sessions_table::Migration),
+			Box::new(m5_create_roles_tables::Migration),
+			Box::new(m6_create_directory_members_table::Migration),
+			Box::new(m7_add_dm_directory_type::Migration),
+			Box::new(m8_create_message_revisions_table::Migration),
+			Box::new(m9_add_message_tombstones::Migration),
+			Box::new(m10_create_messages_thread_index::Migration),
+			Box::new(m11_add_message_search::Migration),
+			Box::new(m12_create_message_reactions_table::Migration),
+			Box::new(m13_create_read_markers_table::Migration),
+			Box::new(m14_add_users_last_seen::Migration),
+			Box::new(m15_create_ws_events_table::Migration),
+			Box::new(m16_create_login_failures_table::Migration),
+			Box::new(m17_create_audit_log_table::Migration),
+			Box::new(m18_create_totp_tables::Migration),
+			Box::new(m19_restrict_directory_parent_delete::Migration),
+			Box::new(m20_add_directory_position::Migration),
+			Box::new(m21_create_attachments_table::Migration),
+			Box::new(m22_create_mentions_table::Migration),
+			Box::new(m23_create_ws_connections_table::Migration),
+			Box::new(m24_add_sessions_previous_refresh_token_hash::Migration),
 			Box::new(m99_seed::Migration),
 		]
 	}
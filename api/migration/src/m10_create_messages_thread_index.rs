@@ -0,0 +1,33 @@
+use crate::m3_create_messages_table::Messages;
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_messages_directory_id_created_at_id")
+					.table(Messages::Table)
+					.col(Messages::DirectoryId)
+					.col(Messages::CreatedAt)
+					.col(Messages::Id)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_index(
+				Index::drop()
+					.name("idx_messages_directory_id_created_at_id")
+					.table(Messages::Table)
+					.to_owned(),
+			)
+			.await
+	}
+}
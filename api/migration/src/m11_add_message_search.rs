@@ -0,0 +1,35 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// Generated columns are not expressible through the schema builder
+		manager
+			.get_connection()
+			.execute_unprepared(
+				"ALTER TABLE messages
+					ADD COLUMN IF NOT EXISTS content_tsv tsvector
+					GENERATED ALWAYS AS (to_tsvector('english', content)) STORED;
+				CREATE INDEX IF NOT EXISTS idx_messages_content_tsv
+					ON messages USING GIN (content_tsv)",
+			)
+			.await?;
+
+		Ok(())
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.get_connection()
+			.execute_unprepared(
+				"DROP INDEX IF EXISTS idx_messages_content_tsv;
+				ALTER TABLE messages DROP COLUMN IF EXISTS content_tsv",
+			)
+			.await?;
+
+		Ok(())
+	}
+}
@@ -0,0 +1,59 @@
+use crate::m1_create_users_table::Users;
+use crate::m3_create_messages_table::Messages;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(MessageReactions::Table)
+					.if_not_exists()
+					.col(integer(MessageReactions::MessageId))
+					.col(string(MessageReactions::Username))
+					.col(string(MessageReactions::Emoji))
+					.col(timestamp_with_time_zone(MessageReactions::CreatedAt))
+					.primary_key(
+						Index::create()
+							.col(MessageReactions::MessageId)
+							.col(MessageReactions::Username)
+							.col(MessageReactions::Emoji),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(MessageReactions::Table, MessageReactions::MessageId)
+							.to(Messages::Table, Messages::Id)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(MessageReactions::Table, MessageReactions::Username)
+							.to(Users::Table, Users::Username)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(MessageReactions::Table).to_owned())
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+pub enum MessageReactions {
+	Table,
+	MessageId,
+	Username,
+	Emoji,
+	CreatedAt,
+}
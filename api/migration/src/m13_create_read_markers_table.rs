@@ -0,0 +1,66 @@
+use crate::m1_create_users_table::Users;
+use crate::m2_create_directory_table::Directory;
+use crate::m3_create_messages_table::Messages;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(ReadMarkers::Table)
+					.if_not_exists()
+					.col(string(ReadMarkers::Username))
+					.col(integer(ReadMarkers::DirectoryId))
+					.col(integer(ReadMarkers::LastReadMessageId))
+					.col(timestamp_with_time_zone(ReadMarkers::UpdatedAt))
+					.primary_key(
+						Index::create()
+							.col(ReadMarkers::Username)
+							.col(ReadMarkers::DirectoryId),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(ReadMarkers::Table, ReadMarkers::Username)
+							.to(Users::Table, Users::Username)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(ReadMarkers::Table, ReadMarkers::DirectoryId)
+							.to(Directory::Table, Directory::Id)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(ReadMarkers::Table, ReadMarkers::LastReadMessageId)
+							.to(Messages::Table, Messages::Id)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(ReadMarkers::Table).to_owned())
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+pub enum ReadMarkers {
+	Table,
+	Username,
+	DirectoryId,
+	LastReadMessageId,
+	UpdatedAt,
+}
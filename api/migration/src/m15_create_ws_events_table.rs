@@ -0,0 +1,48 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// Sequences are not expressible through the schema builder
+		manager
+			.get_connection()
+			.execute_unprepared("CREATE SEQUENCE IF NOT EXISTS ws_event_seq")
+			.await?;
+
+		manager
+			.create_table(
+				Table::create()
+					.table(WsEvents::Table)
+					.if_not_exists()
+					.col(big_integer(WsEvents::Seq).primary_key())
+					.col(text(WsEvents::Envelope))
+					.col(timestamp_with_time_zone(WsEvents::CreatedAt))
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(WsEvents::Table).to_owned())
+			.await?;
+
+		manager
+			.get_connection()
+			.execute_unprepared("DROP SEQUENCE IF EXISTS ws_event_seq")
+			.await?;
+
+		Ok(())
+	}
+}
+
+#[derive(DeriveIden)]
+pub enum WsEvents {
+	Table,
+	Seq,
+	Envelope,
+	CreatedAt,
+}
@@ -0,0 +1,45 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// Not tied to users, since attempts against usernames that do not exist are tracked too
+		manager
+			.create_table(
+				Table::create()
+					.table(LoginFailures::Table)
+					.if_not_exists()
+					.col(string(LoginFailures::Scope))
+					.col(string(LoginFailures::Key))
+					.col(integer(LoginFailures::Failures))
+					.col(timestamp_with_time_zone(LoginFailures::LastFailedAt))
+					.col(timestamp_with_time_zone_null(LoginFailures::LockedUntil))
+					.primary_key(
+						Index::create()
+							.col(LoginFailures::Scope)
+							.col(LoginFailures::Key),
+					)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(LoginFailures::Table).to_owned())
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+pub enum LoginFailures {
+	Table,
+	Scope,
+	Key,
+	Failures,
+	LastFailedAt,
+	LockedUntil,
+}
@@ -0,0 +1,55 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// Entries outlive the users they mention, so usernames are not foreign keys
+		manager
+			.create_table(
+				Table::create()
+					.table(AuditLog::Table)
+					.if_not_exists()
+					.col(pk_auto(AuditLog::Id))
+					.col(string(AuditLog::Event))
+					.col(string_null(AuditLog::Username))
+					.col(string_null(AuditLog::Actor))
+					.col(string_null(AuditLog::Ip))
+					.col(text_null(AuditLog::Details))
+					.col(timestamp_with_time_zone(AuditLog::CreatedAt))
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_audit_log_username_created_at")
+					.table(AuditLog::Table)
+					.col(AuditLog::Username)
+					.col(AuditLog::CreatedAt)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(AuditLog::Table).to_owned())
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+pub enum AuditLog {
+	Table,
+	Id,
+	Event,
+	Username,
+	Actor,
+	Ip,
+	Details,
+	CreatedAt,
+}
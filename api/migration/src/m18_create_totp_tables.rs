@@ -0,0 +1,90 @@
+use crate::m1_create_users_table::Users;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(UserTotp::Table)
+					.if_not_exists()
+					.col(string(UserTotp::Username).primary_key())
+					.col(string(UserTotp::Secret))
+					.col(timestamp_with_time_zone_null(UserTotp::EnabledAt))
+					.col(big_integer_null(UserTotp::LastUsedStep))
+					.col(timestamp_with_time_zone(UserTotp::CreatedAt))
+					.foreign_key(
+						ForeignKey::create()
+							.from(UserTotp::Table, UserTotp::Username)
+							.to(Users::Table, Users::Username)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_table(
+				Table::create()
+					.table(RecoveryCodes::Table)
+					.if_not_exists()
+					.col(pk_auto(RecoveryCodes::Id))
+					.col(string(RecoveryCodes::Username))
+					.col(string(RecoveryCodes::CodeHash))
+					.col(timestamp_with_time_zone_null(RecoveryCodes::UsedAt))
+					.foreign_key(
+						ForeignKey::create()
+							.from(RecoveryCodes::Table, RecoveryCodes::Username)
+							.to(Users::Table, Users::Username)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_recovery_codes_username")
+					.table(RecoveryCodes::Table)
+					.col(RecoveryCodes::Username)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(RecoveryCodes::Table).to_owned())
+			.await?;
+
+		manager
+			.drop_table(Table::drop().table(UserTotp::Table).to_owned())
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+pub enum UserTotp {
+	Table,
+	Username,
+	Secret,
+	EnabledAt,
+	LastUsedStep,
+	CreatedAt,
+}
+
+#[derive(DeriveIden)]
+pub enum RecoveryCodes {
+	Table,
+	Id,
+	Username,
+	CodeHash,
+	UsedAt,
+}
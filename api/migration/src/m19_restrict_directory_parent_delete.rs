@@ -0,0 +1,38 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// Deleting a node that still has children used to turn them into roots. The API now
+		// deals with children explicitly, so refuse it in the database. NO ACTION rather than
+		// RESTRICT still lets a whole subtree be deleted in one statement.
+		manager
+			.get_connection()
+			.execute_unprepared(
+				"ALTER TABLE directory
+					DROP CONSTRAINT IF EXISTS directory_parent_id_fkey,
+					ADD CONSTRAINT directory_parent_id_fkey FOREIGN KEY (parent_id)
+						REFERENCES directory (id) ON UPDATE CASCADE ON DELETE NO ACTION",
+			)
+			.await?;
+
+		Ok(())
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.get_connection()
+			.execute_unprepared(
+				"ALTER TABLE directory
+					DROP CONSTRAINT IF EXISTS directory_parent_id_fkey,
+					ADD CONSTRAINT directory_parent_id_fkey FOREIGN KEY (parent_id)
+						REFERENCES directory (id) ON UPDATE CASCADE ON DELETE SET NULL",
+			)
+			.await?;
+
+		Ok(())
+	}
+}
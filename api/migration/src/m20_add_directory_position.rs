@@ -0,0 +1,59 @@
+use crate::m2_create_directory_table::Directory;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Directory::Table)
+					.add_column(integer(OrderedDirectory::Position).default(0))
+					.to_owned(),
+			)
+			.await?;
+
+		// Keep the order siblings had so far, which was by id
+		manager
+			.get_connection()
+			.execute_unprepared(
+				"UPDATE directory SET position = ordered.position
+				FROM (
+					SELECT id, ROW_NUMBER() OVER (PARTITION BY parent_id ORDER BY id) - 1 AS position
+					FROM directory
+				) AS ordered
+				WHERE directory.id = ordered.id",
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_directory_parent_id_position")
+					.table(Directory::Table)
+					.col(Directory::ParentId)
+					.col(OrderedDirectory::Position)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Directory::Table)
+					.drop_column(OrderedDirectory::Position)
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+enum OrderedDirectory {
+	Position,
+}
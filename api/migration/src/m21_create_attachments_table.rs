@@ -0,0 +1,73 @@
+use crate::m1_create_users_table::Users;
+use crate::m3_create_messages_table::Messages;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(Attachments::Table)
+					.if_not_exists()
+					.col(pk_auto(Attachments::Id))
+					.col(integer_null(Attachments::MessageId))
+					.col(string(Attachments::UploaderUsername))
+					.col(string(Attachments::Filename))
+					.col(string(Attachments::ContentType))
+					.col(big_integer(Attachments::Size))
+					.col(string_uniq(Attachments::StorageKey))
+					.col(string_null(Attachments::ThumbnailKey))
+					.col(timestamp_with_time_zone(Attachments::CreatedAt))
+					.foreign_key(
+						ForeignKey::create()
+							.from(Attachments::Table, Attachments::MessageId)
+							.to(Messages::Table, Messages::Id)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(Attachments::Table, Attachments::UploaderUsername)
+							.to(Users::Table, Users::Username)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_attachments_message_id")
+					.table(Attachments::Table)
+					.col(Attachments::MessageId)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(Attachments::Table).to_owned())
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+pub enum Attachments {
+	Table,
+	Id,
+	MessageId,
+	UploaderUsername,
+	Filename,
+	ContentType,
+	Size,
+	StorageKey,
+	ThumbnailKey,
+	CreatedAt,
+}
@@ -0,0 +1,82 @@
+use crate::m1_create_users_table::Users;
+use crate::m3_create_messages_table::Messages;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(Mentions::Table)
+					.if_not_exists()
+					.col(pk_auto(Mentions::Id))
+					.col(integer(Mentions::MessageId))
+					.col(string(Mentions::Username))
+					.col(string(Mentions::Kind))
+					.col(timestamp_with_time_zone(Mentions::CreatedAt))
+					.col(timestamp_with_time_zone_null(Mentions::ReadAt))
+					.foreign_key(
+						ForeignKey::create()
+							.from(Mentions::Table, Mentions::MessageId)
+							.to(Messages::Table, Messages::Id)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(Mentions::Table, Mentions::Username)
+							.to(Users::Table, Users::Username)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		// A user is mentioned at most once per message, however many ways the message names them
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_mentions_message_id_username")
+					.table(Mentions::Table)
+					.col(Mentions::MessageId)
+					.col(Mentions::Username)
+					.unique()
+					.to_owned(),
+			)
+			.await?;
+
+		// The inbox lists a user's mentions newest first
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_mentions_username_id")
+					.table(Mentions::Table)
+					.col(Mentions::Username)
+					.col(Mentions::Id)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(Mentions::Table).to_owned())
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+pub enum Mentions {
+	Table,
+	Id,
+	MessageId,
+	Username,
+	Kind,
+	CreatedAt,
+	ReadAt,
+}
@@ -0,0 +1,90 @@
+use crate::m1_create_users_table::Users;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(WsNodes::Table)
+					.if_not_exists()
+					.col(pk_auto(WsNodes::Id))
+					.col(timestamp_with_time_zone(WsNodes::HeartbeatAt))
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_table(
+				Table::create()
+					.table(WsConnections::Table)
+					.if_not_exists()
+					.col(integer(WsConnections::NodeId))
+					.col(big_integer(WsConnections::ConnectionId))
+					.col(string(WsConnections::Username))
+					.col(boolean(WsConnections::Idle))
+					.primary_key(
+						Index::create()
+							.col(WsConnections::NodeId)
+							.col(WsConnections::ConnectionId),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(WsConnections::Table, WsConnections::NodeId)
+							.to(WsNodes::Table, WsNodes::Id)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(WsConnections::Table, WsConnections::Username)
+							.to(Users::Table, Users::Username)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		// Presence is looked up by user
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_ws_connections_username")
+					.table(WsConnections::Table)
+					.col(WsConnections::Username)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(WsConnections::Table).to_owned())
+			.await?;
+
+		manager
+			.drop_table(Table::drop().table(WsNodes::Table).to_owned())
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+pub enum WsNodes {
+	Table,
+	Id,
+	HeartbeatAt,
+}
+
+#[derive(DeriveIden)]
+pub enum WsConnections {
+	Table,
+	NodeId,
+	ConnectionId,
+	Username,
+	Idle,
+}
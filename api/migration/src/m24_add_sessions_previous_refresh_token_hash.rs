@@ -0,0 +1,35 @@
+use crate::m4_create_sessions_table::Sessions;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Sessions::Table)
+					.add_column(string_null(RotatedSessions::PreviousRefreshTokenHash))
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Sessions::Table)
+					.drop_column(RotatedSessions::PreviousRefreshTokenHash)
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+enum RotatedSessions {
+	PreviousRefreshTokenHash,
+}
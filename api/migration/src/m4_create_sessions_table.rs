@@ -0,0 +1,65 @@
+use crate::m1_create_users_table::Users;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(Sessions::Table)
+					.if_not_exists()
+					.col(pk_auto(Sessions::Id))
+					.col(string(Sessions::Username))
+					.col(string(Sessions::RefreshTokenHash))
+					.col(string_null(Sessions::DeviceName))
+					.col(string_null(Sessions::Ip))
+					.col(timestamp_with_time_zone(Sessions::CreatedAt))
+					.col(timestamp_with_time_zone(Sessions::LastUsedAt))
+					.col(timestamp_with_time_zone(Sessions::ExpiresAt))
+					.col(timestamp_with_time_zone_null(Sessions::RevokedAt))
+					.foreign_key(
+						ForeignKey::create()
+							.from(Sessions::Table, Sessions::Username)
+							.to(Users::Table, Users::Username)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_sessions_username")
+					.table(Sessions::Table)
+					.col(Sessions::Username)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(Sessions::Table).to_owned())
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+pub enum Sessions {
+	Table,
+	Id,
+	Username,
+	RefreshTokenHash,
+	DeviceName,
+	Ip,
+	CreatedAt,
+	LastUsedAt,
+	ExpiresAt,
+	RevokedAt,
+}
@@ -0,0 +1,144 @@
+use crate::m1_create_users_table::Users;
+use crate::m2_create_directory_table::Directory;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(Roles::Table)
+					.if_not_exists()
+					.col(string(Roles::Name).primary_key())
+					.col(boolean(Roles::CanPost))
+					.col(boolean(Roles::CanCreateNodes))
+					.col(boolean(Roles::CanModerate))
+					.col(boolean(Roles::CanManage))
+					.to_owned(),
+			)
+			.await?;
+
+		let roles_insert = Query::insert()
+			.into_table(Roles::Table)
+			.columns([
+				Roles::Name,
+				Roles::CanPost,
+				Roles::CanCreateNodes,
+				Roles::CanModerate,
+				Roles::CanManage,
+			])
+			.values_from_panic(vec![
+				[
+					"admin".into(),
+					true.into(),
+					true.into(),
+					true.into(),
+					true.into(),
+				],
+				[
+					"moderator".into(),
+					true.into(),
+					true.into(),
+					true.into(),
+					false.into(),
+				],
+				[
+					"member".into(),
+					true.into(),
+					true.into(),
+					false.into(),
+					false.into(),
+				],
+				[
+					"read_only".into(),
+					false.into(),
+					false.into(),
+					false.into(),
+					false.into(),
+				],
+			])
+			.to_owned();
+		manager.exec_stmt(roles_insert).await?;
+
+		manager
+			.create_table(
+				Table::create()
+					.table(DirectoryGrants::Table)
+					.if_not_exists()
+					.col(pk_auto(DirectoryGrants::Id))
+					.col(integer(DirectoryGrants::DirectoryId))
+					.col(string_null(DirectoryGrants::Username))
+					.col(string(DirectoryGrants::Role))
+					.foreign_key(
+						ForeignKey::create()
+							.from(DirectoryGrants::Table, DirectoryGrants::DirectoryId)
+							.to(Directory::Table, Directory::Id)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(DirectoryGrants::Table, DirectoryGrants::Username)
+							.to(Users::Table, Users::Username)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(DirectoryGrants::Table, DirectoryGrants::Role)
+							.to(Roles::Table, Roles::Name)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		// A NULL username is the grant for everyone on that node, so it must be unique too
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_directory_grants_directory_id_username")
+					.table(DirectoryGrants::Table)
+					.col(DirectoryGrants::DirectoryId)
+					.col(DirectoryGrants::Username)
+					.unique()
+					.nulls_not_distinct()
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(DirectoryGrants::Table).to_owned())
+			.await?;
+
+		manager
+			.drop_table(Table::drop().table(Roles::Table).to_owned())
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+pub enum Roles {
+	Table,
+	Name,
+	CanPost,
+	CanCreateNodes,
+	CanModerate,
+	CanManage,
+}
+
+#[derive(DeriveIden)]
+pub enum DirectoryGrants {
+	Table,
+	Id,
+	DirectoryId,
+	Username,
+	Role,
+}
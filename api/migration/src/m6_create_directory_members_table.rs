@@ -0,0 +1,87 @@
+use crate::m1_create_users_table::Users;
+use crate::m2_create_directory_table::Directory;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Directory::Table)
+					.add_column(boolean(PrivateDirectory::IsPrivate).default(false))
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_table(
+				Table::create()
+					.table(DirectoryMembers::Table)
+					.if_not_exists()
+					.col(integer(DirectoryMembers::DirectoryId))
+					.col(string(DirectoryMembers::Username))
+					.primary_key(
+						Index::create()
+							.col(DirectoryMembers::DirectoryId)
+							.col(DirectoryMembers::Username),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(DirectoryMembers::Table, DirectoryMembers::DirectoryId)
+							.to(Directory::Table, Directory::Id)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(DirectoryMembers::Table, DirectoryMembers::Username)
+							.to(Users::Table, Users::Username)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_directory_members_username")
+					.table(DirectoryMembers::Table)
+					.col(DirectoryMembers::Username)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(DirectoryMembers::Table).to_owned())
+			.await?;
+
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Directory::Table)
+					.drop_column(PrivateDirectory::IsPrivate)
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+enum PrivateDirectory {
+	IsPrivate,
+}
+
+#[derive(DeriveIden)]
+pub enum DirectoryMembers {
+	Table,
+	DirectoryId,
+	Username,
+}
@@ -0,0 +1,38 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// DM conversations live outside the public tree, so they are always private roots
+		manager
+			.get_connection()
+			.execute_unprepared(
+				"ALTER TABLE directory
+					DROP CONSTRAINT IF EXISTS directory_type_check,
+					ADD CONSTRAINT directory_type_check CHECK (
+						type IN ('folder', 'thread', 'dm')
+						AND (type <> 'dm' OR (parent_id IS NULL AND is_private))
+					)",
+			)
+			.await?;
+
+		Ok(())
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.get_connection()
+			.execute_unprepared(
+				"DELETE FROM directory WHERE type = 'dm';
+				ALTER TABLE directory
+					DROP CONSTRAINT IF EXISTS directory_type_check,
+					ADD CONSTRAINT directory_type_check CHECK (type IN ('folder', 'thread'))",
+			)
+			.await?;
+
+		Ok(())
+	}
+}
@@ -0,0 +1,88 @@
+use crate::m1_create_users_table::Users;
+use crate::m3_create_messages_table::Messages;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Messages::Table)
+					.add_column(timestamp_with_time_zone_null(EditedMessages::EditedAt))
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_table(
+				Table::create()
+					.table(MessageRevisions::Table)
+					.if_not_exists()
+					.col(pk_auto(MessageRevisions::Id))
+					.col(integer(MessageRevisions::MessageId))
+					.col(string(MessageRevisions::Content))
+					.col(string(MessageRevisions::EditedBy))
+					.col(timestamp_with_time_zone(MessageRevisions::EditedAt))
+					.foreign_key(
+						ForeignKey::create()
+							.from(MessageRevisions::Table, MessageRevisions::MessageId)
+							.to(Messages::Table, Messages::Id)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.from(MessageRevisions::Table, MessageRevisions::EditedBy)
+							.to(Users::Table, Users::Username)
+							.on_delete(ForeignKeyAction::Cascade)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_message_revisions_message_id")
+					.table(MessageRevisions::Table)
+					.col(MessageRevisions::MessageId)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(MessageRevisions::Table).to_owned())
+			.await?;
+
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Messages::Table)
+					.drop_column(EditedMessages::EditedAt)
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+enum EditedMessages {
+	EditedAt,
+}
+
+#[derive(DeriveIden)]
+pub enum MessageRevisions {
+	Table,
+	Id,
+	MessageId,
+	Content,
+	EditedBy,
+	EditedAt,
+}
@@ -0,0 +1,90 @@
+use crate::m1_create_users_table::Users;
+use crate::m3_create_messages_table::Messages;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Messages::Table)
+					.add_column(timestamp_with_time_zone_null(DeletedMessages::DeletedAt))
+					.add_column(string_null(DeletedMessages::DeletedBy))
+					.add_foreign_key(
+						TableForeignKey::new()
+							.name("messages_deleted_by_fkey")
+							.from_tbl(Messages::Table)
+							.from_col(DeletedMessages::DeletedBy)
+							.to_tbl(Users::Table)
+							.to_col(Users::Username)
+							.on_delete(ForeignKeyAction::SetNull)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		// Deleted messages are kept as tombstones, so a parent should never be removed while
+		// replies still point at it; fail loudly instead of silently orphaning them
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Messages::Table)
+					.drop_foreign_key(Alias::new("messages_parent_id_fkey"))
+					.add_foreign_key(
+						TableForeignKey::new()
+							.name("messages_parent_id_fkey")
+							.from_tbl(Messages::Table)
+							.from_col(Messages::ParentId)
+							.to_tbl(Messages::Table)
+							.to_col(Messages::Id)
+							.on_delete(ForeignKeyAction::NoAction)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Messages::Table)
+					.drop_foreign_key(Alias::new("messages_parent_id_fkey"))
+					.add_foreign_key(
+						TableForeignKey::new()
+							.name("messages_parent_id_fkey")
+							.from_tbl(Messages::Table)
+							.from_col(Messages::ParentId)
+							.to_tbl(Messages::Table)
+							.to_col(Messages::Id)
+							.on_delete(ForeignKeyAction::SetNull)
+							.on_update(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Messages::Table)
+					.drop_foreign_key(Alias::new("messages_deleted_by_fkey"))
+					.drop_column(DeletedMessages::DeletedAt)
+					.drop_column(DeletedMessages::DeletedBy)
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(DeriveIden)]
+enum DeletedMessages {
+	DeletedAt,
+	DeletedBy,
+}
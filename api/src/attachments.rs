@@ -0,0 +1,293 @@
+use crate::db;
+use crate::entity::attachments::Model as Attachment;
+use crate::storage::Storage;
+use chrono::{TimeDelta, Utc};
+use image::{ImageFormat, ImageReader};
+use sea_orm::DatabaseConnection;
+use std::{
+	env,
+	io::Cursor,
+	ops::RangeInclusive,
+	sync::{Arc, LazyLock},
+	time::Duration,
+};
+
+const DEFAULT_MAX_SIZE: usize = 25 * 1024 * 1024;
+const DEFAULT_ALLOWED_TYPES: &str =
+	"image/png,image/jpeg,image/gif,image/webp,application/pdf,text/plain,application/zip";
+/// Attachments a single message can carry
+pub const MAX_PER_MESSAGE: usize = 10;
+/// Bounds of the box thumbnails are scaled down to fit in
+const THUMBNAIL_SIZE: u32 = 320;
+/// Images larger than this are not decoded for a thumbnail, whatever their file size
+const MAX_THUMBNAIL_SOURCE_PIXELS: u64 = 50_000_000;
+
+/// How often uploads that were never sent are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_UNSENT_TTL_HOURS: i64 = 24;
+
+/// Room left in a request body for the multipart framing around a file
+const MULTIPART_OVERHEAD: usize = 64 * 1024;
+/// Longest file name kept, in characters
+const MAX_FILENAME_LEN: usize = 255;
+
+/// Largest upload accepted, in bytes, from `ATTACHMENT_MAX_SIZE`.
+pub static MAX_SIZE: LazyLock<usize> = LazyLock::new(|| {
+	env::var("ATTACHMENT_MAX_SIZE")
+		.ok()
+		.and_then(|size| size.trim().parse().ok())
+		.unwrap_or(DEFAULT_MAX_SIZE)
+});
+
+/// How long an upload may wait to be sent with a message before it is deleted, from
+/// `ATTACHMENT_UNSENT_TTL_HOURS`.
+static UNSENT_TTL: LazyLock<TimeDelta> = LazyLock::new(|| {
+	let hours = env::var("ATTACHMENT_UNSENT_TTL_HOURS")
+		.ok()
+		.and_then(|hours| hours.trim().parse().ok())
+		.unwrap_or(DEFAULT_UNSENT_TTL_HOURS);
+	TimeDelta::hours(hours)
+});
+
+/// Comma-separated MIME types accepted for upload, from `ATTACHMENT_ALLOWED_TYPES`. A type
+/// ending in `/*`, such as `image/*`, accepts every subtype.
+static ALLOWED_TYPES: LazyLock<Vec<String>> = LazyLock::new(|| {
+	env::var("ATTACHMENT_ALLOWED_TYPES")
+		.unwrap_or_else(|_| DEFAULT_ALLOWED_TYPES.to_string())
+		.split(',')
+		.map(|content_type| content_type.trim().to_ascii_lowercase())
+		.filter(|content_type| !content_type.is_empty())
+		.collect()
+});
+
+/// Largest request body accepted for an upload, file and multipart framing together.
+pub fn body_limit() -> usize {
+	*MAX_SIZE + MULTIPART_OVERHEAD
+}
+
+/// The last component of a file name a client sent, without control characters, since it ends
+/// up in response headers.
+pub fn sanitize_filename(filename: &str) -> String {
+	let filename: String = filename
+		.rsplit(['/', '\\'])
+		.next()
+		.unwrap_or_default()
+		.chars()
+		.filter(|c| !c.is_control())
+		.take(MAX_FILENAME_LEN)
+		.collect();
+
+	match filename.trim() {
+		"" | "." | ".." => "file".to_string(),
+		filename => filename.to_string(),
+	}
+}
+
+/// The MIME type of `content_type`, without its parameters.
+pub fn essence(content_type: &str) -> String {
+	content_type
+		.split(';')
+		.next()
+		.unwrap_or_default()
+		.trim()
+		.to_ascii_lowercase()
+}
+
+pub fn is_allowed_type(content_type: &str) -> bool {
+	let content_type = essence(content_type);
+	ALLOWED_TYPES
+		.iter()
+		.any(|allowed| match allowed.strip_suffix("/*") {
+			Some(top_level) => content_type
+				.split_once('/')
+				.is_some_and(|(content_top_level, _)| content_top_level == top_level),
+			None => *allowed == content_type,
+		})
+}
+
+fn image_format(content_type: &str) -> Option<ImageFormat> {
+	match essence(content_type).as_str() {
+		"image/png" => Some(ImageFormat::Png),
+		"image/jpeg" => Some(ImageFormat::Jpeg),
+		"image/gif" => Some(ImageFormat::Gif),
+		"image/webp" => Some(ImageFormat::WebP),
+		_ => None,
+	}
+}
+
+/// Whether `data` is really the kind of image `content_type` claims, so that a file cannot be
+/// served as an image it is not. Anything other than a supported image passes.
+pub fn matches_content(content_type: &str, data: &[u8]) -> bool {
+	match image_format(content_type) {
+		Some(format) => image::guess_format(data).is_ok_and(|guessed| guessed == format),
+		None => true,
+	}
+}
+
+/// A PNG preview of a supported image, scaled to fit `THUMBNAIL_SIZE` while keeping its aspect
+/// ratio. Returns `None` for anything else or an image that fails to decode.
+pub fn thumbnail(content_type: &str, data: &[u8]) -> Option<Vec<u8>> {
+	let format = image_format(content_type)?;
+	let reader = ImageReader::with_format(Cursor::new(data), format);
+
+	let (width, height) = reader.into_dimensions().ok()?;
+	if width as u64 * height as u64 > MAX_THUMBNAIL_SOURCE_PIXELS {
+		return None;
+	}
+
+	let image = image::load_from_memory_with_format(data, format).ok()?;
+	let mut png = Vec::new();
+	image
+		.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+		.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+		.ok()?;
+	Some(png)
+}
+
+/// What a `Range` header asks for out of an object of `size` bytes.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+	Full,
+	Partial(RangeInclusive<u64>),
+	Unsatisfiable,
+}
+
+/// Parses a `Range` header. Only single byte ranges are served partially; anything else,
+/// including several ranges at once, gets the whole object, as HTTP allows.
+pub fn parse_range(header: Option<&str>, size: u64) -> RangeRequest {
+	let Some(spec) = header.and_then(|header| header.trim().strip_prefix("bytes=")) else {
+		return RangeRequest::Full;
+	};
+	if spec.contains(',') {
+		return RangeRequest::Full;
+	}
+	let Some((start, end)) = spec.split_once('-') else {
+		return RangeRequest::Full;
+	};
+
+	let range = match (start.trim(), end.trim()) {
+		// The last `suffix` bytes
+		("", suffix) => match suffix.parse::<u64>() {
+			Ok(0) => return RangeRequest::Unsatisfiable,
+			Ok(suffix) => size.saturating_sub(suffix)..=size.saturating_sub(1),
+			Err(_) => return RangeRequest::Full,
+		},
+		(start, "") => match start.parse::<u64>() {
+			Ok(start) => start..=size.saturating_sub(1),
+			Err(_) => return RangeRequest::Full,
+		},
+		(start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+			(Ok(start), Ok(end)) if start <= end => start..=end.min(size.saturating_sub(1)),
+			_ => return RangeRequest::Full,
+		},
+	};
+
+	if size == 0 || *range.start() >= size {
+		RangeRequest::Unsatisfiable
+	} else {
+		RangeRequest::Partial(range)
+	}
+}
+
+/// Removes the stored objects of attachments that are no longer in the database. Failures are
+/// only logged, since the attachments cannot be reached any more either way.
+pub async fn remove_objects(storage: &dyn Storage, attachments: &[Attachment]) {
+	for attachment in attachments {
+		let keys = std::iter::once(&attachment.storage_key).chain(&attachment.thumbnail_key);
+		for key in keys {
+			if let Err(err) = storage.delete(key).await {
+				eprintln!("{err}");
+			}
+		}
+	}
+}
+
+/// Deletes uploads that were never sent with a message once they are older than `UNSENT_TTL`,
+/// checking every `SWEEP_INTERVAL` for as long as the server runs.
+pub async fn sweep_unsent(conn: DatabaseConnection, storage: Arc<dyn Storage>) {
+	let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+	loop {
+		interval.tick().await;
+
+		match db::delete_unsent_attachments(&conn, Utc::now() - *UNSENT_TTL).await {
+			Ok(unsent) => remove_objects(storage.as_ref(), &unsent).await,
+			Err(err) => eprintln!("Failed to sweep unsent attachments: {err}"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn range(header: &str, size: u64) -> RangeRequest {
+		parse_range(Some(header), size)
+	}
+
+	#[test]
+	fn parse_range_serves_single_byte_ranges() {
+		assert_eq!(range("bytes=0-9", 100), RangeRequest::Partial(0..=9));
+		assert_eq!(range(" bytes=10-10 ", 100), RangeRequest::Partial(10..=10));
+		assert_eq!(range("bytes=90-", 100), RangeRequest::Partial(90..=99));
+		// An end past the object is cut to its last byte
+		assert_eq!(range("bytes=50-500", 100), RangeRequest::Partial(50..=99));
+	}
+
+	#[test]
+	fn parse_range_serves_suffix_ranges() {
+		assert_eq!(range("bytes=-10", 100), RangeRequest::Partial(90..=99));
+		assert_eq!(range("bytes=-500", 100), RangeRequest::Partial(0..=99));
+		assert_eq!(range("bytes=-0", 100), RangeRequest::Unsatisfiable);
+	}
+
+	#[test]
+	fn parse_range_refuses_unsatisfiable_ranges() {
+		assert_eq!(range("bytes=100-", 100), RangeRequest::Unsatisfiable);
+		assert_eq!(range("bytes=100-200", 100), RangeRequest::Unsatisfiable);
+		assert_eq!(range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+		assert_eq!(range("bytes=-10", 0), RangeRequest::Unsatisfiable);
+	}
+
+	#[test]
+	fn parse_range_serves_everything_otherwise() {
+		assert_eq!(parse_range(None, 100), RangeRequest::Full);
+		for header in [
+			"items=0-9",
+			"bytes=0-9,20-29",
+			"bytes=9-0",
+			"bytes=a-9",
+			"bytes=0-b",
+			"bytes=-c",
+			"bytes=5",
+			"bytes=-",
+		] {
+			assert_eq!(range(header, 100), RangeRequest::Full, "{header:?}");
+		}
+	}
+
+	#[test]
+	fn sanitize_filename_keeps_only_the_last_component() {
+		assert_eq!(sanitize_filename("report.pdf"), "report.pdf");
+		assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+		assert_eq!(sanitize_filename("C:\\Users\\me\\photo.png"), "photo.png");
+		assert_eq!(sanitize_filename("  spaced out.txt  "), "spaced out.txt");
+	}
+
+	#[test]
+	fn sanitize_filename_drops_control_characters() {
+		assert_eq!(sanitize_filename("a\nb\0c\u{7f}.txt"), "abc.txt");
+	}
+
+	#[test]
+	fn sanitize_filename_replaces_empty_names() {
+		for filename in ["", "   ", ".", "..", "dir/", "dir/..", "\u{1b}"] {
+			assert_eq!(sanitize_filename(filename), "file", "{filename:?}");
+		}
+	}
+
+	#[test]
+	fn sanitize_filename_limits_length() {
+		let long = "é".repeat(MAX_FILENAME_LEN + 10);
+		assert_eq!(sanitize_filename(&long).chars().count(), MAX_FILENAME_LEN);
+	}
+}
@@ -1,35 +1,128 @@
+use crate::AppState;
 use crate::db;
 use crate::entity;
+use crate::totp;
 use anyhow::{Context, Error, Result};
 use axum::{
-	extract::{Query, Request},
-	http::{HeaderMap, StatusCode},
+	extract::{Query, Request, State},
+	http::{HeaderMap, StatusCode, header},
 	middleware::Next,
 	response::Response,
 };
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use bcrypt::BcryptError;
-use entity::users::Model as User;
+use chrono::{Duration, Utc};
+use entity::{sessions::Model as Session, users::Model as User};
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use rand::RngCore;
 use sea_orm::{DatabaseConnection, DbErr};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{collections::HashMap, env};
 
-#[derive(Serialize, Deserialize)]
-struct Claims {
+const ACCESS_TOKEN_LIFETIME: Duration = Duration::minutes(15);
+const REFRESH_TOKEN_LIFETIME: Duration = Duration::days(30);
+/// How stale the last use of a session may get before a request records it again
+const SESSION_TOUCH_INTERVAL: Duration = Duration::minutes(1);
+/// How long after the password is checked the second factor may be given
+const MFA_TOKEN_LIFETIME: Duration = Duration::minutes(5);
+const RECOVERY_CODE_COUNT: usize = 10;
+
+const LOGIN_SCOPE_USERNAME: &str = "username";
+const LOGIN_SCOPE_IP: &str = "ip";
+/// Failed logins a username gets before being locked out
+const USERNAME_FAILURE_THRESHOLD: i32 = 5;
+/// Higher than for usernames, since many users can share an IP
+const IP_FAILURE_THRESHOLD: i32 = 20;
+/// Failures further apart than this start the count over
+const FAILURE_WINDOW: Duration = Duration::hours(24);
+const BASE_LOCKOUT: Duration = Duration::seconds(30);
+const MAX_LOCKOUT: Duration = Duration::hours(1);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Claims {
 	pub sub: String, // username
+	pub sid: i32,    // session id
 	pub exp: usize,  // expiration time
 }
 
+/// Stands in for the password between the two steps of a login with a second factor. Access
+/// tokens lack `mfa_pending`, so neither kind of token can pass for the other.
+#[derive(Serialize, Deserialize)]
+struct MfaClaims {
+	sub: String,
+	mfa_pending: bool,
+	exp: usize,
+}
+
 #[derive(Deserialize)]
 pub struct Credentials {
 	pub username: String,
 	pub password: String,
 }
 
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+	pub refresh_token: String,
+}
+
+#[derive(Serialize)]
+pub struct Tokens {
+	pub token: String,
+	pub refresh_token: String,
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+	#[serde(flatten)]
+	pub session: Session,
+	pub current: bool,
+}
+
 #[derive(Serialize)]
 pub struct AuthResponse {
 	pub user: User,
-	pub token: String,
+	#[serde(flatten)]
+	pub tokens: Tokens,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+	Authenticated(AuthResponse),
+	/// The password was right, and the second factor is to be sent along with this token
+	MfaRequired {
+		mfa_token: String,
+	},
+}
+
+#[derive(Deserialize)]
+pub struct MfaLoginRequest {
+	pub mfa_token: String,
+	/// A TOTP code or a recovery code
+	pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct MfaCodeRequest {
+	pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrollment {
+	pub secret: String,
+	pub otpauth_uri: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+	pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct MfaStatus {
+	pub totp_enabled: bool,
+	pub recovery_codes_remaining: u64,
 }
 
 pub fn hash_password(password: &str) -> Result<String, BcryptError> {
@@ -40,28 +133,56 @@ fn verify_password(password: &str, hash: &str) -> Result<bool, BcryptError> {
 	bcrypt::verify(password, hash)
 }
 
-pub fn generate_token(username: &str) -> Result<String> {
-	let jwt_secret = env::var("JWT_SECRET").context("JWT_SECRET must be set")?;
-
-	let expiration = chrono::Utc::now()
-		.checked_add_signed(chrono::Duration::days(30))
+fn expiration_after(lifetime: Duration) -> Result<usize> {
+	Ok(Utc::now()
+		.checked_add_signed(lifetime)
 		.context("Failed to calculate expiration time")?
-		.timestamp() as usize;
+		.timestamp() as usize)
+}
 
-	let claims = Claims {
-		sub: username.to_string(),
-		exp: expiration,
-	};
+fn encode_claims(claims: &impl Serialize) -> Result<String> {
+	let jwt_secret = env::var("JWT_SECRET").context("JWT_SECRET must be set")?;
 
 	let token = encode(
 		&Header::default(),
-		&claims,
+		claims,
 		&EncodingKey::from_secret(jwt_secret.as_ref()),
 	)?;
 	Ok(token)
 }
 
-fn validate_token(token: &str) -> Result<Claims> {
+fn generate_token(username: &str, session_id: i32) -> Result<String> {
+	encode_claims(&Claims {
+		sub: username.to_string(),
+		sid: session_id,
+		exp: expiration_after(ACCESS_TOKEN_LIFETIME)?,
+	})
+}
+
+fn generate_mfa_token(username: &str) -> Result<String> {
+	encode_claims(&MfaClaims {
+		sub: username.to_string(),
+		mfa_pending: true,
+		exp: expiration_after(MFA_TOKEN_LIFETIME)?,
+	})
+}
+
+/// Returns the username whose password was checked, if the token is valid.
+fn validate_mfa_token(token: &str) -> Option<String> {
+	let jwt_secret = env::var("JWT_SECRET").ok()?;
+
+	let claims: MfaClaims = decode(
+		token,
+		&DecodingKey::from_secret(jwt_secret.as_ref()),
+		&Validation::default(),
+	)
+	.ok()?
+	.claims;
+
+	claims.mfa_pending.then_some(claims.sub)
+}
+
+pub fn validate_token(token: &str) -> Result<Claims> {
 	let jwt_secret = env::var("JWT_SECRET").context("JWT_SECRET must be set")?;
 
 	let token_data = decode(
@@ -73,6 +194,125 @@ fn validate_token(token: &str) -> Result<Claims> {
 	Ok(token_data.claims)
 }
 
+fn generate_refresh_secret() -> String {
+	let mut bytes = [0u8; 32];
+	rand::rng().fill_bytes(&mut bytes);
+	URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Hashes refresh token secrets and recovery codes, which are random enough not to need a slow
+/// hash.
+fn hash_secret(secret: &str) -> String {
+	format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// Refresh tokens have the form `<session id>.<secret>`; only a hash of the secret is stored.
+fn parse_refresh_token(refresh_token: &str) -> Option<(i32, &str)> {
+	let (session_id, secret) = refresh_token.split_once('.')?;
+	Some((session_id.parse().ok()?, secret))
+}
+
+pub fn device_name_from_headers(headers: &HeaderMap) -> Option<String> {
+	headers
+		.get(header::USER_AGENT)?
+		.to_str()
+		.ok()
+		.map(|user_agent| user_agent.chars().take(255).collect())
+}
+
+pub async fn create_session(
+	db: &DatabaseConnection,
+	username: &str,
+	device_name: Option<String>,
+	ip: Option<String>,
+) -> Result<Tokens> {
+	let secret = generate_refresh_secret();
+	let expires_at = Utc::now() + REFRESH_TOKEN_LIFETIME;
+
+	let session = db::create_session(
+		db,
+		username,
+		hash_secret(&secret),
+		device_name,
+		ip,
+		expires_at,
+	)
+	.await?;
+
+	Ok(Tokens {
+		token: generate_token(username, session.id)?,
+		refresh_token: format!("{}.{secret}", session.id),
+	})
+}
+
+/// What came of presenting a refresh token.
+pub enum RefreshOutcome {
+	Refreshed(Tokens),
+	Rejected,
+	/// A refresh token that had already been rotated away was presented, so it was copied and
+	/// the session has been revoked
+	Reused {
+		username: String,
+		session_id: i32,
+	},
+}
+
+/// Exchanges a refresh token for a new token pair, rotating the refresh token.
+///
+/// Presenting the refresh token that was rotated away last revokes the whole session, since it
+/// means the token was copied. Any other wrong token is only rejected, so that guessing at
+/// session ids cannot log anyone out.
+pub async fn refresh_session(
+	db: &DatabaseConnection,
+	refresh_token: &str,
+	ip: Option<String>,
+) -> Result<RefreshOutcome> {
+	let Some((session_id, secret)) = parse_refresh_token(refresh_token) else {
+		return Ok(RefreshOutcome::Rejected);
+	};
+
+	let session = match db::get_session(db, session_id).await {
+		Ok(session) => session,
+		Err(DbErr::RecordNotFound(_)) => return Ok(RefreshOutcome::Rejected),
+		Err(err) => return Err(Error::from(err)),
+	};
+
+	if session.revoked_at.is_some() || session.expires_at < Utc::now() {
+		return Ok(RefreshOutcome::Rejected);
+	}
+
+	let presented_hash = hash_secret(secret);
+	if session.refresh_token_hash != presented_hash {
+		if session.previous_refresh_token_hash.as_ref() != Some(&presented_hash) {
+			return Ok(RefreshOutcome::Rejected);
+		}
+		db::revoke_session(db, session.id).await?;
+		return Ok(RefreshOutcome::Reused {
+			username: session.username,
+			session_id: session.id,
+		});
+	}
+
+	let secret = generate_refresh_secret();
+	let expires_at = Utc::now() + REFRESH_TOKEN_LIFETIME;
+	let (username, session_id) = (session.username.clone(), session.id);
+	let Some(session) =
+		db::rotate_session(db, session, hash_secret(&secret), ip, expires_at).await?
+	else {
+		// The token was rotated by a concurrent refresh, so it has been presented twice
+		db::revoke_session(db, session_id).await?;
+		return Ok(RefreshOutcome::Reused {
+			username,
+			session_id,
+		});
+	};
+
+	Ok(RefreshOutcome::Refreshed(Tokens {
+		token: generate_token(&session.username, session.id)?,
+		refresh_token: format!("{}.{secret}", session.id),
+	}))
+}
+
 fn extract_token_from_header(headers: &HeaderMap) -> Option<String> {
 	headers
 		.get("Authorization")?
@@ -86,22 +326,313 @@ fn extract_token_from_query(query: &HashMap<String, String>) -> Option<String> {
 	query.get("token").cloned()
 }
 
+/// What came of a login attempt.
+pub enum LoginOutcome {
+	Authenticated(User),
+	/// The password was right, but a second factor has to be given as well
+	MfaRequired {
+		mfa_token: String,
+	},
+	Rejected,
+	/// Too many recent failures for the username or the source IP; nothing was checked
+	LockedOut(std::time::Duration),
+}
+
+/// What came of checking a second factor.
+pub enum CodeCheck {
+	Accepted,
+	Rejected,
+	LockedOut(std::time::Duration),
+}
+
+/// Whether `key` is currently locked out, and for how long if so.
+async fn lockout_remaining(
+	db: &DatabaseConnection,
+	scope: &str,
+	key: &str,
+) -> Result<Option<Duration>> {
+	let now = Utc::now();
+	Ok(db::get_login_failures(db, scope, key)
+		.await?
+		.and_then(|failures| failures.locked_until)
+		.map(|locked_until| locked_until.to_utc() - now)
+		.filter(|remaining| *remaining > Duration::zero()))
+}
+
+/// The longer of the lockouts of the username and the source IP, if either is locked out.
+async fn login_lockout(
+	db: &DatabaseConnection,
+	username: &str,
+	ip: &str,
+) -> Result<Option<std::time::Duration>> {
+	let lockout = lockout_remaining(db, LOGIN_SCOPE_USERNAME, username)
+		.await?
+		.max(lockout_remaining(db, LOGIN_SCOPE_IP, ip).await?);
+	Ok(lockout.map(|lockout| lockout.to_std()).transpose()?)
+}
+
+/// Lockout for the given number of recent failures, doubling with every failure past the
+/// threshold.
+fn lockout_duration(failures: i32, threshold: i32) -> Option<Duration> {
+	let excess = failures
+		.checked_sub(threshold)
+		.filter(|excess| *excess >= 0)?;
+	Some((BASE_LOCKOUT * 2i32.pow(excess.min(16) as u32)).min(MAX_LOCKOUT))
+}
+
+async fn record_failure(
+	db: &DatabaseConnection,
+	scope: &str,
+	key: &str,
+	threshold: i32,
+	username: &str,
+	ip: &str,
+) -> Result<()> {
+	let now = Utc::now();
+	let failures = db::record_login_failure(db, scope, key, now - FAILURE_WINDOW).await?;
+
+	if let Some(lockout) = lockout_duration(failures.failures, threshold) {
+		db::lock_login(db, scope, key, now + lockout).await?;
+		db::add_audit_entry(
+			db,
+			"login_locked",
+			Some(username),
+			None,
+			Some(ip),
+			Some(format!(
+				"{scope} {key} locked for {}s after {} failed logins",
+				lockout.num_seconds(),
+				failures.failures
+			)),
+		)
+		.await?;
+	}
+
+	Ok(())
+}
+
+/// Counts a failed password or second factor against both the username and the source IP.
+async fn record_login_failure(
+	db: &DatabaseConnection,
+	event: &str,
+	username: &str,
+	ip: &str,
+) -> Result<()> {
+	db::add_audit_entry(db, event, Some(username), None, Some(ip), None).await?;
+	record_failure(
+		db,
+		LOGIN_SCOPE_USERNAME,
+		username,
+		USERNAME_FAILURE_THRESHOLD,
+		username,
+		ip,
+	)
+	.await?;
+	record_failure(db, LOGIN_SCOPE_IP, ip, IP_FAILURE_THRESHOLD, username, ip).await
+}
+
+/// Checks credentials, refusing to even verify the password while the username or the source IP
+/// is locked out after repeated failures.
 pub async fn authenticate_user(
 	db: &DatabaseConnection,
 	credentials: &Credentials,
-) -> Result<Option<User>> {
-	match db::get_user(db, &credentials.username).await {
-		Ok(user) => match verify_password(&credentials.password, &user.password) {
-			Ok(true) => Ok(Some(user)),
-			Ok(false) => Ok(None),
-			Err(err) => Err(Error::from(err)),
-		},
-		Err(DbErr::RecordNotFound(_)) => Ok(None),
-		Err(err) => Err(Error::from(err)),
+	ip: &str,
+) -> Result<LoginOutcome> {
+	let username = credentials.username.as_str();
+
+	if let Some(remaining) = login_lockout(db, username, ip).await? {
+		return Ok(LoginOutcome::LockedOut(remaining));
+	}
+
+	let user = match db::get_user(db, username).await {
+		Ok(user) if verify_password(&credentials.password, &user.password)? => Some(user),
+		Ok(_) | Err(DbErr::RecordNotFound(_)) => None,
+		Err(err) => return Err(Error::from(err)),
+	};
+
+	let Some(user) = user else {
+		record_login_failure(db, "login_failed", username, ip).await?;
+		return Ok(LoginOutcome::Rejected);
+	};
+
+	// Failures keep counting until the second factor is given too, so that knowing the password
+	// does not help guessing codes
+	if totp_enabled(db, username).await? {
+		return Ok(LoginOutcome::MfaRequired {
+			mfa_token: generate_mfa_token(username)?,
+		});
+	}
+
+	// Failures from the IP keep counting, or one valid account would reset them for guessing others
+	db::clear_login_failures(db, LOGIN_SCOPE_USERNAME, username).await?;
+	Ok(LoginOutcome::Authenticated(user))
+}
+
+/// Finishes a login started by `authenticate_user` with the second factor.
+pub async fn complete_mfa_login(
+	db: &DatabaseConnection,
+	request: &MfaLoginRequest,
+	ip: &str,
+) -> Result<LoginOutcome> {
+	let Some(username) = validate_mfa_token(&request.mfa_token) else {
+		return Ok(LoginOutcome::Rejected);
+	};
+
+	match check_second_factor(db, &username, &request.code, ip).await? {
+		CodeCheck::Accepted => Ok(LoginOutcome::Authenticated(
+			db::get_user(db, &username).await?,
+		)),
+		CodeCheck::Rejected => Ok(LoginOutcome::Rejected),
+		CodeCheck::LockedOut(remaining) => Ok(LoginOutcome::LockedOut(remaining)),
+	}
+}
+
+/// Lifts the lockout of `username` on behalf of the admin `actor`. Returns whether it had any
+/// failed logins to forget.
+pub async fn unlock_login(db: &DatabaseConnection, username: &str, actor: &str) -> Result<bool> {
+	let unlocked = db::clear_login_failures(db, LOGIN_SCOPE_USERNAME, username).await?;
+	db::add_audit_entry(
+		db,
+		"login_unlocked",
+		Some(username),
+		Some(actor),
+		None,
+		None,
+	)
+	.await?;
+	Ok(unlocked)
+}
+
+async fn totp_enabled(db: &DatabaseConnection, username: &str) -> Result<bool> {
+	Ok(db::get_totp(db, username)
+		.await?
+		.is_some_and(|totp| totp.enabled_at.is_some()))
+}
+
+/// Accepts a TOTP code not used before, or else an unused recovery code, which is used up.
+async fn verify_second_factor(db: &DatabaseConnection, username: &str, code: &str) -> Result<bool> {
+	let Some(totp) = db::get_totp(db, username)
+		.await?
+		.filter(|totp| totp.enabled_at.is_some())
+	else {
+		return Ok(false);
+	};
+
+	if let Some(step) = totp::verify(&totp.secret, code, Utc::now(), totp.last_used_step) {
+		return Ok(db::use_totp_step(db, username, step).await?);
 	}
+
+	let code_hash = hash_secret(&totp::normalize_recovery_code(code));
+	if !db::use_recovery_code(db, username, &code_hash).await? {
+		return Ok(false);
+	}
+
+	db::add_audit_entry(db, "recovery_code_used", Some(username), None, None, None).await?;
+	Ok(true)
+}
+
+/// Checks a second factor, counting failures towards the same lockouts as passwords.
+async fn check_second_factor(
+	db: &DatabaseConnection,
+	username: &str,
+	code: &str,
+	ip: &str,
+) -> Result<CodeCheck> {
+	if let Some(remaining) = login_lockout(db, username, ip).await? {
+		return Ok(CodeCheck::LockedOut(remaining));
+	}
+
+	if !verify_second_factor(db, username, code).await? {
+		record_login_failure(db, "mfa_failed", username, ip).await?;
+		return Ok(CodeCheck::Rejected);
+	}
+
+	db::clear_login_failures(db, LOGIN_SCOPE_USERNAME, username).await?;
+	Ok(CodeCheck::Accepted)
+}
+
+pub async fn get_mfa_status(db: &DatabaseConnection, username: &str) -> Result<MfaStatus> {
+	Ok(MfaStatus {
+		totp_enabled: totp_enabled(db, username).await?,
+		recovery_codes_remaining: db::count_unused_recovery_codes(db, username).await?,
+	})
+}
+
+/// Starts enrolling `username` in TOTP with a new secret, which only takes effect once confirmed
+/// with a code. Returns `None` if TOTP is already enabled.
+pub async fn start_totp_enrollment(
+	db: &DatabaseConnection,
+	username: &str,
+) -> Result<Option<TotpEnrollment>> {
+	let secret = totp::generate_secret();
+
+	if db::set_pending_totp(db, username, secret.clone())
+		.await?
+		.is_none()
+	{
+		return Ok(None);
+	}
+
+	Ok(Some(TotpEnrollment {
+		otpauth_uri: totp::otpauth_uri(&secret, username),
+		secret,
+	}))
+}
+
+/// Enables TOTP if `code` matches the pending secret, returning the recovery codes, which are
+/// shown this once and never stored in the clear.
+pub async fn confirm_totp_enrollment(
+	db: &DatabaseConnection,
+	username: &str,
+	code: &str,
+) -> Result<Option<RecoveryCodes>> {
+	let Some(totp) = db::get_totp(db, username)
+		.await?
+		.filter(|totp| totp.enabled_at.is_none())
+	else {
+		return Ok(None);
+	};
+
+	let Some(step) = totp::verify(&totp.secret, code, Utc::now(), totp.last_used_step) else {
+		return Ok(None);
+	};
+
+	let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+		.map(|_| totp::generate_recovery_code())
+		.collect();
+	let hashes = recovery_codes
+		.iter()
+		.map(|code| hash_secret(&totp::normalize_recovery_code(code)))
+		.collect();
+
+	db::enable_totp(db, username, step, hashes).await?;
+	db::add_audit_entry(db, "mfa_enabled", Some(username), None, None, None).await?;
+
+	Ok(Some(RecoveryCodes { recovery_codes }))
+}
+
+/// Turns TOTP off given a TOTP or recovery code. Returns `None` if it was not enabled.
+pub async fn disable_totp(
+	db: &DatabaseConnection,
+	username: &str,
+	code: &str,
+	ip: &str,
+) -> Result<Option<CodeCheck>> {
+	if !totp_enabled(db, username).await? {
+		return Ok(None);
+	}
+
+	let check = check_second_factor(db, username, code, ip).await?;
+	if let CodeCheck::Accepted = check {
+		db::delete_totp(db, username).await?;
+		db::add_audit_entry(db, "mfa_disabled", Some(username), None, Some(ip), None).await?;
+	}
+
+	Ok(Some(check))
 }
 
 pub async fn auth_middleware(
+	State(app_state): State<AppState>,
 	headers: HeaderMap,
 	Query(query): Query<HashMap<String, String>>,
 	mut request: Request,
@@ -113,8 +644,52 @@ pub async fn auth_middleware(
 
 	let claims = validate_token(&token).map_err(|_| StatusCode::UNAUTHORIZED)?;
 
-	// Add the username to request extensions so handlers can access it
-	request.extensions_mut().insert(claims.sub);
+	let session = match db::get_session(&app_state.conn, claims.sid).await {
+		Ok(session) if session.revoked_at.is_none() && session.username == claims.sub => session,
+		Ok(_) | Err(DbErr::RecordNotFound(_)) => return Err(StatusCode::UNAUTHORIZED),
+		Err(err) => {
+			eprintln!("{err}");
+			return Err(StatusCode::INTERNAL_SERVER_ERROR);
+		}
+	};
+
+	// Only now and then, so that the sessions list stays current without a write per request
+	let now = Utc::now();
+	if session.last_used_at < now - SESSION_TOUCH_INTERVAL
+		&& let Err(err) = db::touch_session(&app_state.conn, session.id, now).await
+	{
+		eprintln!("{err}");
+	}
+
+	// Add the claims to request extensions so handlers can access the username and session
+	request.extensions_mut().insert(claims);
 
 	Ok(next.run(request).await)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lockout_starts_at_the_threshold() {
+		assert_eq!(lockout_duration(0, 5), None);
+		assert_eq!(lockout_duration(4, 5), None);
+		assert_eq!(lockout_duration(5, 5), Some(BASE_LOCKOUT));
+	}
+
+	#[test]
+	fn lockout_doubles_with_every_failure() {
+		assert_eq!(lockout_duration(6, 5), Some(Duration::seconds(60)));
+		assert_eq!(lockout_duration(7, 5), Some(Duration::seconds(120)));
+		assert_eq!(lockout_duration(21, 20), Some(Duration::seconds(60)));
+	}
+
+	#[test]
+	fn lockout_is_capped() {
+		// 30 seconds doubled seven times is past an hour
+		assert_eq!(lockout_duration(12, 5), Some(MAX_LOCKOUT));
+		assert_eq!(lockout_duration(1000, 5), Some(MAX_LOCKOUT));
+		assert_eq!(lockout_duration(i32::MAX, 5), Some(MAX_LOCKOUT));
+	}
+}
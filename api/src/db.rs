@@ -1,17 +1,51 @@
 use crate::entity::{
-	directory, directory::Model as Directory, messages, messages::Model as Message, users,
-	users::Model as User,
+	attachments, attachments::Model as Attachment, audit_log, audit_log::Model as AuditEntry,
+	directory, directory::Model as Directory, directory_grants,
+	directory_grants::Model as DirectoryGrant, directory_members,
+	directory_members::Model as DirectoryMember, login_failures,
+	login_failures::Model as LoginFailure, mentions, mentions::Model as Mention, message_reactions,
+	message_revisions, message_revisions::Model as MessageRevision, messages,
+	messages::Model as Message, read_markers, read_markers::Model as ReadMarker, recovery_codes,
+	roles, roles::Model as Role, sessions, sessions::Model as Session, user_totp,
+	user_totp::Model as UserTotp, users, users::Model as User, ws_connections,
+	ws_connections::Model as WsConnection, ws_nodes,
 };
-use chrono::Utc;
+use crate::mentions::MentionKind;
+use chrono::{DateTime, Utc};
 use sea_orm::{
-	ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, Set,
+	ActiveModelTrait, ColumnTrait, Condition, ConnectionTrait, DatabaseConnection,
+	DatabaseTransaction, DbBackend, DbErr, EntityTrait, FromQueryResult, Order, PaginatorTrait,
+	QueryFilter, QueryOrder, QuerySelect, Set, Statement, TransactionTrait,
+	prelude::{DateTimeWithTimeZone, Expr},
+	sea_query::{OnConflict, SimpleExpr},
 };
-use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Arbitrary key of the advisory lock held while changing the shape of the directory tree
+const DIRECTORY_TREE_LOCK_KEY: i64 = 0x6469_7265_6374_6f72;
+/// Arbitrary key of the advisory locks held while changing the WebSocket connections of a user,
+/// paired with a hash of the username
+const WS_CONNECTIONS_LOCK_KEY: i32 = 0x7773_636f;
+/// Arbitrary key of the advisory locks held while opening a DM conversation, paired with a hash
+/// of its participants
+const DM_CONVERSATIONS_LOCK_KEY: i32 = 0x646d_636f;
 
 pub async fn get_users(db: &DatabaseConnection) -> Result<Vec<User>, DbErr> {
 	users::Entity::find().all(db).await
 }
 
+/// Returns those of `usernames` that belong to a user.
+pub async fn get_users_named(
+	db: &DatabaseConnection,
+	usernames: impl IntoIterator<Item = String>,
+) -> Result<Vec<User>, DbErr> {
+	users::Entity::find()
+		.filter(users::Column::Username.is_in(usernames))
+		.all(db)
+		.await
+}
+
 pub async fn get_user(db: &DatabaseConnection, username: &str) -> Result<User, DbErr> {
 	users::Entity::find()
 		.filter(users::Column::Username.eq(username))
@@ -27,59 +61,1490 @@ pub async fn create_user(db: &DatabaseConnection, user: User) -> Result<User, Db
 		username: Set(user.username),
 		name: Set(user.name),
 		password: Set(user.password),
+		last_seen_at: Set(None),
 	}
 	.insert(db)
 	.await
 }
 
-pub async fn get_directory(db: &DatabaseConnection, id: i32) -> Result<Vec<Directory>, DbErr> {
-	let mut results: Vec<Directory> = Vec::new();
-	let mut queue: VecDeque<i32> = VecDeque::new();
+pub async fn set_last_seen(
+	db: &DatabaseConnection,
+	username: &str,
+	last_seen_at: DateTime<Utc>,
+) -> Result<(), DbErr> {
+	users::Entity::update_many()
+		.col_expr(
+			users::Column::LastSeenAt,
+			Expr::value(Some(DateTimeWithTimeZone::from(last_seen_at))),
+		)
+		.filter(users::Column::Username.eq(username))
+		.exec(db)
+		.await?;
+	Ok(())
+}
 
-	match directory::Entity::find_by_id(id).one(db).await? {
-		Some(root_node) => {
-			results.push(root_node.clone());
-			queue.push_back(root_node.id);
+/// Registers a node that WebSocket connections can be open on, returning its id.
+pub async fn create_ws_node(db: &DatabaseConnection, now: DateTime<Utc>) -> Result<i32, DbErr> {
+	let node = ws_nodes::ActiveModel {
+		heartbeat_at: Set(now.into()),
+		..Default::default()
+	}
+	.insert(db)
+	.await?;
+	Ok(node.id)
+}
 
-			while let Some(current_parent_id) = queue.pop_front() {
-				let children = directory::Entity::find()
-					.filter(directory::Column::ParentId.eq(Some(current_parent_id)))
-					.all(db)
-					.await?;
+/// Records that a node is still alive. A node given up on in the meantime is registered again,
+/// though without the connections it had.
+pub async fn touch_ws_node(
+	db: &DatabaseConnection,
+	id: i32,
+	now: DateTime<Utc>,
+) -> Result<(), DbErr> {
+	ws_nodes::Entity::insert(ws_nodes::ActiveModel {
+		id: Set(id),
+		heartbeat_at: Set(now.into()),
+	})
+	.on_conflict(
+		OnConflict::column(ws_nodes::Column::Id)
+			.update_column(ws_nodes::Column::HeartbeatAt)
+			.to_owned(),
+	)
+	.exec(db)
+	.await?;
+	Ok(())
+}
 
-				for child in children {
-					results.push(child.clone());
-					queue.push_back(child.id);
-				}
+/// Removes the nodes whose last heartbeat came before `before`, returning the connections that
+/// were open on them.
+pub async fn delete_stale_ws_nodes(
+	db: &DatabaseConnection,
+	before: DateTime<Utc>,
+) -> Result<Vec<WsConnection>, DbErr> {
+	let txn = db.begin().await?;
+
+	let stale: Vec<i32> = ws_nodes::Entity::find()
+		.filter(ws_nodes::Column::HeartbeatAt.lt(before))
+		.all(&txn)
+		.await?
+		.into_iter()
+		.map(|node| node.id)
+		.collect();
+	if stale.is_empty() {
+		return Ok(Vec::new());
+	}
+
+	let connections = ws_connections::Entity::delete_many()
+		.filter(ws_connections::Column::NodeId.is_in(stale.iter().copied()))
+		.exec_with_returning(&txn)
+		.await?;
+	ws_nodes::Entity::delete_many()
+		.filter(ws_nodes::Column::Id.is_in(stale))
+		.exec(&txn)
+		.await?;
+
+	txn.commit().await?;
+	Ok(connections)
+}
+
+/// The open WebSocket connections of `username`, on every node.
+pub async fn get_ws_connections(
+	db: &impl ConnectionTrait,
+	username: &str,
+) -> Result<Vec<WsConnection>, DbErr> {
+	ws_connections::Entity::find()
+		.filter(ws_connections::Column::Username.eq(username))
+		.all(db)
+		.await
+}
+
+pub async fn get_all_ws_connections(db: &DatabaseConnection) -> Result<Vec<WsConnection>, DbErr> {
+	ws_connections::Entity::find().all(db).await
+}
+
+/// What happens to a WebSocket connection in `update_ws_connection`.
+pub enum WsConnectionChange {
+	Open,
+	SetIdle(bool),
+	Close,
+}
+
+/// Applies `change` to a connection of `username`, returning all of the user's connections before
+/// and after. Changes to the connections of a user are serialized, so that each transition is
+/// seen by exactly one of them.
+pub async fn update_ws_connection(
+	db: &DatabaseConnection,
+	node_id: i32,
+	connection_id: u64,
+	username: &str,
+	change: WsConnectionChange,
+) -> Result<(Vec<WsConnection>, Vec<WsConnection>), DbErr> {
+	let txn = db.begin().await?;
+	txn.execute(Statement::from_sql_and_values(
+		DbBackend::Postgres,
+		"SELECT pg_advisory_xact_lock($1, hashtext($2))",
+		[WS_CONNECTIONS_LOCK_KEY.into(), username.into()],
+	))
+	.await?;
+
+	let before = get_ws_connections(&txn, username).await?;
+	let connection_id = connection_id as i64;
+	match change {
+		WsConnectionChange::Open => {
+			ws_connections::ActiveModel {
+				node_id: Set(node_id),
+				connection_id: Set(connection_id),
+				username: Set(username.to_string()),
+				idle: Set(false),
 			}
+			.insert(&txn)
+			.await?;
+		}
+		WsConnectionChange::SetIdle(idle) => {
+			ws_connections::Entity::update_many()
+				.col_expr(ws_connections::Column::Idle, Expr::value(idle))
+				.filter(ws_connections::Column::NodeId.eq(node_id))
+				.filter(ws_connections::Column::ConnectionId.eq(connection_id))
+				.exec(&txn)
+				.await?;
+		}
+		WsConnectionChange::Close => {
+			ws_connections::Entity::delete_by_id((node_id, connection_id))
+				.exec(&txn)
+				.await?;
+		}
+	}
+	let after = get_ws_connections(&txn, username).await?;
+
+	txn.commit().await?;
+	Ok((before, after))
+}
+
+pub async fn create_session(
+	db: &DatabaseConnection,
+	username: &str,
+	refresh_token_hash: String,
+	device_name: Option<String>,
+	ip: Option<String>,
+	expires_at: DateTime<Utc>,
+) -> Result<Session, DbErr> {
+	let now = Utc::now();
+
+	sessions::ActiveModel {
+		username: Set(username.to_string()),
+		refresh_token_hash: Set(refresh_token_hash),
+		device_name: Set(device_name),
+		ip: Set(ip),
+		created_at: Set(now.into()),
+		last_used_at: Set(now.into()),
+		expires_at: Set(expires_at.into()),
+		revoked_at: Set(None),
+		..Default::default()
+	}
+	.insert(db)
+	.await
+}
+
+pub async fn get_session(db: &DatabaseConnection, id: i32) -> Result<Session, DbErr> {
+	sessions::Entity::find_by_id(id)
+		.one(db)
+		.await?
+		.ok_or(DbErr::RecordNotFound(format!(
+			"Session with id {id} not found"
+		)))
+}
+
+/// Replaces the refresh token of a session, provided it still holds the one `session` was read
+/// with and has not been revoked, keeping the old one's hash to recognize it if it comes back.
+/// Returns `None` when another refresh got there first.
+pub async fn rotate_session(
+	db: &DatabaseConnection,
+	session: Session,
+	refresh_token_hash: String,
+	ip: Option<String>,
+	expires_at: DateTime<Utc>,
+) -> Result<Option<Session>, DbErr> {
+	let mut update = sessions::Entity::update_many()
+		.col_expr(
+			sessions::Column::RefreshTokenHash,
+			Expr::value(refresh_token_hash),
+		)
+		.col_expr(
+			sessions::Column::PreviousRefreshTokenHash,
+			Expr::value(Some(session.refresh_token_hash.clone())),
+		)
+		.col_expr(
+			sessions::Column::LastUsedAt,
+			Expr::value(DateTimeWithTimeZone::from(Utc::now())),
+		)
+		.col_expr(
+			sessions::Column::ExpiresAt,
+			Expr::value(DateTimeWithTimeZone::from(expires_at)),
+		)
+		.filter(sessions::Column::Id.eq(session.id))
+		.filter(sessions::Column::RefreshTokenHash.eq(session.refresh_token_hash))
+		.filter(sessions::Column::RevokedAt.is_null());
+	if ip.is_some() {
+		update = update.col_expr(sessions::Column::Ip, Expr::value(ip));
+	}
+
+	Ok(update.exec_with_returning(db).await?.pop())
+}
+
+/// Records that a session was just used.
+pub async fn touch_session(
+	db: &DatabaseConnection,
+	id: i32,
+	last_used_at: DateTime<Utc>,
+) -> Result<(), DbErr> {
+	sessions::Entity::update_many()
+		.col_expr(
+			sessions::Column::LastUsedAt,
+			Expr::value(DateTimeWithTimeZone::from(last_used_at)),
+		)
+		.filter(sessions::Column::Id.eq(id))
+		.exec(db)
+		.await?;
+	Ok(())
+}
+
+pub async fn get_active_sessions(
+	db: &DatabaseConnection,
+	username: &str,
+) -> Result<Vec<Session>, DbErr> {
+	sessions::Entity::find()
+		.filter(sessions::Column::Username.eq(username))
+		.filter(sessions::Column::RevokedAt.is_null())
+		.filter(sessions::Column::ExpiresAt.gt(Utc::now()))
+		.order_by_desc(sessions::Column::LastUsedAt)
+		.all(db)
+		.await
+}
+
+pub async fn revoke_session(db: &DatabaseConnection, id: i32) -> Result<(), DbErr> {
+	sessions::Entity::update_many()
+		.col_expr(
+			sessions::Column::RevokedAt,
+			Expr::value(Some(DateTimeWithTimeZone::from(Utc::now()))),
+		)
+		.filter(sessions::Column::Id.eq(id))
+		.filter(sessions::Column::RevokedAt.is_null())
+		.exec(db)
+		.await?;
+	Ok(())
+}
+
+/// Revokes every active session of `username` except `keep_id`, returning the revoked ids.
+pub async fn revoke_other_sessions(
+	db: &DatabaseConnection,
+	username: &str,
+	keep_id: i32,
+) -> Result<Vec<i32>, DbErr> {
+	let revoked = sessions::Entity::update_many()
+		.col_expr(
+			sessions::Column::RevokedAt,
+			Expr::value(Some(DateTimeWithTimeZone::from(Utc::now()))),
+		)
+		.filter(sessions::Column::Username.eq(username))
+		.filter(sessions::Column::Id.ne(keep_id))
+		.filter(sessions::Column::RevokedAt.is_null())
+		.exec_with_returning(db)
+		.await?;
+
+	Ok(revoked.into_iter().map(|session| session.id).collect())
+}
+
+pub async fn get_login_failures(
+	db: &DatabaseConnection,
+	scope: &str,
+	key: &str,
+) -> Result<Option<LoginFailure>, DbErr> {
+	login_failures::Entity::find_by_id((scope.to_string(), key.to_string()))
+		.one(db)
+		.await
+}
+
+/// Counts a failed login, starting over if the previous failure happened before `since`.
+pub async fn record_login_failure(
+	db: &DatabaseConnection,
+	scope: &str,
+	key: &str,
+	since: DateTime<Utc>,
+) -> Result<LoginFailure, DbErr> {
+	login_failures::Entity::insert(login_failures::ActiveModel {
+		scope: Set(scope.to_string()),
+		key: Set(key.to_string()),
+		failures: Set(1),
+		last_failed_at: Set(Utc::now().into()),
+		locked_until: Set(None),
+	})
+	.on_conflict(
+		OnConflict::columns([login_failures::Column::Scope, login_failures::Column::Key])
+			.value(
+				login_failures::Column::Failures,
+				Expr::cust_with_values(
+					"CASE WHEN \"login_failures\".\"last_failed_at\" < $1 THEN 1 \
+					 ELSE \"login_failures\".\"failures\" + 1 END",
+					[DateTimeWithTimeZone::from(since)],
+				),
+			)
+			.update_column(login_failures::Column::LastFailedAt)
+			.to_owned(),
+	)
+	.exec_with_returning(db)
+	.await
+}
+
+pub async fn lock_login(
+	db: &DatabaseConnection,
+	scope: &str,
+	key: &str,
+	until: DateTime<Utc>,
+) -> Result<(), DbErr> {
+	login_failures::Entity::update_many()
+		.col_expr(
+			login_failures::Column::LockedUntil,
+			Expr::value(Some(DateTimeWithTimeZone::from(until))),
+		)
+		.filter(login_failures::Column::Scope.eq(scope))
+		.filter(login_failures::Column::Key.eq(key))
+		.exec(db)
+		.await?;
+	Ok(())
+}
+
+/// Forgets the failed logins of `key`, lifting any lockout. Returns whether there were any.
+pub async fn clear_login_failures(
+	db: &DatabaseConnection,
+	scope: &str,
+	key: &str,
+) -> Result<bool, DbErr> {
+	let result = login_failures::Entity::delete_many()
+		.filter(login_failures::Column::Scope.eq(scope))
+		.filter(login_failures::Column::Key.eq(key))
+		.exec(db)
+		.await?;
+	Ok(result.rows_affected > 0)
+}
+
+pub async fn add_audit_entry(
+	db: &DatabaseConnection,
+	event: &str,
+	username: Option<&str>,
+	actor: Option<&str>,
+	ip: Option<&str>,
+	details: Option<String>,
+) -> Result<AuditEntry, DbErr> {
+	audit_log::ActiveModel {
+		event: Set(event.to_string()),
+		username: Set(username.map(str::to_string)),
+		actor: Set(actor.map(str::to_string)),
+		ip: Set(ip.map(str::to_string)),
+		details: Set(details),
+		created_at: Set(Utc::now().into()),
+		..Default::default()
+	}
+	.insert(db)
+	.await
+}
+
+pub async fn get_totp(db: &DatabaseConnection, username: &str) -> Result<Option<UserTotp>, DbErr> {
+	user_totp::Entity::find_by_id(username.to_string())
+		.one(db)
+		.await
+}
+
+/// Stores a secret awaiting confirmation, replacing any earlier one that was never confirmed.
+/// Returns `None`, changing nothing, if TOTP is already enabled.
+pub async fn set_pending_totp(
+	db: &DatabaseConnection,
+	username: &str,
+	secret: String,
+) -> Result<Option<UserTotp>, DbErr> {
+	let txn = db.begin().await?;
+
+	let existing = user_totp::Entity::find_by_id(username.to_string())
+		.lock_exclusive()
+		.one(&txn)
+		.await?;
+
+	let totp = match existing {
+		Some(totp) if totp.enabled_at.is_some() => return Ok(None),
+		Some(totp) => {
+			let mut totp: user_totp::ActiveModel = totp.into();
+			totp.secret = Set(secret);
+			totp.created_at = Set(Utc::now().into());
+			totp.update(&txn).await?
 		}
 		None => {
-			return Err(DbErr::RecordNotFound(format!(
-				"Directory with id {id} not found"
+			user_totp::ActiveModel {
+				username: Set(username.to_string()),
+				secret: Set(secret),
+				enabled_at: Set(None),
+				last_used_step: Set(None),
+				created_at: Set(Utc::now().into()),
+			}
+			.insert(&txn)
+			.await?
+		}
+	};
+
+	txn.commit().await?;
+	Ok(Some(totp))
+}
+
+/// Enables TOTP after the first code was accepted at `step`, replacing any recovery codes.
+pub async fn enable_totp(
+	db: &DatabaseConnection,
+	username: &str,
+	step: i64,
+	recovery_code_hashes: Vec<String>,
+) -> Result<(), DbErr> {
+	let txn = db.begin().await?;
+
+	let enabled = user_totp::Entity::update_many()
+		.col_expr(
+			user_totp::Column::EnabledAt,
+			Expr::value(Some(DateTimeWithTimeZone::from(Utc::now()))),
+		)
+		.col_expr(user_totp::Column::LastUsedStep, Expr::value(Some(step)))
+		.filter(user_totp::Column::Username.eq(username))
+		.filter(user_totp::Column::EnabledAt.is_null())
+		.exec(&txn)
+		.await?;
+
+	if enabled.rows_affected == 0 {
+		return Err(DbErr::RecordNotFound(format!(
+			"No pending TOTP enrolment for {username}"
+		)));
+	}
+
+	recovery_codes::Entity::delete_many()
+		.filter(recovery_codes::Column::Username.eq(username))
+		.exec(&txn)
+		.await?;
+
+	recovery_codes::Entity::insert_many(recovery_code_hashes.into_iter().map(|code_hash| {
+		recovery_codes::ActiveModel {
+			username: Set(username.to_string()),
+			code_hash: Set(code_hash),
+			used_at: Set(None),
+			..Default::default()
+		}
+	}))
+	.exec(&txn)
+	.await?;
+
+	txn.commit().await
+}
+
+/// Records that a code from `step` was used, returning false if one from that step or a later
+/// one already was.
+pub async fn use_totp_step(
+	db: &DatabaseConnection,
+	username: &str,
+	step: i64,
+) -> Result<bool, DbErr> {
+	let result = user_totp::Entity::update_many()
+		.col_expr(user_totp::Column::LastUsedStep, Expr::value(Some(step)))
+		.filter(user_totp::Column::Username.eq(username))
+		.filter(
+			Condition::any()
+				.add(user_totp::Column::LastUsedStep.is_null())
+				.add(user_totp::Column::LastUsedStep.lt(step)),
+		)
+		.exec(db)
+		.await?;
+	Ok(result.rows_affected > 0)
+}
+
+/// Marks an unused recovery code as used, returning whether there was one with this hash.
+pub async fn use_recovery_code(
+	db: &DatabaseConnection,
+	username: &str,
+	code_hash: &str,
+) -> Result<bool, DbErr> {
+	let result = recovery_codes::Entity::update_many()
+		.col_expr(
+			recovery_codes::Column::UsedAt,
+			Expr::value(Some(DateTimeWithTimeZone::from(Utc::now()))),
+		)
+		.filter(recovery_codes::Column::Username.eq(username))
+		.filter(recovery_codes::Column::CodeHash.eq(code_hash))
+		.filter(recovery_codes::Column::UsedAt.is_null())
+		.exec(db)
+		.await?;
+	Ok(result.rows_affected > 0)
+}
+
+pub async fn count_unused_recovery_codes(
+	db: &DatabaseConnection,
+	username: &str,
+) -> Result<u64, DbErr> {
+	recovery_codes::Entity::find()
+		.filter(recovery_codes::Column::Username.eq(username))
+		.filter(recovery_codes::Column::UsedAt.is_null())
+		.count(db)
+		.await
+}
+
+pub async fn delete_totp(db: &DatabaseConnection, username: &str) -> Result<(), DbErr> {
+	let txn = db.begin().await?;
+
+	recovery_codes::Entity::delete_many()
+		.filter(recovery_codes::Column::Username.eq(username))
+		.exec(&txn)
+		.await?;
+
+	user_totp::Entity::delete_by_id(username.to_string())
+		.exec(&txn)
+		.await?;
+
+	txn.commit().await
+}
+
+/// Returns the subtree of `id` in a single query, each node before its children and siblings in
+/// `position` order.
+pub async fn get_directory(db: &impl ConnectionTrait, id: i32) -> Result<Vec<Directory>, DbErr> {
+	let nodes = directory::Entity::find()
+		.from_raw_sql(Statement::from_sql_and_values(
+			DbBackend::Postgres,
+			r#"WITH RECURSIVE subtree AS (
+				SELECT directory.*, ARRAY[directory.position, directory.id] AS sort_key
+				FROM directory
+				WHERE directory.id = $1
+				UNION ALL
+				SELECT child.*, subtree.sort_key || ARRAY[child.position, child.id]
+				FROM directory AS child
+				JOIN subtree ON child.parent_id = subtree.id
+			)
+			SELECT id, name, type, parent_id, is_private, position
+			FROM subtree
+			ORDER BY sort_key"#,
+			[id.into()],
+		))
+		.all(db)
+		.await?;
+
+	if nodes.is_empty() {
+		return Err(DbErr::RecordNotFound(format!(
+			"Directory with id {id} not found"
+		)));
+	}
+
+	Ok(nodes)
+}
+
+/// Returns the nodes without a parent: the roots of every tree and all DM conversations.
+pub async fn get_root_nodes(db: &DatabaseConnection) -> Result<Vec<Directory>, DbErr> {
+	directory::Entity::find()
+		.filter(directory::Column::ParentId.is_null())
+		.all(db)
+		.await
+}
+
+#[derive(Serialize)]
+pub struct DirectoryWithUnread {
+	#[serde(flatten)]
+	pub node: Directory,
+	/// Unread messages by others in this node and every node below it
+	pub unread_count: i64,
+	/// The subset of `unread_count` that mention the user
+	pub mention_count: i64,
+}
+
+#[derive(FromQueryResult)]
+struct UnreadCount {
+	directory_id: i32,
+	unread_count: i64,
+	mention_count: i64,
+}
+
+/// Attaches the unread and mention counts of `username` to a subtree listed parents-first, as
+/// returned by `get_directory`, rolling the counts of each node up into its ancestors.
+pub async fn with_unread_counts(
+	db: &DatabaseConnection,
+	username: &str,
+	nodes: Vec<Directory>,
+) -> Result<Vec<DirectoryWithUnread>, DbErr> {
+	let node_ids: Vec<i32> = nodes.iter().map(|node| node.id).collect();
+
+	let counts: HashMap<i32, (i64, i64)> = messages::Entity::find()
+		.select_only()
+		.column(messages::Column::DirectoryId)
+		.column_as(Expr::cust("COUNT(*)"), "unread_count")
+		.column_as(
+			Expr::cust_with_values(
+				"COUNT(*) FILTER (WHERE EXISTS (SELECT 1 FROM \"mentions\" \
+				 WHERE \"mentions\".\"message_id\" = \"messages\".\"id\" \
+				 AND \"mentions\".\"username\" = $1))",
+				[username],
+			),
+			"mention_count",
+		)
+		.filter(messages::Column::DirectoryId.is_in(node_ids))
+		.filter(messages::Column::AuthorUsername.ne(username))
+		.filter(messages::Column::DeletedAt.is_null())
+		.filter(Expr::cust_with_values(
+			"\"messages\".\"id\" > COALESCE((SELECT \"last_read_message_id\" \
+			 FROM \"read_markers\" WHERE \"read_markers\".\"username\" = $1 \
+			 AND \"read_markers\".\"directory_id\" = \"messages\".\"directory_id\"), 0)",
+			[username],
+		))
+		.group_by(messages::Column::DirectoryId)
+		.into_model::<UnreadCount>()
+		.all(db)
+		.await?
+		.into_iter()
+		.map(|count| {
+			(
+				count.directory_id,
+				(count.unread_count, count.mention_count),
+			)
+		})
+		.collect();
+
+	let mut nodes: Vec<DirectoryWithUnread> = nodes
+		.into_iter()
+		.map(|node| {
+			let (unread_count, mention_count) = counts.get(&node.id).copied().unwrap_or_default();
+			DirectoryWithUnread {
+				node,
+				unread_count,
+				mention_count,
+			}
+		})
+		.collect();
+
+	// Children always come after their parent, so walking backwards totals each subtree before
+	// it is added to its parent
+	let positions: HashMap<i32, usize> = nodes
+		.iter()
+		.enumerate()
+		.map(|(index, node)| (node.node.id, index))
+		.collect();
+	for index in (0..nodes.len()).rev() {
+		if let Some(parent_index) = nodes[index]
+			.node
+			.parent_id
+			.and_then(|parent_id| positions.get(&parent_id).copied())
+		{
+			nodes[parent_index].unread_count += nodes[index].unread_count;
+			nodes[parent_index].mention_count += nodes[index].mention_count;
+		}
+	}
+
+	Ok(nodes)
+}
+
+/// Moves the read marker of `username` in a thread forward to `message_id`, along with the
+/// user's mentions up to it. Markers never move backwards, so reads reported out of order by
+/// several devices are harmless.
+pub async fn mark_read(
+	db: &DatabaseConnection,
+	username: &str,
+	directory_id: i32,
+	message_id: i32,
+) -> Result<ReadMarker, DbErr> {
+	let marker = read_markers::Entity::insert(read_markers::ActiveModel {
+		username: Set(username.to_string()),
+		directory_id: Set(directory_id),
+		last_read_message_id: Set(message_id),
+		updated_at: Set(Utc::now().into()),
+	})
+	.on_conflict(
+		OnConflict::columns([
+			read_markers::Column::Username,
+			read_markers::Column::DirectoryId,
+		])
+		.value(
+			read_markers::Column::LastReadMessageId,
+			Expr::cust(
+				"GREATEST(\"read_markers\".\"last_read_message_id\", \
+				 \"excluded\".\"last_read_message_id\")",
+			),
+		)
+		.update_column(read_markers::Column::UpdatedAt)
+		.to_owned(),
+	)
+	.exec_with_returning(db)
+	.await?;
+
+	mentions::Entity::update_many()
+		.col_expr(mentions::Column::ReadAt, Expr::value(marker.updated_at))
+		.filter(mentions::Column::Username.eq(username))
+		.filter(mentions::Column::ReadAt.is_null())
+		.filter(mentions::Column::MessageId.lte(marker.last_read_message_id))
+		.filter(Expr::cust_with_values(
+			"\"mentions\".\"message_id\" IN \
+			 (SELECT \"id\" FROM \"messages\" WHERE \"directory_id\" = $1)",
+			[directory_id],
+		))
+		.exec(db)
+		.await?;
+
+	Ok(marker)
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MentionWithMessage {
+	#[serde(flatten)]
+	pub mention: Mention,
+	pub message: Message,
+}
+
+#[derive(Serialize)]
+pub struct MentionsPage {
+	pub mentions: Vec<MentionWithMessage>,
+	/// Unread mentions of the user overall, not only on this page
+	pub unread_count: u64,
+	pub has_more: bool,
+}
+
+/// Mentions of `username` in messages that still exist in `directory_ids`.
+fn visible_mentions(username: &str, directory_ids: Vec<i32>) -> Condition {
+	Condition::all()
+		.add(mentions::Column::Username.eq(username))
+		.add(messages::Column::DirectoryId.is_in(directory_ids))
+		.add(messages::Column::DeletedAt.is_null())
+}
+
+/// Returns a page of the mentions of `username` in `directory_ids`, newest first, starting below
+/// the mention with id `before` when given.
+pub async fn get_mentions(
+	db: &DatabaseConnection,
+	username: &str,
+	directory_ids: Vec<i32>,
+	unread_only: bool,
+	before: Option<i32>,
+	limit: u64,
+) -> Result<MentionsPage, DbErr> {
+	let unread_count = mentions::Entity::find()
+		.inner_join(messages::Entity)
+		.filter(visible_mentions(username, directory_ids.clone()))
+		.filter(mentions::Column::ReadAt.is_null())
+		.count(db)
+		.await?;
+
+	let mut query = mentions::Entity::find()
+		.find_also_related(messages::Entity)
+		.filter(visible_mentions(username, directory_ids));
+	if unread_only {
+		query = query.filter(mentions::Column::ReadAt.is_null());
+	}
+	if let Some(before) = before {
+		query = query.filter(mentions::Column::Id.lt(before));
+	}
+
+	let mut mentions: Vec<MentionWithMessage> = query
+		.order_by_desc(mentions::Column::Id)
+		.limit(limit + 1)
+		.all(db)
+		.await?
+		.into_iter()
+		.filter_map(|(mention, message)| {
+			Some(MentionWithMessage {
+				mention,
+				message: message?,
+			})
+		})
+		.collect();
+
+	let has_more = mentions.len() as u64 > limit;
+	mentions.truncate(limit as usize);
+
+	Ok(MentionsPage {
+		mentions,
+		unread_count,
+		has_more,
+	})
+}
+
+/// Marks the given mentions of `username` read, or all of them when `ids` is `None`, returning
+/// the ids of those that were unread.
+pub async fn mark_mentions_read(
+	db: &DatabaseConnection,
+	username: &str,
+	ids: Option<&[i32]>,
+) -> Result<Vec<i32>, DbErr> {
+	let mut update = mentions::Entity::update_many()
+		.col_expr(mentions::Column::ReadAt, Expr::value(Utc::now()))
+		.filter(mentions::Column::Username.eq(username))
+		.filter(mentions::Column::ReadAt.is_null());
+	if let Some(ids) = ids {
+		update = update.filter(mentions::Column::Id.is_in(ids.iter().copied()));
+	}
+
+	Ok(update
+		.exec_with_returning(db)
+		.await?
+		.into_iter()
+		.map(|mention| mention.id)
+		.collect())
+}
+
+pub async fn get_directory_node(db: &DatabaseConnection, id: i32) -> Result<Directory, DbErr> {
+	directory::Entity::find_by_id(id)
+		.one(db)
+		.await?
+		.ok_or(DbErr::RecordNotFound(format!(
+			"Directory with id {id} not found"
+		)))
+}
+
+/// Returns the nodes from `id` up to its root, starting with `id` itself.
+pub async fn get_ancestors(db: &impl ConnectionTrait, id: i32) -> Result<Vec<Directory>, DbErr> {
+	let mut results: Vec<Directory> = Vec::new();
+	let mut visited: HashSet<i32> = HashSet::new();
+	let mut current_id = Some(id);
+
+	while let Some(node_id) = current_id {
+		if !visited.insert(node_id) {
+			return Err(DbErr::Custom(format!(
+				"Directory with id {node_id} is part of a cycle"
 			)));
 		}
+
+		let node = directory::Entity::find_by_id(node_id)
+			.one(db)
+			.await?
+			.ok_or(DbErr::RecordNotFound(format!(
+				"Directory with id {node_id} not found"
+			)))?;
+
+		current_id = node.parent_id;
+		results.push(node);
 	}
 
 	Ok(results)
 }
 
+/// Creates a node at the end of its siblings. The creator of a private node becomes its first
+/// member, so that they can still see it.
 pub async fn create_directory(
 	db: &DatabaseConnection,
 	directory: Directory,
+	creator: &str,
 ) -> Result<Directory, DbErr> {
-	directory::ActiveModel {
+	let txn = db.begin().await?;
+	lock_directory_tree(&txn).await?;
+
+	let created = directory::ActiveModel {
 		name: Set(directory.name),
 		r#type: Set(directory.r#type),
 		parent_id: Set(directory.parent_id),
+		is_private: Set(directory.is_private),
+		position: Set(next_position(&txn, directory.parent_id).await?),
 		..Default::default()
 	}
-	.insert(db)
-	.await
+	.insert(&txn)
+	.await?;
+
+	if created.is_private {
+		add_directory_member(&txn, created.id, creator).await?;
+	}
+
+	txn.commit().await?;
+	Ok(created)
 }
 
-pub async fn get_message_thread(db: &DatabaseConnection, id: i32) -> Result<Vec<Message>, DbErr> {
-	messages::Entity::find()
-		.filter(messages::Column::DirectoryId.eq(id))
+fn children_of(parent_id: Option<i32>) -> SimpleExpr {
+	match parent_id {
+		Some(parent_id) => directory::Column::ParentId.eq(parent_id),
+		None => directory::Column::ParentId.is_null(),
+	}
+}
+
+/// Position after the last child of `parent_id`, for appending a node there.
+async fn next_position(db: &impl ConnectionTrait, parent_id: Option<i32>) -> Result<i32, DbErr> {
+	let last = directory::Entity::find()
+		.filter(children_of(parent_id))
+		.order_by_desc(directory::Column::Position)
+		.one(db)
+		.await?;
+	Ok(last.map_or(0, |node| node.position + 1))
+}
+
+/// How a node with children is deleted.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeleteMode {
+	/// Fail unless the node has no children
+	#[default]
+	Refuse,
+	/// Delete the whole subtree along with the node
+	Cascade,
+	/// Hand the children over to the node's parent
+	Reparent,
+}
+
+/// The nodes affected by deleting one.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DeletedDirectory {
+	pub id: i32,
+	pub parent_id: Option<i32>,
+	/// The node and, when cascading, everything that was below it
+	pub deleted_ids: Vec<i32>,
+	/// Children now under `parent_id`, when reparenting
+	pub reparented_ids: Vec<i32>,
+	/// Members of the node if it was private, who were the only ones besides admins to see it
+	pub members: Option<Vec<String>>,
+}
+
+/// Takes the lock that changes to the shape of the tree hold until commit, so that two moves
+/// cannot together form a cycle that neither would on its own.
+async fn lock_directory_tree(txn: &DatabaseTransaction) -> Result<(), DbErr> {
+	txn.execute(Statement::from_sql_and_values(
+		DbBackend::Postgres,
+		"SELECT pg_advisory_xact_lock($1)",
+		[DIRECTORY_TREE_LOCK_KEY.into()],
+	))
+	.await?;
+	Ok(())
+}
+
+/// Renames, moves and/or reorders a node. A `parent_id` of `Some(None)` makes it a root. Without
+/// a `position`, a moved node goes after its new siblings; with one, the siblings from there on
+/// shift down to make room.
+pub async fn update_directory_node(
+	db: &DatabaseConnection,
+	id: i32,
+	name: Option<String>,
+	parent_id: Option<Option<i32>>,
+	position: Option<i32>,
+) -> Result<Directory, DbErr> {
+	let txn = db.begin().await?;
+	lock_directory_tree(&txn).await?;
+
+	let node = directory::Entity::find_by_id(id)
+		.one(&txn)
+		.await?
+		.ok_or(DbErr::RecordNotFound(format!(
+			"Directory with id {id} not found"
+		)))?;
+
+	if let Some(Some(parent_id)) = parent_id
+		&& get_ancestors(&txn, parent_id)
+			.await?
+			.iter()
+			.any(|ancestor| ancestor.id == id)
+	{
+		return Err(DbErr::Custom(format!(
+			"Moving directory {id} under {parent_id} would create a cycle"
+		)));
+	}
+
+	let new_parent_id = parent_id.unwrap_or(node.parent_id);
+	let moved = new_parent_id != node.parent_id;
+
+	let mut node: directory::ActiveModel = node.into();
+	if let Some(name) = name {
+		node.name = Set(name);
+	}
+	if moved {
+		node.parent_id = Set(new_parent_id);
+	}
+
+	match position {
+		Some(position) => {
+			directory::Entity::update_many()
+				.col_expr(
+					directory::Column::Position,
+					Expr::col(directory::Column::Position).add(1),
+				)
+				.filter(children_of(new_parent_id))
+				.filter(directory::Column::Position.gte(position))
+				.filter(directory::Column::Id.ne(id))
+				.exec(&txn)
+				.await?;
+			node.position = Set(position);
+		}
+		None if moved => node.position = Set(next_position(&txn, new_parent_id).await?),
+		None => {}
+	}
+
+	let node = node.update(&txn).await?;
+
+	txn.commit().await?;
+	Ok(node)
+}
+
+/// Deletes a node and, depending on `mode`, its subtree, returning what changed along with the
+/// attachments of the deleted messages, whose stored objects are left for the caller to delete.
+pub async fn delete_directory_node(
+	db: &DatabaseConnection,
+	id: i32,
+	mode: DeleteMode,
+) -> Result<(DeletedDirectory, Vec<Attachment>), DbErr> {
+	let txn = db.begin().await?;
+	lock_directory_tree(&txn).await?;
+
+	let node = directory::Entity::find_by_id(id)
+		.one(&txn)
+		.await?
+		.ok_or(DbErr::RecordNotFound(format!(
+			"Directory with id {id} not found"
+		)))?;
+
+	let children: Vec<i32> = directory::Entity::find()
+		.filter(directory::Column::ParentId.eq(id))
+		.all(&txn)
+		.await?
+		.into_iter()
+		.map(|child| child.id)
+		.collect();
+
+	let mut deleted = DeletedDirectory {
+		id,
+		parent_id: node.parent_id,
+		deleted_ids: vec![id],
+		reparented_ids: Vec::new(),
+		members: None,
+	};
+
+	// The memberships go with the node, so who could see it has to be recorded beforehand
+	if node.is_private {
+		deleted.members = Some(
+			get_directory_members(&txn, id)
+				.await?
+				.into_iter()
+				.map(|member| member.username)
+				.collect(),
+		);
+	}
+
+	match mode {
+		DeleteMode::Refuse if !children.is_empty() => {
+			return Err(DbErr::Custom(format!(
+				"Directory with id {id} is not empty"
+			)));
+		}
+		DeleteMode::Refuse => {}
+		DeleteMode::Reparent if node.parent_id.is_none() && !children.is_empty() => {
+			return Err(DbErr::Custom(format!(
+				"Directory with id {id} is a root, so its children have nowhere to go"
+			)));
+		}
+		DeleteMode::Reparent => {
+			// The children keep their order, after those already under the new parent
+			let offset = next_position(&txn, node.parent_id).await?;
+			directory::Entity::update_many()
+				.col_expr(directory::Column::ParentId, Expr::value(node.parent_id))
+				.col_expr(
+					directory::Column::Position,
+					Expr::col(directory::Column::Position).add(offset),
+				)
+				.filter(directory::Column::ParentId.eq(id))
+				.exec(&txn)
+				.await?;
+			deleted.reparented_ids = children;
+		}
+		DeleteMode::Cascade => {
+			deleted.deleted_ids = get_directory(&txn, id)
+				.await?
+				.iter()
+				.map(|node| node.id)
+				.collect();
+		}
+	}
+
+	// The rows go with the messages through foreign keys, but the objects need their keys
+	let removed_attachments = attachments::Entity::find()
+		.inner_join(messages::Entity)
+		.filter(messages::Column::DirectoryId.is_in(deleted.deleted_ids.clone()))
+		.all(&txn)
+		.await?;
+
+	directory::Entity::delete_many()
+		.filter(directory::Column::Id.is_in(deleted.deleted_ids.clone()))
+		.exec(&txn)
+		.await?;
+
+	txn.commit().await?;
+	Ok((deleted, removed_attachments))
+}
+
+/// Returns the ids of every directory node `username` is an explicit member of.
+pub async fn get_user_memberships(
+	db: &impl ConnectionTrait,
+	username: &str,
+) -> Result<HashSet<i32>, DbErr> {
+	Ok(directory_members::Entity::find()
+		.filter(directory_members::Column::Username.eq(username))
+		.all(db)
+		.await?
+		.into_iter()
+		.map(|member| member.directory_id)
+		.collect())
+}
+
+pub async fn get_directory_members(
+	db: &impl ConnectionTrait,
+	directory_id: i32,
+) -> Result<Vec<DirectoryMember>, DbErr> {
+	directory_members::Entity::find()
+		.filter(directory_members::Column::DirectoryId.eq(directory_id))
+		.all(db)
+		.await
+}
+
+pub async fn add_directory_member(
+	db: &impl ConnectionTrait,
+	directory_id: i32,
+	username: &str,
+) -> Result<DirectoryMember, DbErr> {
+	directory_members::Entity::insert(directory_members::ActiveModel {
+		directory_id: Set(directory_id),
+		username: Set(username.to_string()),
+	})
+	.on_conflict(
+		OnConflict::columns([
+			directory_members::Column::DirectoryId,
+			directory_members::Column::Username,
+		])
+		.do_nothing()
+		.to_owned(),
+	)
+	.do_nothing()
+	.exec(db)
+	.await?;
+
+	Ok(DirectoryMember {
+		directory_id,
+		username: username.to_string(),
+	})
+}
+
+pub async fn remove_directory_member(
+	db: &DatabaseConnection,
+	directory_id: i32,
+	username: &str,
+) -> Result<(), DbErr> {
+	let result = directory_members::Entity::delete_by_id((directory_id, username.to_string()))
+		.exec(db)
+		.await?;
+
+	if result.rows_affected == 0 {
+		return Err(DbErr::RecordNotFound(format!(
+			"User {username} is not a member of directory {directory_id}"
+		)));
+	}
+
+	Ok(())
+}
+
+/// Returns the DM conversations `username` takes part in, each with its sorted participants.
+pub async fn get_dm_conversations(
+	db: &impl ConnectionTrait,
+	username: &str,
+) -> Result<Vec<(Directory, Vec<String>)>, DbErr> {
+	let memberships = get_user_memberships(db, username).await?;
+
+	let conversations = directory::Entity::find()
+		.filter(directory::Column::Type.eq("dm"))
+		.filter(directory::Column::Id.is_in(memberships))
+		.find_with_related(directory_members::Entity)
+		.all(db)
+		.await?;
+
+	Ok(conversations
+		.into_iter()
+		.map(|(conversation, members)| {
+			let mut participants: Vec<String> =
+				members.into_iter().map(|member| member.username).collect();
+			participants.sort();
+			(conversation, participants)
+		})
+		.collect())
+}
+
+/// Returns the DM conversation between exactly `participants`, which must be sorted, creating it
+/// if there is none yet, along with whether it was created. Conversations between the same
+/// participants are opened one at a time, so that two requests cannot both create one.
+pub async fn open_dm_conversation(
+	db: &DatabaseConnection,
+	name: String,
+	participants: &[String],
+) -> Result<(Directory, bool), DbErr> {
+	let txn = db.begin().await?;
+	txn.execute(Statement::from_sql_and_values(
+		DbBackend::Postgres,
+		"SELECT pg_advisory_xact_lock($1, hashtext($2))",
+		[
+			DM_CONVERSATIONS_LOCK_KEY.into(),
+			participants.join("\n").into(),
+		],
+	))
+	.await?;
+
+	if let Some((conversation, _)) = get_dm_conversations(&txn, &participants[0])
+		.await?
+		.into_iter()
+		.find(|(_, existing)| existing == participants)
+	{
+		txn.commit().await?;
+		return Ok((conversation, false));
+	}
+
+	let conversation = directory::ActiveModel {
+		name: Set(name),
+		r#type: Set("dm".to_string()),
+		parent_id: Set(None),
+		is_private: Set(true),
+		..Default::default()
+	}
+	.insert(&txn)
+	.await?;
+
+	directory_members::Entity::insert_many(participants.iter().map(|username| {
+		directory_members::ActiveModel {
+			directory_id: Set(conversation.id),
+			username: Set(username.clone()),
+		}
+	}))
+	.exec(&txn)
+	.await?;
+
+	txn.commit().await?;
+	Ok((conversation, true))
+}
+
+/// Hides the content of deleted messages, which are kept as tombstones for their replies.
+fn redact_deleted(mut message: Message) -> Message {
+	if message.deleted_at.is_some() {
+		message.content = String::new();
+	}
+	message
+}
+
+pub enum ThreadCursor {
+	Latest,
+	Before(i32),
+	After(i32),
+	/// A window centred on the given message, which is included
+	Around(i32),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReactionSummary {
+	pub emoji: String,
+	pub count: usize,
+	pub usernames: Vec<String>,
+}
+
+/// A message to create, with the ids of the uploads to send along with it.
+#[derive(Deserialize)]
+pub struct NewMessage {
+	#[serde(flatten)]
+	pub message: Message,
+	#[serde(default)]
+	pub attachment_ids: Vec<i32>,
+}
+
+#[derive(Serialize)]
+pub struct MessageWithReactions {
+	#[serde(flatten)]
+	pub message: Message,
+	pub reactions: Vec<ReactionSummary>,
+	pub attachments: Vec<Attachment>,
+}
+
+#[derive(Serialize)]
+pub struct ThreadPage {
+	pub messages: Vec<MessageWithReactions>,
+	pub has_more_before: bool,
+	pub has_more_after: bool,
+}
+
+fn thread_position(message: &Message) -> SimpleExpr {
+	Expr::tuple([Expr::value(message.created_at), Expr::value(message.id)]).into()
+}
+
+fn thread_order() -> SimpleExpr {
+	Expr::tuple([
+		Expr::col(messages::Column::CreatedAt).into(),
+		Expr::col(messages::Column::Id).into(),
+	])
+	.into()
+}
+
+/// Loads up to `limit` messages before or after `anchor` (exclusive), nearest first, along with
+/// whether more messages exist past them.
+async fn get_thread_slice(
+	db: &DatabaseConnection,
+	id: i32,
+	anchor: Option<&Message>,
+	before: bool,
+	limit: u64,
+) -> Result<(Vec<Message>, bool), DbErr> {
+	let mut query = messages::Entity::find().filter(messages::Column::DirectoryId.eq(id));
+
+	if let Some(anchor) = anchor {
+		let position = thread_position(anchor);
+		query = query.filter(if before {
+			Expr::expr(thread_order()).lt(position)
+		} else {
+			Expr::expr(thread_order()).gt(position)
+		});
+	}
+
+	let order = if before { Order::Desc } else { Order::Asc };
+	let mut messages = query
+		.order_by(messages::Column::CreatedAt, order.clone())
+		.order_by(messages::Column::Id, order)
+		.limit(limit + 1)
+		.all(db)
+		.await?;
+
+	let has_more = messages.len() as u64 > limit;
+	messages.truncate(limit as usize);
+
+	Ok((messages.into_iter().map(redact_deleted).collect(), has_more))
+}
+
+/// Returns a page of a thread ordered by `created_at, id`.
+pub async fn get_message_thread(
+	db: &DatabaseConnection,
+	id: i32,
+	cursor: ThreadCursor,
+	limit: u64,
+) -> Result<ThreadPage, DbErr> {
+	let anchor = match cursor {
+		ThreadCursor::Latest => None,
+		ThreadCursor::Before(message_id)
+		| ThreadCursor::After(message_id)
+		| ThreadCursor::Around(message_id) => {
+			let anchor = get_message(db, message_id).await?;
+			if anchor.directory_id != id {
+				return Err(DbErr::RecordNotFound(format!(
+					"Message with id {message_id} not found in thread {id}"
+				)));
+			}
+			Some(anchor)
+		}
+	};
+
+	match cursor {
+		ThreadCursor::Latest | ThreadCursor::Before(_) => {
+			let (mut messages, has_more_before) =
+				get_thread_slice(db, id, anchor.as_ref(), true, limit).await?;
+			messages.reverse();
+
+			Ok(ThreadPage {
+				messages: with_reactions(db, messages).await?,
+				has_more_before,
+				has_more_after: anchor.is_some(),
+			})
+		}
+		ThreadCursor::After(_) => {
+			let (messages, has_more_after) =
+				get_thread_slice(db, id, anchor.as_ref(), false, limit).await?;
+
+			Ok(ThreadPage {
+				messages: with_reactions(db, messages).await?,
+				has_more_before: true,
+				has_more_after,
+			})
+		}
+		ThreadCursor::Around(_) => {
+			let before_limit = limit.saturating_sub(1) / 2;
+			let after_limit = limit.saturating_sub(1) - before_limit;
+
+			let (mut messages, has_more_before) =
+				get_thread_slice(db, id, anchor.as_ref(), true, before_limit).await?;
+			messages.reverse();
+			messages.extend(anchor.clone());
+
+			let (after, has_more_after) =
+				get_thread_slice(db, id, anchor.as_ref(), false, after_limit).await?;
+			messages.extend(after);
+
+			Ok(ThreadPage {
+				messages: with_reactions(db, messages).await?,
+				has_more_before,
+				has_more_after,
+			})
+		}
+	}
+}
+
+pub struct SearchFilters {
+	/// Parsed with `websearch_to_tsquery`, so quoted phrases, `or` and `-` exclusions work
+	pub query: String,
+	pub directory_ids: Vec<i32>,
+	pub author: Option<String>,
+	pub from: Option<DateTime<Utc>>,
+	pub to: Option<DateTime<Utc>>,
+	pub has_replies: Option<bool>,
+}
+
+#[derive(FromQueryResult, Serialize)]
+pub struct SearchResult {
+	#[sea_orm(nested)]
+	#[serde(flatten)]
+	pub message: Message,
+	/// HTML-escaped excerpt of the content with matches wrapped in `<mark>` tags
+	pub snippet: String,
+}
+
+fn search_query(query: &str) -> SimpleExpr {
+	Expr::cust_with_values("websearch_to_tsquery('english', $1)", [query])
+}
+
+/// Searches the non-deleted messages in `filters.directory_ids`, best matches first.
+pub async fn search_messages(
+	db: &DatabaseConnection,
+	filters: SearchFilters,
+	limit: u64,
+	offset: u64,
+) -> Result<Vec<SearchResult>, DbErr> {
+	let query = search_query(&filters.query);
+
+	let mut select = messages::Entity::find()
+		.column_as(
+			Expr::cust_with_exprs(
+				"ts_headline('english', replace(replace(replace(\"messages\".\"content\", \
+				 '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), $1, \
+				 'StartSel=<mark>, StopSel=</mark>, MaxFragments=2, MaxWords=20, MinWords=5')",
+				[query.clone()],
+			),
+			"snippet",
+		)
+		.filter(Expr::cust_with_exprs(
+			"\"messages\".\"content_tsv\" @@ $1",
+			[query.clone()],
+		))
+		.filter(messages::Column::DirectoryId.is_in(filters.directory_ids))
+		.filter(messages::Column::DeletedAt.is_null());
+
+	if let Some(author) = filters.author {
+		select = select.filter(messages::Column::AuthorUsername.eq(author));
+	}
+	if let Some(from) = filters.from {
+		select = select.filter(messages::Column::CreatedAt.gte(from));
+	}
+	if let Some(to) = filters.to {
+		select = select.filter(messages::Column::CreatedAt.lt(to));
+	}
+	if let Some(has_replies) = filters.has_replies {
+		let replies = Expr::cust(
+			"EXISTS (SELECT 1 FROM \"messages\" AS \"replies\" \
+			 WHERE \"replies\".\"parent_id\" = \"messages\".\"id\" \
+			 AND \"replies\".\"deleted_at\" IS NULL)",
+		);
+		select = select.filter(if has_replies { replies } else { replies.not() });
+	}
+
+	select
+		.order_by(
+			Expr::cust_with_exprs("ts_rank(\"messages\".\"content_tsv\", $1)", [query]),
+			Order::Desc,
+		)
+		.order_by_desc(messages::Column::CreatedAt)
+		.order_by_desc(messages::Column::Id)
+		.limit(limit)
+		.offset(offset)
+		.into_model::<SearchResult>()
 		.all(db)
 		.await
 }
@@ -89,21 +1554,27 @@ pub async fn get_message(db: &DatabaseConnection, id: i32) -> Result<Message, Db
 		.filter(messages::Column::Id.eq(id))
 		.one(db)
 		.await?
+		.map(redact_deleted)
 		.ok_or(DbErr::RecordNotFound(format!(
 			"Message with id {id} not found"
 		)))
 }
 
+/// Creates a message along with the uploads in `attachment_ids`, which must have been made by the
+/// author and not yet been sent with another message, and the mentions of `recipients`, as
+/// resolved by `mentions::resolve`. Editing a message later notifies no one.
 pub async fn create_message(
 	db: &DatabaseConnection,
 	author_username: String,
 	message: Message,
-) -> Result<Message, DbErr> {
+	attachment_ids: &[i32],
+	recipients: &[(String, MentionKind)],
+) -> Result<(Message, Vec<Mention>), DbErr> {
 	match directory::Entity::find_by_id(message.directory_id)
 		.one(db)
 		.await?
 	{
-		Some(directory) if directory.r#type == "thread" => {
+		Some(directory) if directory.r#type == "thread" || directory.r#type == "dm" => {
 			if let Some(parent_id) = message.parent_id {
 				let parent = messages::Entity::find_by_id(parent_id)
 					.one(db)
@@ -120,19 +1591,58 @@ pub async fn create_message(
 				}
 			}
 
-			messages::ActiveModel {
-				author_username: Set(author_username),
+			let txn = db.begin().await?;
+
+			let created = messages::ActiveModel {
+				author_username: Set(author_username.clone()),
 				content: Set(message.content),
 				directory_id: Set(message.directory_id),
 				parent_id: Set(message.parent_id),
 				created_at: Set(Utc::now().into()),
 				..Default::default()
 			}
-			.insert(db)
-			.await
+			.insert(&txn)
+			.await?;
+
+			let attachment_ids: HashSet<i32> = attachment_ids.iter().copied().collect();
+			if !attachment_ids.is_empty() {
+				let claimed = attachments::Entity::update_many()
+					.col_expr(attachments::Column::MessageId, Expr::value(created.id))
+					.filter(attachments::Column::Id.is_in(attachment_ids.iter().copied()))
+					.filter(attachments::Column::UploaderUsername.eq(author_username))
+					.filter(attachments::Column::MessageId.is_null())
+					.exec(&txn)
+					.await?;
+
+				if claimed.rows_affected != attachment_ids.len() as u64 {
+					return Err(DbErr::Custom(
+						"Attachments must be unsent uploads of the author".to_string(),
+					));
+				}
+			}
+
+			let mentions = if recipients.is_empty() {
+				Vec::new()
+			} else {
+				mentions::Entity::insert_many(recipients.iter().map(|(username, kind)| {
+					mentions::ActiveModel {
+						message_id: Set(created.id),
+						username: Set(username.clone()),
+						kind: Set(kind.as_str().to_string()),
+						created_at: Set(created.created_at),
+						read_at: Set(None),
+						..Default::default()
+					}
+				}))
+				.exec_with_returning_many(&txn)
+				.await?
+			};
+
+			txn.commit().await?;
+			Ok((created, mentions))
 		}
 		Some(directory) => Err(DbErr::Custom(format!(
-			"Messages can only be created for a directory node of type 'thread', not '{}'",
+			"Messages can only be created for a directory node of type 'thread' or 'dm', not '{}'",
 			directory.r#type
 		))),
 		None => Err(DbErr::RecordNotFound(format!(
@@ -141,3 +1651,394 @@ pub async fn create_message(
 		))),
 	}
 }
+
+/// Replaces the content of a message, keeping the previous content as a revision.
+pub async fn edit_message(
+	db: &DatabaseConnection,
+	id: i32,
+	editor_username: &str,
+	content: String,
+) -> Result<Message, DbErr> {
+	let txn = db.begin().await?;
+
+	let message = messages::Entity::find_by_id(id)
+		.lock_exclusive()
+		.one(&txn)
+		.await?
+		.ok_or(DbErr::RecordNotFound(format!(
+			"Message with id {id} not found"
+		)))?;
+
+	if message.deleted_at.is_some() {
+		return Err(DbErr::Custom(format!(
+			"Message with id {id} has been deleted"
+		)));
+	}
+
+	let now = Utc::now();
+
+	message_revisions::ActiveModel {
+		message_id: Set(message.id),
+		content: Set(message.content.clone()),
+		edited_by: Set(editor_username.to_string()),
+		edited_at: Set(now.into()),
+		..Default::default()
+	}
+	.insert(&txn)
+	.await?;
+
+	let mut message: messages::ActiveModel = message.into();
+	message.content = Set(content);
+	message.edited_at = Set(Some(now.into()));
+	let message = message.update(&txn).await?;
+
+	txn.commit().await?;
+	Ok(message)
+}
+
+/// Turns a message into a tombstone and drops its attachments, returning the tombstone along
+/// with the removed attachments, whose stored objects are left for the caller to delete. Purging
+/// additionally erases its content and revisions from the database instead of only hiding them.
+pub async fn delete_message(
+	db: &DatabaseConnection,
+	id: i32,
+	deleted_by: &str,
+	purge: bool,
+) -> Result<(Message, Vec<Attachment>), DbErr> {
+	let txn = db.begin().await?;
+
+	let message = messages::Entity::find_by_id(id)
+		.lock_exclusive()
+		.one(&txn)
+		.await?
+		.ok_or(DbErr::RecordNotFound(format!(
+			"Message with id {id} not found"
+		)))?;
+
+	let already_deleted = message.deleted_at.is_some();
+	let mut message: messages::ActiveModel = message.into();
+
+	if !already_deleted {
+		message.deleted_at = Set(Some(Utc::now().into()));
+		message.deleted_by = Set(Some(deleted_by.to_string()));
+	}
+
+	message_reactions::Entity::delete_many()
+		.filter(message_reactions::Column::MessageId.eq(id))
+		.exec(&txn)
+		.await?;
+
+	let removed_attachments = attachments::Entity::find()
+		.filter(attachments::Column::MessageId.eq(id))
+		.all(&txn)
+		.await?;
+	attachments::Entity::delete_many()
+		.filter(attachments::Column::MessageId.eq(id))
+		.exec(&txn)
+		.await?;
+
+	if purge {
+		message.content = Set(String::new());
+
+		message_revisions::Entity::delete_many()
+			.filter(message_revisions::Column::MessageId.eq(id))
+			.exec(&txn)
+			.await?;
+	}
+
+	let message = message.update(&txn).await?;
+
+	txn.commit().await?;
+	Ok((redact_deleted(message), removed_attachments))
+}
+
+/// Revisions of deleted messages are hidden along with their content.
+pub async fn get_message_revisions(
+	db: &DatabaseConnection,
+	message_id: i32,
+) -> Result<Vec<MessageRevision>, DbErr> {
+	if get_message(db, message_id).await?.deleted_at.is_some() {
+		return Ok(Vec::new());
+	}
+
+	message_revisions::Entity::find()
+		.filter(message_revisions::Column::MessageId.eq(message_id))
+		.order_by_asc(message_revisions::Column::EditedAt)
+		.all(db)
+		.await
+}
+
+/// The canonical form of a single Unicode emoji, including skin tone, flag and ZWJ sequences, so
+/// that spellings such as `❤` and `❤️` count as the same reaction. Anything else is `None`.
+pub fn canonical_emoji(emoji: &str) -> Option<&'static str> {
+	emojis::get(emoji).map(|emoji| emoji.as_str())
+}
+
+/// Groups the reactions on each of `message_ids` by emoji, in the order each emoji was first used.
+async fn get_reaction_summaries(
+	db: &DatabaseConnection,
+	message_ids: &[i32],
+) -> Result<HashMap<i32, Vec<ReactionSummary>>, DbErr> {
+	let reactions = message_reactions::Entity::find()
+		.filter(message_reactions::Column::MessageId.is_in(message_ids.iter().copied()))
+		.order_by_asc(message_reactions::Column::CreatedAt)
+		.all(db)
+		.await?;
+
+	let mut summaries: HashMap<i32, Vec<ReactionSummary>> = HashMap::new();
+	for reaction in reactions {
+		let message_summaries = summaries.entry(reaction.message_id).or_default();
+		match message_summaries
+			.iter_mut()
+			.find(|summary| summary.emoji == reaction.emoji)
+		{
+			Some(summary) => {
+				summary.count += 1;
+				summary.usernames.push(reaction.username);
+			}
+			None => message_summaries.push(ReactionSummary {
+				emoji: reaction.emoji,
+				count: 1,
+				usernames: vec![reaction.username],
+			}),
+		}
+	}
+
+	Ok(summaries)
+}
+
+pub async fn get_message_reactions(
+	db: &DatabaseConnection,
+	message_id: i32,
+) -> Result<Vec<ReactionSummary>, DbErr> {
+	Ok(get_reaction_summaries(db, &[message_id])
+		.await?
+		.remove(&message_id)
+		.unwrap_or_default())
+}
+
+pub async fn with_reactions(
+	db: &DatabaseConnection,
+	messages: Vec<Message>,
+) -> Result<Vec<MessageWithReactions>, DbErr> {
+	let message_ids: Vec<i32> = messages.iter().map(|message| message.id).collect();
+	let mut summaries = get_reaction_summaries(db, &message_ids).await?;
+	let mut attachments = get_message_attachments(db, &message_ids).await?;
+
+	Ok(messages
+		.into_iter()
+		.map(|message| MessageWithReactions {
+			reactions: summaries.remove(&message.id).unwrap_or_default(),
+			attachments: attachments.remove(&message.id).unwrap_or_default(),
+			message,
+		})
+		.collect())
+}
+
+pub async fn create_attachment(
+	db: &DatabaseConnection,
+	attachment: Attachment,
+) -> Result<Attachment, DbErr> {
+	attachments::ActiveModel {
+		message_id: Set(None),
+		uploader_username: Set(attachment.uploader_username),
+		filename: Set(attachment.filename),
+		content_type: Set(attachment.content_type),
+		size: Set(attachment.size),
+		storage_key: Set(attachment.storage_key),
+		thumbnail_key: Set(attachment.thumbnail_key),
+		created_at: Set(Utc::now().into()),
+		..Default::default()
+	}
+	.insert(db)
+	.await
+}
+
+/// Deletes the uploads created before `created_before` that were never sent with a message,
+/// returning them so that their stored objects can be deleted too.
+pub async fn delete_unsent_attachments(
+	db: &DatabaseConnection,
+	created_before: DateTime<Utc>,
+) -> Result<Vec<Attachment>, DbErr> {
+	attachments::Entity::delete_many()
+		.filter(attachments::Column::MessageId.is_null())
+		.filter(attachments::Column::CreatedAt.lt(created_before))
+		.exec_with_returning(db)
+		.await
+}
+
+pub async fn get_attachment(db: &DatabaseConnection, id: i32) -> Result<Attachment, DbErr> {
+	attachments::Entity::find_by_id(id)
+		.one(db)
+		.await?
+		.ok_or(DbErr::RecordNotFound(format!(
+			"Attachment with id {id} not found"
+		)))
+}
+
+/// Groups the attachments of each message by message id, in upload order.
+pub async fn get_message_attachments(
+	db: &DatabaseConnection,
+	message_ids: &[i32],
+) -> Result<HashMap<i32, Vec<Attachment>>, DbErr> {
+	let mut grouped: HashMap<i32, Vec<Attachment>> = HashMap::new();
+	if message_ids.is_empty() {
+		return Ok(grouped);
+	}
+
+	let attachments = attachments::Entity::find()
+		.filter(attachments::Column::MessageId.is_in(message_ids.iter().copied()))
+		.order_by_asc(attachments::Column::Id)
+		.all(db)
+		.await?;
+
+	for attachment in attachments {
+		if let Some(message_id) = attachment.message_id {
+			grouped.entry(message_id).or_default().push(attachment);
+		}
+	}
+	Ok(grouped)
+}
+
+/// Adds a reaction, doing nothing if `username` already reacted with `emoji`.
+pub async fn add_reaction(
+	db: &DatabaseConnection,
+	message_id: i32,
+	username: &str,
+	emoji: &str,
+) -> Result<Vec<ReactionSummary>, DbErr> {
+	message_reactions::Entity::insert(message_reactions::ActiveModel {
+		message_id: Set(message_id),
+		username: Set(username.to_string()),
+		emoji: Set(emoji.to_string()),
+		created_at: Set(Utc::now().into()),
+	})
+	.on_conflict(
+		OnConflict::columns([
+			message_reactions::Column::MessageId,
+			message_reactions::Column::Username,
+			message_reactions::Column::Emoji,
+		])
+		.do_nothing()
+		.to_owned(),
+	)
+	.do_nothing()
+	.exec(db)
+	.await?;
+
+	get_message_reactions(db, message_id).await
+}
+
+pub async fn remove_reaction(
+	db: &DatabaseConnection,
+	message_id: i32,
+	username: &str,
+	emoji: &str,
+) -> Result<Vec<ReactionSummary>, DbErr> {
+	let result = message_reactions::Entity::delete_by_id((
+		message_id,
+		username.to_string(),
+		emoji.to_string(),
+	))
+	.exec(db)
+	.await?;
+
+	if result.rows_affected == 0 {
+		return Err(DbErr::RecordNotFound(format!(
+			"User {username} has not reacted to message {message_id} with {emoji}"
+		)));
+	}
+
+	get_message_reactions(db, message_id).await
+}
+
+pub async fn get_roles(db: &DatabaseConnection) -> Result<Vec<Role>, DbErr> {
+	roles::Entity::find().all(db).await
+}
+
+pub async fn get_role(db: &DatabaseConnection, name: &str) -> Result<Role, DbErr> {
+	roles::Entity::find_by_id(name)
+		.one(db)
+		.await?
+		.ok_or(DbErr::RecordNotFound(format!("Role {name} not found")))
+}
+
+pub async fn get_directory_grants(
+	db: &DatabaseConnection,
+	directory_id: i32,
+) -> Result<Vec<DirectoryGrant>, DbErr> {
+	directory_grants::Entity::find()
+		.filter(directory_grants::Column::DirectoryId.eq(directory_id))
+		.all(db)
+		.await
+}
+
+/// Returns the grants on `directory_ids` that apply to `username`, including grants to everyone.
+pub async fn get_user_grants(
+	db: &DatabaseConnection,
+	username: &str,
+	directory_ids: &[i32],
+) -> Result<Vec<DirectoryGrant>, DbErr> {
+	directory_grants::Entity::find()
+		.filter(directory_grants::Column::DirectoryId.is_in(directory_ids.iter().copied()))
+		.filter(
+			Condition::any()
+				.add(directory_grants::Column::Username.eq(username))
+				.add(directory_grants::Column::Username.is_null()),
+		)
+		.all(db)
+		.await
+}
+
+pub async fn set_directory_grant(
+	db: &DatabaseConnection,
+	directory_id: i32,
+	grant: DirectoryGrant,
+) -> Result<DirectoryGrant, DbErr> {
+	let existing = directory_grants::Entity::find()
+		.filter(directory_grants::Column::DirectoryId.eq(directory_id))
+		.filter(match &grant.username {
+			Some(username) => directory_grants::Column::Username.eq(username),
+			None => directory_grants::Column::Username.is_null(),
+		})
+		.one(db)
+		.await?;
+
+	match existing {
+		Some(existing) => {
+			let mut existing: directory_grants::ActiveModel = existing.into();
+			existing.role = Set(grant.role);
+			existing.update(db).await
+		}
+		None => {
+			directory_grants::ActiveModel {
+				directory_id: Set(directory_id),
+				username: Set(grant.username),
+				role: Set(grant.role),
+				..Default::default()
+			}
+			.insert(db)
+			.await
+		}
+	}
+}
+
+pub async fn delete_directory_grant(
+	db: &DatabaseConnection,
+	directory_id: i32,
+	grant_id: i32,
+) -> Result<(), DbErr> {
+	let result = directory_grants::Entity::delete_many()
+		.filter(directory_grants::Column::Id.eq(grant_id))
+		.filter(directory_grants::Column::DirectoryId.eq(directory_id))
+		.exec(db)
+		.await?;
+
+	if result.rows_affected == 0 {
+		return Err(DbErr::RecordNotFound(format!(
+			"Grant with id {grant_id} not found on directory {directory_id}"
+		)));
+	}
+
+	Ok(())
+}
@@ -0,0 +1,54 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "attachments")]
+pub struct Model {
+	#[sea_orm(primary_key)]
+	pub id: i32,
+	/// Unset until the upload is sent with a message
+	pub message_id: Option<i32>,
+	pub uploader_username: String,
+	pub filename: String,
+	pub content_type: String,
+	pub size: i64,
+	#[serde(skip)]
+	pub storage_key: String,
+	#[serde(skip)]
+	pub thumbnail_key: Option<String>,
+	pub created_at: DateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(
+		belongs_to = "super::messages::Entity",
+		from = "Column::MessageId",
+		to = "super::messages::Column::Id",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Messages,
+	#[sea_orm(
+		belongs_to = "super::users::Entity",
+		from = "Column::UploaderUsername",
+		to = "super::users::Column::Username",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Users,
+}
+
+impl Related<super::messages::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Messages.def()
+	}
+}
+
+impl Related<super::users::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Users.def()
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
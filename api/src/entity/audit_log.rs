@@ -0,0 +1,23 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// A security-relevant event, such as an account being locked after failed logins.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "audit_log")]
+pub struct Model {
+	#[sea_orm(primary_key)]
+	pub id: i32,
+	pub event: String,
+	/// The account the event concerns
+	pub username: Option<String>,
+	/// Who caused the event, when not the account itself
+	pub actor: Option<String>,
+	pub ip: Option<String>,
+	pub details: Option<String>,
+	pub created_at: DateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
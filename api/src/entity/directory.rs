@@ -9,6 +9,11 @@ pub struct Model {
 	pub name: String,
 	pub r#type: String,
 	pub parent_id: Option<i32>,
+	#[serde(default)]
+	pub is_private: bool,
+	/// Order among siblings, lowest first, with ties broken by id
+	#[serde(default)]
+	pub position: i32,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -18,11 +23,13 @@ pub enum Relation {
 		from = "Column::ParentId",
 		to = "Column::Id",
 		on_update = "Cascade",
-		on_delete = "SetNull"
+		on_delete = "NoAction"
 	)]
 	SelfRef,
 	#[sea_orm(has_many = "super::messages::Entity")]
 	Messages,
+	#[sea_orm(has_many = "super::directory_members::Entity")]
+	DirectoryMembers,
 }
 
 impl Related<super::messages::Entity> for Entity {
@@ -31,4 +38,10 @@ impl Related<super::messages::Entity> for Entity {
 	}
 }
 
+impl Related<super::directory_members::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::DirectoryMembers.def()
+	}
+}
+
 impl ActiveModelBehavior for ActiveModel {}
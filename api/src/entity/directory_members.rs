@@ -0,0 +1,46 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "directory_members")]
+pub struct Model {
+	#[sea_orm(primary_key, auto_increment = false)]
+	#[serde(skip_deserializing)]
+	pub directory_id: i32,
+	#[sea_orm(primary_key, auto_increment = false)]
+	pub username: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(
+		belongs_to = "super::directory::Entity",
+		from = "Column::DirectoryId",
+		to = "super::directory::Column::Id",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Directory,
+	#[sea_orm(
+		belongs_to = "super::users::Entity",
+		from = "Column::Username",
+		to = "super::users::Column::Username",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Users,
+}
+
+impl Related<super::directory::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Directory.def()
+	}
+}
+
+impl Related<super::users::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Users.def()
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,20 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Recent failed logins for a username or a source IP, as told apart by `scope`.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "login_failures")]
+pub struct Model {
+	#[sea_orm(primary_key, auto_increment = false)]
+	pub scope: String,
+	#[sea_orm(primary_key, auto_increment = false)]
+	pub key: String,
+	pub failures: i32,
+	pub last_failed_at: DateTimeWithTimeZone,
+	pub locked_until: Option<DateTimeWithTimeZone>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
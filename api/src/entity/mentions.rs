@@ -0,0 +1,51 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// A message that notifies `username`, either by name or through `@here` or `@everyone`.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "mentions")]
+pub struct Model {
+	#[sea_orm(primary_key)]
+	pub id: i32,
+	pub message_id: i32,
+	pub username: String,
+	/// `user`, `here` or `everyone`, whichever named the user most directly
+	pub kind: String,
+	pub created_at: DateTimeWithTimeZone,
+	/// Unset until the user reads the mention or the thread past it
+	pub read_at: Option<DateTimeWithTimeZone>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(
+		belongs_to = "super::messages::Entity",
+		from = "Column::MessageId",
+		to = "super::messages::Column::Id",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Messages,
+	#[sea_orm(
+		belongs_to = "super::users::Entity",
+		from = "Column::Username",
+		to = "super::users::Column::Username",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Users,
+}
+
+impl Related<super::messages::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Messages.def()
+	}
+}
+
+impl Related<super::users::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Users.def()
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
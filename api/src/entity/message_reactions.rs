@@ -0,0 +1,48 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "message_reactions")]
+pub struct Model {
+	#[sea_orm(primary_key, auto_increment = false)]
+	pub message_id: i32,
+	#[sea_orm(primary_key, auto_increment = false)]
+	pub username: String,
+	#[sea_orm(primary_key, auto_increment = false)]
+	pub emoji: String,
+	pub created_at: DateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(
+		belongs_to = "super::messages::Entity",
+		from = "Column::MessageId",
+		to = "super::messages::Column::Id",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Messages,
+	#[sea_orm(
+		belongs_to = "super::users::Entity",
+		from = "Column::Username",
+		to = "super::users::Column::Username",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Users,
+}
+
+impl Related<super::messages::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Messages.def()
+	}
+}
+
+impl Related<super::users::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Users.def()
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -14,6 +14,12 @@ pub struct Model {
 	#[serde(skip_deserializing)]
 	pub created_at: DateTimeWithTimeZone,
 	pub parent_id: Option<i32>,
+	#[serde(skip_deserializing)]
+	pub edited_at: Option<DateTimeWithTimeZone>,
+	#[serde(skip_deserializing)]
+	pub deleted_at: Option<DateTimeWithTimeZone>,
+	#[serde(skip_deserializing)]
+	pub deleted_by: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -31,7 +37,7 @@ pub enum Relation {
 		from = "Column::ParentId",
 		to = "Column::Id",
 		on_update = "Cascade",
-		on_delete = "SetNull"
+		on_delete = "NoAction"
 	)]
 	SelfRef,
 	#[sea_orm(
@@ -42,6 +48,14 @@ pub enum Relation {
 		on_delete = "Cascade"
 	)]
 	Users,
+	#[sea_orm(has_many = "super::message_revisions::Entity")]
+	MessageRevisions,
+	#[sea_orm(has_many = "super::message_reactions::Entity")]
+	MessageReactions,
+	#[sea_orm(has_many = "super::attachments::Entity")]
+	Attachments,
+	#[sea_orm(has_many = "super::mentions::Entity")]
+	Mentions,
 }
 
 impl Related<super::directory::Entity> for Entity {
@@ -56,4 +70,28 @@ impl Related<super::users::Entity> for Entity {
 	}
 }
 
+impl Related<super::message_revisions::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::MessageRevisions.def()
+	}
+}
+
+impl Related<super::message_reactions::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::MessageReactions.def()
+	}
+}
+
+impl Related<super::attachments::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Attachments.def()
+	}
+}
+
+impl Related<super::mentions::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Mentions.def()
+	}
+}
+
 impl ActiveModelBehavior for ActiveModel {}
@@ -1,3 +1,19 @@
+pub mod attachments;
+pub mod audit_log;
 pub mod directory;
+pub mod directory_grants;
+pub mod directory_members;
+pub mod login_failures;
+pub mod mentions;
+pub mod message_reactions;
+pub mod message_revisions;
 pub mod messages;
+pub mod read_markers;
+pub mod recovery_codes;
+pub mod roles;
+pub mod sessions;
+pub mod user_totp;
 pub mod users;
+pub mod ws_connections;
+pub mod ws_events;
+pub mod ws_nodes;
@@ -0,0 +1,62 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// The last message `username` has read in a thread or DM conversation.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "read_markers")]
+pub struct Model {
+	#[sea_orm(primary_key, auto_increment = false)]
+	pub username: String,
+	#[sea_orm(primary_key, auto_increment = false)]
+	pub directory_id: i32,
+	pub last_read_message_id: i32,
+	pub updated_at: DateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(
+		belongs_to = "super::users::Entity",
+		from = "Column::Username",
+		to = "super::users::Column::Username",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Users,
+	#[sea_orm(
+		belongs_to = "super::directory::Entity",
+		from = "Column::DirectoryId",
+		to = "super::directory::Column::Id",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Directory,
+	#[sea_orm(
+		belongs_to = "super::messages::Entity",
+		from = "Column::LastReadMessageId",
+		to = "super::messages::Column::Id",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Messages,
+}
+
+impl Related<super::users::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Users.def()
+	}
+}
+
+impl Related<super::directory::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Directory.def()
+	}
+}
+
+impl Related<super::messages::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Messages.def()
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,27 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "roles")]
+pub struct Model {
+	#[sea_orm(primary_key, auto_increment = false)]
+	pub name: String,
+	pub can_post: bool,
+	pub can_create_nodes: bool,
+	pub can_moderate: bool,
+	pub can_manage: bool,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(has_many = "super::directory_grants::Entity")]
+	DirectoryGrants,
+}
+
+impl Related<super::directory_grants::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::DirectoryGrants.def()
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,41 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "sessions")]
+pub struct Model {
+	#[sea_orm(primary_key)]
+	pub id: i32,
+	pub username: String,
+	#[serde(skip_serializing)]
+	pub refresh_token_hash: String,
+	/// Hash of the refresh token rotated away last, which must never be presented again
+	#[serde(skip_serializing)]
+	pub previous_refresh_token_hash: Option<String>,
+	pub device_name: Option<String>,
+	pub ip: Option<String>,
+	pub created_at: DateTimeWithTimeZone,
+	pub last_used_at: DateTimeWithTimeZone,
+	pub expires_at: DateTimeWithTimeZone,
+	pub revoked_at: Option<DateTimeWithTimeZone>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(
+		belongs_to = "super::users::Entity",
+		from = "Column::Username",
+		to = "super::users::Column::Username",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Users,
+}
+
+impl Related<super::users::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Users.def()
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,37 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// A user's TOTP secret, which only applies to logins once enrolment has been confirmed with a
+/// code.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "user_totp")]
+pub struct Model {
+	#[sea_orm(primary_key, auto_increment = false)]
+	pub username: String,
+	#[serde(skip_serializing)]
+	pub secret: String,
+	pub enabled_at: Option<DateTimeWithTimeZone>,
+	/// Time step of the last code accepted, so no code can be used twice
+	pub last_used_step: Option<i64>,
+	pub created_at: DateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(
+		belongs_to = "super::users::Entity",
+		from = "Column::Username",
+		to = "super::users::Column::Username",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Users,
+}
+
+impl Related<super::users::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Users.def()
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
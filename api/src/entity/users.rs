@@ -9,6 +9,9 @@ pub struct Model {
 	pub name: String,
 	#[serde(skip_serializing)]
 	pub password: String,
+	/// When the user's last WebSocket connection closed
+	#[serde(skip_deserializing)]
+	pub last_seen_at: Option<DateTimeWithTimeZone>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
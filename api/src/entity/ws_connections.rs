@@ -0,0 +1,49 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// An open WebSocket connection, so that every node knows who is online.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "ws_connections")]
+pub struct Model {
+	#[sea_orm(primary_key, auto_increment = false)]
+	pub node_id: i32,
+	/// Only unique within the node the connection is open on
+	#[sea_orm(primary_key, auto_increment = false)]
+	pub connection_id: i64,
+	pub username: String,
+	pub idle: bool,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(
+		belongs_to = "super::ws_nodes::Entity",
+		from = "Column::NodeId",
+		to = "super::ws_nodes::Column::Id",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	WsNodes,
+	#[sea_orm(
+		belongs_to = "super::users::Entity",
+		from = "Column::Username",
+		to = "super::users::Column::Username",
+		on_update = "Cascade",
+		on_delete = "Cascade"
+	)]
+	Users,
+}
+
+impl Related<super::ws_nodes::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::WsNodes.def()
+	}
+}
+
+impl Related<super::users::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::Users.def()
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
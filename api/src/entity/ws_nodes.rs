@@ -0,0 +1,25 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// A running API process, which keeps `heartbeat_at` current for as long as it is alive.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "ws_nodes")]
+pub struct Model {
+	#[sea_orm(primary_key)]
+	pub id: i32,
+	pub heartbeat_at: DateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+	#[sea_orm(has_many = "super::ws_connections::Entity")]
+	WsConnections,
+}
+
+impl Related<super::ws_connections::Entity> for Entity {
+	fn to() -> RelationDef {
+		Relation::WsConnections.def()
+	}
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1,32 +1,46 @@
+mod attachments;
 mod auth;
 mod db;
 mod entity;
+mod mentions;
+mod permissions;
+mod rate_limit;
 mod routes;
+mod storage;
+mod totp;
 mod websocket;
 use anyhow::{Context, Result};
 use auth::auth_middleware;
 use axum::{
 	Router,
 	body::Body,
+	extract::DefaultBodyLimit,
 	http::{HeaderMap, StatusCode, Uri},
 	middleware,
 	response::Response,
-	routing::{get, post},
+	routing::{delete, get, post},
 };
 use dotenvy::dotenv;
 use migration::{Migrator, MigratorTrait};
+use rate_limit::{prune_buckets, rate_limit_by_ip, rate_limit_by_user, rate_limit_login};
 use reqwest::Client;
 use routes::*;
 use sea_orm::{Database, DatabaseConnection};
-use std::{env, sync::LazyLock};
+use std::{
+	env,
+	net::SocketAddr,
+	sync::{Arc, LazyLock},
+};
+use storage::Storage;
 use tokio::net::TcpListener;
 use tower_http::cors::{Any, CorsLayer};
-use websocket::WsState;
+use websocket::{FanOutBackend, WsState};
 
 #[derive(Clone)]
 pub struct AppState {
 	pub conn: DatabaseConnection,
 	pub ws_state: WsState,
+	pub storage: Arc<dyn Storage>,
 }
 
 static HTTP_CLIENT: LazyLock<Client> = LazyLock::new(Client::new);
@@ -50,7 +64,21 @@ async fn main() -> Result<()> {
 		.await
 		.context("Failed to run database migrations")?;
 
-	let ws_state = WsState::new(1000);
+	let ws_fanout = env::var("WS_FANOUT")
+		.map(|backend| backend.parse::<FanOutBackend>())
+		.unwrap_or(Ok(FanOutBackend::default()))?;
+
+	let ws_state = WsState::new(1000, ws_fanout, &conn)
+		.await
+		.context("Failed to set up WebSocket fan-out")?;
+	let storage = storage::from_env().context("Failed to set up attachment storage")?;
+	tokio::spawn(attachments::sweep_unsent(conn.clone(), storage.clone()));
+	tokio::spawn(prune_buckets());
+	let app_state = AppState {
+		conn,
+		ws_state,
+		storage,
+	};
 
 	let cors = CorsLayer::new()
 		.allow_origin(Any)
@@ -60,24 +88,99 @@ async fn main() -> Result<()> {
 	let app = Router::new()
 		.route("/api/users", get(get_users))
 		.route("/api/users/{username}", get(get_user))
-		.route("/api/directory/{id}", get(get_directory))
-		.route("/api/directory", post(create_directory))
+		.route("/api/users/{username}/unlock", post(unlock_user))
+		.route("/api/presence", get(get_presence_snapshot))
+		.route(
+			"/api/directory/{id}",
+			get(get_directory)
+				.patch(update_directory)
+				.delete(delete_directory),
+		)
+		.route("/api/directory", get(get_roots).post(create_directory))
+		.route("/api/directory/{id}/role", get(get_directory_role))
+		.route("/api/directory/{id}/read", post(mark_read))
+		.route(
+			"/api/directory/{id}/grants",
+			get(get_directory_grants).post(set_directory_grant),
+		)
+		.route(
+			"/api/directory/{id}/grants/{grant_id}",
+			delete(delete_directory_grant),
+		)
+		.route(
+			"/api/directory/{id}/members",
+			get(get_directory_members).post(add_directory_member),
+		)
+		.route(
+			"/api/directory/{id}/members/{username}",
+			delete(remove_directory_member),
+		)
+		.route(
+			"/api/dm",
+			get(get_dm_conversations).post(open_dm_conversation),
+		)
+		.route("/api/roles", get(get_roles))
 		.route("/api/thread/{id}", get(get_message_thread))
-		.route("/api/message/{id}", get(get_message))
+		.route(
+			"/api/message/{id}",
+			get(get_message).patch(edit_message).delete(delete_message),
+		)
+		.route("/api/message/{id}/revisions", get(get_message_revisions))
+		.route("/api/message/{id}/reactions", post(add_reaction))
+		.route(
+			"/api/message/{id}/reactions/{emoji}",
+			delete(remove_reaction),
+		)
 		.route("/api/message", post(create_message))
+		.route(
+			"/api/attachments",
+			post(upload_attachment).layer(DefaultBodyLimit::max(attachments::body_limit())),
+		)
+		.route("/api/attachments/{id}", get(get_attachment))
+		.route(
+			"/api/attachments/{id}/thumbnail",
+			get(get_attachment_thumbnail),
+		)
+		.route("/api/search", get(search_messages))
+		.route("/api/mentions", get(get_mentions))
+		.route("/api/mentions/read", post(mark_mentions_read))
+		.route("/api/sessions", get(get_sessions))
+		.route("/api/sessions/{id}", delete(revoke_session))
+		.route("/api/sessions/revoke-others", post(revoke_other_sessions))
 		.route("/api/ws", get(ws_handler))
-		.route_layer(middleware::from_fn(auth_middleware))
+		.route("/api/mfa", get(get_mfa))
+		.route("/api/mfa/totp", post(enroll_totp))
+		.route("/api/mfa/totp/verify", post(verify_totp))
+		.route("/api/mfa/totp/disable", post(disable_mfa))
+		.route_layer(middleware::from_fn(rate_limit_by_user))
+		.route_layer(middleware::from_fn_with_state(
+			app_state.clone(),
+			auth_middleware,
+		))
 		.route("/api/signup", post(signup))
-		.route("/api/login", post(login))
+		.route(
+			"/api/login",
+			post(login).layer(middleware::from_fn(rate_limit_login)),
+		)
+		.route(
+			"/api/login/mfa",
+			post(login_mfa).layer(middleware::from_fn(rate_limit_login)),
+		)
+		.route("/api/token/refresh", post(refresh_token))
+		.route_layer(middleware::from_fn(rate_limit_by_ip))
 		.fallback(get(move |uri: Uri, headers: HeaderMap| {
 			proxy(uri, app_host, app_port, headers)
 		}))
 		.layer(cors)
-		.with_state(AppState { conn, ws_state });
+		.with_state(app_state);
 
 	let listener = TcpListener::bind(format!("{api_host}:{api_port}")).await?;
 	println!("Server running on http://{api_host}:{api_port}");
-	axum::serve(listener, app).await?;
+	axum::serve(
+		listener,
+		app.into_make_service_with_connect_info::<SocketAddr>(),
+	)
+	.await?;
 
 	Ok(())
 }
@@ -0,0 +1,205 @@
+use crate::db::MentionWithMessage;
+use crate::entity::{mentions::Model as Mention, messages::Model as Message};
+use crate::permissions::{Permission, get_viewers, has_permission};
+use crate::websocket::{PresenceStatus, WsState, get_all_presence};
+use anyhow::Result;
+use sea_orm::{DatabaseConnection, DbErr};
+use std::collections::{HashMap, HashSet};
+
+/// How a message named a mentioned user, from the most to the least direct.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MentionKind {
+	User,
+	Here,
+	Everyone,
+}
+
+impl MentionKind {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			MentionKind::User => "user",
+			MentionKind::Here => "here",
+			MentionKind::Everyone => "everyone",
+		}
+	}
+}
+
+/// The mentions written in a message, before they are checked against its thread.
+#[derive(Default)]
+struct ParsedMentions {
+	usernames: HashSet<String>,
+	here: bool,
+	everyone: bool,
+}
+
+impl ParsedMentions {
+	fn is_empty(&self) -> bool {
+		self.usernames.is_empty() && !self.here && !self.everyone
+	}
+}
+
+fn is_name_char(c: char) -> bool {
+	c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// Finds `@username`, `@here` and `@everyone` in a message. An `@` must not follow a name
+/// character, so that email addresses are not mistaken for mentions, and anything inside a code
+/// span or block is left alone, since it is rendered verbatim.
+fn parse(content: &str) -> ParsedMentions {
+	let mut parsed = ParsedMentions::default();
+	let mut in_code = false;
+	let mut previous = None;
+	let mut chars = content.char_indices().peekable();
+
+	while let Some((index, c)) = chars.next() {
+		match c {
+			'`' => in_code = !in_code,
+			'@' if !in_code && !previous.is_some_and(is_name_char) => {
+				let start = index + 1;
+				let mut end = start;
+				while let Some(&(next_index, next)) = chars.peek() {
+					if !is_name_char(next) {
+						break;
+					}
+					end = next_index + next.len_utf8();
+					chars.next();
+				}
+
+				// Trailing punctuation ends the sentence rather than the name
+				match content[start..end].trim_end_matches(['.', '-']) {
+					"" => {}
+					"here" => parsed.here = true,
+					"everyone" => parsed.everyone = true,
+					username => {
+						parsed.usernames.insert(username.to_string());
+					}
+				}
+				previous = content[..end].chars().next_back();
+				continue;
+			}
+			_ => {}
+		}
+		previous = Some(c);
+	}
+
+	parsed
+}
+
+/// Works out who a message by `author` in `directory_id` mentions. Only users who can see the
+/// thread are notified, so naming someone outside a private node reveals nothing to them, and
+/// authors are never notified of their own messages. `@here` reaches the users who are online.
+///
+/// Since `@here` and `@everyone` notify a whole thread at once, they are plain text unless the
+/// author may moderate it.
+pub async fn resolve(
+	db: &DatabaseConnection,
+	author: &str,
+	directory_id: i32,
+	content: &str,
+) -> Result<Vec<(String, MentionKind)>, DbErr> {
+	let mut parsed = parse(content);
+	if (parsed.here || parsed.everyone)
+		&& !has_permission(db, author, directory_id, Permission::Moderate).await?
+	{
+		parsed.here = false;
+		parsed.everyone = false;
+	}
+	if parsed.is_empty() {
+		return Ok(Vec::new());
+	}
+
+	let presence = match parsed.here {
+		true => get_all_presence(db).await?,
+		false => HashMap::new(),
+	};
+
+	// Only those named can be mentioned, unless the whole thread is
+	let candidates = (!parsed.here && !parsed.everyone).then_some(&parsed.usernames);
+
+	Ok(get_viewers(db, directory_id, candidates)
+		.await?
+		.into_iter()
+		.filter(|username| username != author)
+		.filter_map(|username| {
+			let kind = if parsed.usernames.contains(&username) {
+				MentionKind::User
+			} else if parsed.here && presence.get(&username) == Some(&PresenceStatus::Online) {
+				MentionKind::Here
+			} else if parsed.everyone {
+				MentionKind::Everyone
+			} else {
+				return None;
+			};
+			Some((username, kind))
+		})
+		.collect())
+}
+
+/// Sends each mentioned user a `mention` event, which only reaches their own connections.
+pub async fn notify(ws_state: &WsState, mentions: Vec<Mention>, message: &Message) -> Result<()> {
+	for mention in mentions {
+		ws_state
+			.broadcast(
+				"mentions",
+				"mention",
+				MentionWithMessage {
+					mention,
+					message: message.clone(),
+				},
+			)
+			.await?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn usernames(content: &str) -> Vec<String> {
+		let mut usernames: Vec<String> = parse(content).usernames.into_iter().collect();
+		usernames.sort();
+		usernames
+	}
+
+	#[test]
+	fn parse_finds_usernames() {
+		assert_eq!(usernames("@alice"), ["alice"]);
+		assert_eq!(usernames("hi @bob and @carol_2!"), ["bob", "carol_2"]);
+		assert_eq!(usernames("(@dave) @dave"), ["dave"]);
+		assert_eq!(usernames("@ alone @"), Vec::<String>::new());
+	}
+
+	#[test]
+	fn parse_trims_trailing_punctuation() {
+		assert_eq!(usernames("thanks @alice."), ["alice"]);
+		assert_eq!(usernames("@bob-- see above"), ["bob"]);
+		assert_eq!(usernames("@first.last, hi"), ["first.last"]);
+	}
+
+	#[test]
+	fn parse_finds_here_and_everyone() {
+		let parsed = parse("@here and @everyone");
+		assert!(parsed.here && parsed.everyone && parsed.usernames.is_empty());
+
+		let parsed = parse("@hereafter @everyones");
+		assert!(!parsed.here && !parsed.everyone);
+		assert!(!parsed.is_empty());
+	}
+
+	#[test]
+	fn parse_ignores_email_addresses() {
+		assert_eq!(usernames("mail alice@example.com"), Vec::<String>::new());
+		assert_eq!(usernames("a.b@c.d, or @e"), ["e"]);
+		assert!(parse("ops@here.example").is_empty());
+	}
+
+	#[test]
+	fn parse_ignores_code_spans_and_blocks() {
+		assert_eq!(usernames("`@alice` @bob"), ["bob"]);
+		assert_eq!(usernames("```\n@alice\n``` @bob"), ["bob"]);
+		assert!(parse("`@everyone`").is_empty());
+		// An unclosed span runs to the end
+		assert_eq!(usernames("@bob `@alice"), ["bob"]);
+	}
+}
@@ -0,0 +1,266 @@
+use crate::db;
+use crate::entity::{
+	directory::Model as Directory, messages::Model as Message, roles::Model as Role,
+};
+use sea_orm::{DatabaseConnection, DbErr};
+use std::{collections::HashSet, env, sync::LazyLock};
+
+/// Role used when no grant applies to a user anywhere up the tree.
+const DEFAULT_ROLE: &str = "member";
+const ADMIN_ROLE: &str = "admin";
+
+/// Users listed in `ADMIN_USERNAMES` are admins of every node, including the roots.
+static ADMIN_USERNAMES: LazyLock<HashSet<String>> = LazyLock::new(|| {
+	env::var("ADMIN_USERNAMES")
+		.unwrap_or_default()
+		.split(',')
+		.map(str::trim)
+		.filter(|username| !username.is_empty())
+		.map(str::to_string)
+		.collect()
+});
+
+#[derive(Clone, Copy)]
+pub enum Permission {
+	Post,
+	CreateNodes,
+	Moderate,
+	Manage,
+}
+
+impl Permission {
+	fn granted_by(self, role: &Role) -> bool {
+		match self {
+			Permission::Post => role.can_post,
+			Permission::CreateNodes => role.can_create_nodes,
+			Permission::Moderate => role.can_moderate,
+			Permission::Manage => role.can_manage,
+		}
+	}
+}
+
+pub fn is_admin(username: &str) -> bool {
+	ADMIN_USERNAMES.contains(username)
+}
+
+/// Resolves the role of `username` on a directory node.
+///
+/// The nearest grant walking up the `parent_id` chain wins, and at the same node a grant to the
+/// user takes precedence over a grant to everyone.
+pub async fn get_role(
+	db: &DatabaseConnection,
+	username: &str,
+	directory_id: i32,
+) -> Result<Role, DbErr> {
+	if is_admin(username) {
+		return db::get_role(db, ADMIN_ROLE).await;
+	}
+
+	let ancestor_ids: Vec<i32> = db::get_ancestors(db, directory_id)
+		.await?
+		.iter()
+		.map(|node| node.id)
+		.collect();
+	let grants = db::get_user_grants(db, username, &ancestor_ids).await?;
+
+	let role = ancestor_ids
+		.iter()
+		.find_map(|id| {
+			let mut node_grants = grants.iter().filter(|grant| grant.directory_id == *id);
+			node_grants
+				.clone()
+				.find(|grant| grant.username.is_some())
+				.or_else(|| node_grants.next())
+		})
+		.map_or(DEFAULT_ROLE, |grant| grant.role.as_str());
+
+	db::get_role(db, role).await
+}
+
+/// Whether `username` can see a directory node and its messages.
+///
+/// A private node is only visible to its members, and hides its whole subtree from everyone
+/// else; a node below several private nodes requires membership of each of them.
+pub async fn can_view(
+	db: &DatabaseConnection,
+	username: &str,
+	directory_id: i32,
+) -> Result<bool, DbErr> {
+	let ancestors = db::get_ancestors(db, directory_id).await?;
+	if is_admin(username) || ancestors.iter().all(|node| !node.is_private) {
+		return Ok(true);
+	}
+
+	let memberships = db::get_user_memberships(db, username).await?;
+	Ok(ancestors
+		.iter()
+		.all(|node| !node.is_private || memberships.contains(&node.id)))
+}
+
+/// Returns the users who can see a directory node, as `can_view` decides for each of them. With
+/// `candidates`, only those of them are considered rather than every user.
+pub async fn get_viewers(
+	db: &DatabaseConnection,
+	directory_id: i32,
+	candidates: Option<&HashSet<String>>,
+) -> Result<HashSet<String>, DbErr> {
+	let mut private_members: Vec<HashSet<String>> = Vec::new();
+	for node in db::get_ancestors(db, directory_id).await? {
+		if node.is_private {
+			let members = db::get_directory_members(db, node.id).await?;
+			private_members.push(members.into_iter().map(|member| member.username).collect());
+		}
+	}
+
+	let users = match (candidates, private_members.first()) {
+		(Some(candidates), _) => db::get_users_named(db, candidates.iter().cloned()).await?,
+		// Nobody but the members of the nearest private node and the admins can see below it
+		(None, Some(members)) => {
+			let usernames = members.iter().chain(ADMIN_USERNAMES.iter()).cloned();
+			db::get_users_named(db, usernames).await?
+		}
+		(None, None) => db::get_users(db).await?,
+	};
+
+	Ok(users
+		.into_iter()
+		.map(|user| user.username)
+		.filter(|username| {
+			is_admin(username)
+				|| private_members
+					.iter()
+					.all(|members| members.contains(username))
+		})
+		.collect())
+}
+
+/// Drops the nodes `username` cannot see from a subtree listed parents-first, as returned by
+/// `db::get_directory`. The first node is assumed to be visible.
+pub async fn filter_visible_nodes(
+	db: &DatabaseConnection,
+	username: &str,
+	nodes: Vec<Directory>,
+) -> Result<Vec<Directory>, DbErr> {
+	if is_admin(username) || nodes.iter().skip(1).all(|node| !node.is_private) {
+		return Ok(nodes);
+	}
+
+	let memberships = db::get_user_memberships(db, username).await?;
+	let mut visible_ids: HashSet<i32> = HashSet::new();
+
+	Ok(nodes
+		.into_iter()
+		.enumerate()
+		.filter(|(index, node)| {
+			let parent_visible = *index == 0
+				|| node
+					.parent_id
+					.is_some_and(|parent_id| visible_ids.contains(&parent_id));
+			let visible = parent_visible
+				&& (*index == 0 || !node.is_private || memberships.contains(&node.id));
+			if visible {
+				visible_ids.insert(node.id);
+			}
+			visible
+		})
+		.map(|(_, node)| node)
+		.collect())
+}
+
+/// Returns the roots of every tree and the DM conversations `username` can see.
+pub async fn get_visible_roots(
+	db: &DatabaseConnection,
+	username: &str,
+) -> Result<Vec<Directory>, DbErr> {
+	let memberships = db::get_user_memberships(db, username).await?;
+	Ok(db::get_root_nodes(db)
+		.await?
+		.into_iter()
+		.filter(|root| !root.is_private || is_admin(username) || memberships.contains(&root.id))
+		.collect())
+}
+
+/// Returns the ids of the nodes `username` can see in the subtree of `directory_id`, or in every
+/// tree and DM conversation when none is given.
+pub async fn get_visible_directory_ids(
+	db: &DatabaseConnection,
+	username: &str,
+	directory_id: Option<i32>,
+) -> Result<Vec<i32>, DbErr> {
+	let roots = match directory_id {
+		Some(id) if can_view(db, username, id).await? => vec![id],
+		Some(_) => Vec::new(),
+		None => get_visible_roots(db, username)
+			.await?
+			.into_iter()
+			.map(|root| root.id)
+			.collect(),
+	};
+
+	let mut ids = Vec::new();
+	for root in roots {
+		let nodes = db::get_directory(db, root).await?;
+		ids.extend(
+			filter_visible_nodes(db, username, nodes)
+				.await?
+				.into_iter()
+				.map(|node| node.id),
+		);
+	}
+	Ok(ids)
+}
+
+/// Whether `username` holds `permission` on a node. Nodes the user cannot see grant nothing.
+pub async fn has_permission(
+	db: &DatabaseConnection,
+	username: &str,
+	directory_id: i32,
+	permission: Permission,
+) -> Result<bool, DbErr> {
+	if !can_view(db, username, directory_id).await? {
+		return Ok(false);
+	}
+
+	let role = get_role(db, username, directory_id).await?;
+	Ok(permission.granted_by(&role))
+}
+
+/// Only the author may edit a message, and only while still allowed to post in its thread.
+pub async fn can_edit_message(
+	db: &DatabaseConnection,
+	username: &str,
+	message: &Message,
+) -> Result<bool, DbErr> {
+	if message.author_username != username || message.deleted_at.is_some() {
+		return Ok(false);
+	}
+
+	has_permission(db, username, message.directory_id, Permission::Post).await
+}
+
+/// Reacting counts as posting in the thread, and deleted messages take no new reactions.
+pub async fn can_react_to_message(
+	db: &DatabaseConnection,
+	username: &str,
+	message: &Message,
+) -> Result<bool, DbErr> {
+	if message.deleted_at.is_some() {
+		return Ok(false);
+	}
+
+	has_permission(db, username, message.directory_id, Permission::Post).await
+}
+
+/// Authors may delete their own messages; moderators may delete and purge anyone's.
+pub async fn can_delete_message(
+	db: &DatabaseConnection,
+	username: &str,
+	message: &Message,
+	purge: bool,
+) -> Result<bool, DbErr> {
+	if !purge && message.author_username == username {
+		return can_view(db, username, message.directory_id).await;
+	}
+
+	has_permission(db, username, message.directory_id, Permission::Moderate).await
+}
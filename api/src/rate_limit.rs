@@ -0,0 +1,311 @@
+use crate::auth::Claims;
+use axum::{
+	extract::{ConnectInfo, FromRequestParts, Request, rejection::ExtensionRejection},
+	http::{HeaderMap, HeaderValue, StatusCode, header, request::Parts},
+	middleware::Next,
+	response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+use std::{
+	collections::HashMap,
+	env,
+	net::{IpAddr, SocketAddr},
+	sync::{LazyLock, Mutex},
+	time::{Duration, Instant},
+};
+
+/// How often full buckets are dropped, since they behave like missing ones.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Reverse proxies trusted to tell who the client is through `X-Forwarded-For`, listed in
+/// `TRUSTED_PROXIES` as addresses or networks separated by commas.
+static TRUSTED_PROXIES: LazyLock<Vec<IpNet>> = LazyLock::new(|| {
+	env::var("TRUSTED_PROXIES")
+		.unwrap_or_default()
+		.split(',')
+		.map(str::trim)
+		.filter(|proxy| !proxy.is_empty())
+		.filter_map(|proxy| {
+			let net = proxy
+				.parse::<IpNet>()
+				.or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from));
+			if net.is_err() {
+				eprintln!("Ignoring invalid trusted proxy '{proxy}'");
+			}
+			net.ok()
+		})
+		.collect()
+});
+
+/// Allows `capacity` requests at once, refilled evenly over `period`.
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+	capacity: u32,
+	period: Duration,
+}
+
+impl Limit {
+	const fn new(capacity: u32, secs: u64) -> Self {
+		Self {
+			capacity,
+			period: Duration::from_secs(secs),
+		}
+	}
+
+	/// Parses a limit of the form `<requests>/<seconds>`, e.g. `10/60`.
+	fn parse(s: &str) -> Option<Self> {
+		let (capacity, secs) = s.trim().split_once('/')?;
+		let limit = Limit {
+			capacity: capacity.trim().parse().ok()?,
+			period: Duration::from_secs(secs.trim().parse().ok()?),
+		};
+		(limit.capacity > 0 && !limit.period.is_zero()).then_some(limit)
+	}
+
+	fn from_env(name: &str, default: Limit) -> Self {
+		env::var(name)
+			.ok()
+			.and_then(|limit| Self::parse(&limit))
+			.unwrap_or(default)
+	}
+
+	fn per_second(&self) -> f64 {
+		self.capacity as f64 / self.period.as_secs_f64()
+	}
+}
+
+struct RateLimits {
+	http_user: Limit,
+	http_ip: Limit,
+	login_ip: Limit,
+	ws_default: Limit,
+	/// Limits for specific WebSocket actions, keyed by `<module>.<type>`
+	ws: HashMap<String, Limit>,
+}
+
+static LIMITS: LazyLock<RateLimits> = LazyLock::new(|| {
+	let mut ws: HashMap<String, Limit> = [
+		("messages.typing", Limit::new(30, 60)),
+		("messages.stop_typing", Limit::new(30, 60)),
+		("messages.create_message", Limit::new(30, 60)),
+	]
+	.into_iter()
+	.map(|(action, limit)| (action.to_string(), limit))
+	.collect();
+
+	// Entries look like `messages.typing=10/60`, separated by commas
+	ws.extend(
+		env::var("RATE_LIMIT_WS")
+			.unwrap_or_default()
+			.split(',')
+			.filter_map(|entry| {
+				let (action, limit) = entry.split_once('=')?;
+				Some((action.trim().to_string(), Limit::parse(limit)?))
+			}),
+	);
+
+	RateLimits {
+		http_user: Limit::from_env("RATE_LIMIT_HTTP_USER", Limit::new(300, 60)),
+		http_ip: Limit::from_env("RATE_LIMIT_HTTP_IP", Limit::new(600, 60)),
+		login_ip: Limit::from_env("RATE_LIMIT_LOGIN_IP", Limit::new(10, 300)),
+		ws_default: Limit::from_env("RATE_LIMIT_WS_DEFAULT", Limit::new(120, 60)),
+		ws,
+	}
+});
+
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+	limit: Limit,
+}
+
+impl Bucket {
+	fn refill(&mut self, now: Instant) {
+		let elapsed = now.duration_since(self.updated).as_secs_f64();
+		self.tokens =
+			(self.tokens + elapsed * self.limit.per_second()).min(self.limit.capacity as f64);
+		self.updated = now;
+	}
+}
+
+static BUCKETS: LazyLock<Mutex<HashMap<String, Bucket>>> = LazyLock::new(Default::default);
+
+/// Drops full buckets now and then, so that the clients seen once do not add up.
+pub async fn prune_buckets() {
+	let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+	loop {
+		interval.tick().await;
+		let now = Instant::now();
+		BUCKETS
+			.lock()
+			.unwrap_or_else(|err| err.into_inner())
+			.retain(|_, bucket| {
+				bucket.refill(now);
+				bucket.tokens < bucket.limit.capacity as f64
+			});
+	}
+}
+
+/// Takes a request from the bucket for `key`, or returns how long until one is available.
+fn check(key: String, limit: Limit) -> Result<(), Duration> {
+	let now = Instant::now();
+	let mut buckets = BUCKETS.lock().unwrap_or_else(|err| err.into_inner());
+
+	let bucket = buckets.entry(key).or_insert(Bucket {
+		tokens: limit.capacity as f64,
+		updated: now,
+		limit,
+	});
+	bucket.refill(now);
+
+	if bucket.tokens >= 1.0 {
+		bucket.tokens -= 1.0;
+		Ok(())
+	} else {
+		Err(Duration::from_secs_f64(
+			(1.0 - bucket.tokens) / limit.per_second(),
+		))
+	}
+}
+
+/// Checks a WebSocket envelope against the limit for its action. Actions without a limit of
+/// their own share one bucket per user.
+pub fn check_ws(username: &str, module: &str, r#type: &str) -> Result<(), Duration> {
+	let action = format!("{module}.{type}");
+	match LIMITS.ws.get(&action) {
+		Some(limit) => check(format!("ws:{username}:{action}"), *limit),
+		None => check(format!("ws:{username}"), LIMITS.ws_default),
+	}
+}
+
+fn is_trusted_proxy(ip: IpAddr) -> bool {
+	TRUSTED_PROXIES.iter().any(|proxy| proxy.contains(&ip))
+}
+
+/// Works out the address of the client from the peer of the connection. Each proxy appends the
+/// address it got the request from to `X-Forwarded-For`, so the client is the last entry not
+/// added by a trusted proxy. Entries further left could have been written by anyone.
+fn client_ip(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+	let forwarded: Vec<&str> = headers
+		.get_all("x-forwarded-for")
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|value| value.split(','))
+		.map(str::trim)
+		.collect();
+
+	let mut client = peer;
+	for entry in forwarded.iter().rev() {
+		if !is_trusted_proxy(client) {
+			break;
+		}
+		match entry.parse() {
+			Ok(ip) => client = ip,
+			Err(_) => break,
+		}
+	}
+	client
+}
+
+/// Address of the client that sent a request, behind any trusted proxies.
+pub struct ClientIp(pub IpAddr);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+	type Rejection = ExtensionRejection;
+
+	async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+		let ConnectInfo(addr) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state).await?;
+		Ok(ClientIp(client_ip(addr.ip(), &parts.headers)))
+	}
+}
+
+pub fn too_many_requests(retry_after: Duration) -> Response {
+	let mut response = StatusCode::TOO_MANY_REQUESTS.into_response();
+	response.headers_mut().insert(
+		header::RETRY_AFTER,
+		HeaderValue::from(retry_after.as_secs_f64().ceil() as u64),
+	);
+	response
+}
+
+pub async fn rate_limit_by_ip(ClientIp(ip): ClientIp, request: Request, next: Next) -> Response {
+	match check(format!("ip:{ip}"), LIMITS.http_ip) {
+		Ok(()) => next.run(request).await,
+		Err(retry_after) => too_many_requests(retry_after),
+	}
+}
+
+/// Runs after `auth_middleware`, which provides the claims.
+pub async fn rate_limit_by_user(request: Request, next: Next) -> Response {
+	let key = match request.extensions().get::<Claims>() {
+		Some(claims) => format!("user:{}", claims.sub),
+		None => return next.run(request).await,
+	};
+
+	match check(key, LIMITS.http_user) {
+		Ok(()) => next.run(request).await,
+		Err(retry_after) => too_many_requests(retry_after),
+	}
+}
+
+/// Stricter than the general limit, against credential stuffing.
+pub async fn rate_limit_login(ClientIp(ip): ClientIp, request: Request, next: Next) -> Response {
+	match check(format!("login:{ip}"), LIMITS.login_ip) {
+		Ok(()) => next.run(request).await,
+		Err(retry_after) => too_many_requests(retry_after),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn limit_parse_reads_requests_per_seconds() {
+		let limit = Limit::parse("10/60").unwrap();
+		assert_eq!(limit.capacity, 10);
+		assert_eq!(limit.period, Duration::from_secs(60));
+
+		let limit = Limit::parse(" 5 / 1 ").unwrap();
+		assert_eq!(limit.capacity, 5);
+		assert_eq!(limit.period, Duration::from_secs(1));
+	}
+
+	#[test]
+	fn limit_parse_rejects_malformed_and_empty_limits() {
+		for limit in [
+			"", "10", "10/", "/60", "a/60", "10/b", "-1/60", "10/60/2", "0/60", "10/0",
+		] {
+			assert!(Limit::parse(limit).is_none(), "{limit:?}");
+		}
+	}
+
+	#[test]
+	fn check_allows_bursts_up_to_capacity() {
+		let limit = Limit::new(3, 60);
+		for _ in 0..3 {
+			assert!(check("test:burst".to_string(), limit).is_ok());
+		}
+
+		// One request comes back every 20 seconds
+		let retry_after = check("test:burst".to_string(), limit).unwrap_err();
+		assert!(retry_after > Duration::from_secs(19) && retry_after <= Duration::from_secs(20));
+	}
+
+	#[test]
+	fn check_keeps_a_bucket_per_key() {
+		let limit = Limit::new(1, 60);
+		assert!(check("test:a".to_string(), limit).is_ok());
+		assert!(check("test:a".to_string(), limit).is_err());
+		assert!(check("test:b".to_string(), limit).is_ok());
+	}
+
+	#[test]
+	fn check_refills_over_time() {
+		let limit = Limit::new(1, 1);
+		assert!(check("test:refill".to_string(), limit).is_ok());
+		assert!(check("test:refill".to_string(), limit).is_err());
+		std::thread::sleep(Duration::from_millis(1100));
+		assert!(check("test:refill".to_string(), limit).is_ok());
+	}
+}
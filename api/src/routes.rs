@@ -1,33 +1,1186 @@
 use crate::AppState;
-use crate::auth::{AuthResponse, Credentials, authenticate_user, generate_token, hash_password};
-use crate::db;
+use crate::attachments::{self, RangeRequest};
+use crate::auth::{
+	AuthResponse, Claims, CodeCheck, Credentials, LoginOutcome, LoginResponse, MfaCodeRequest,
+	MfaLoginRequest, MfaStatus, RecoveryCodes, RefreshOutcome, RefreshRequest, SessionInfo, Tokens,
+	TotpEnrollment, authenticate_user, complete_mfa_login, confirm_totp_enrollment, create_session,
+	device_name_from_headers, disable_totp, get_mfa_status, hash_password, refresh_session,
+	start_totp_enrollment, unlock_login,
+};
+use crate::db::{
+	self, DeleteMode, DeletedDirectory, DirectoryWithUnread, MentionsPage, MessageWithReactions,
+	NewMessage, ReactionSummary, SearchFilters, SearchResult, ThreadCursor, ThreadPage,
+};
 use crate::entity::{
-	directory::Model as Directory, messages::Model as Message, users::Model as User,
+	attachments::Model as Attachment, directory::Model as Directory,
+	directory_grants::Model as DirectoryGrant, directory_members::Model as DirectoryMember,
+	message_revisions::Model as MessageRevision, messages::Model as Message,
+	read_markers::Model as ReadMarker, roles::Model as Role, users::Model as User,
+};
+use crate::mentions;
+use crate::permissions::{
+	Permission, can_delete_message, can_edit_message, can_react_to_message, can_view,
+	filter_visible_nodes, get_role, get_visible_directory_ids, get_visible_roots, has_permission,
+	is_admin,
+};
+use crate::rate_limit::{ClientIp, too_many_requests};
+use crate::storage;
+use crate::websocket::{
+	MembershipChangedPayload, MentionsReadPayload, NodeMovedAwayPayload, NodeMovedPayload,
+	PresencePayload, PresenceStatus, ReactionsUpdatedPayload, SessionsRevokedPayload,
+	get_all_presence, handle_socket,
 };
-use crate::websocket::handle_socket;
 use axum::{
 	Extension, Json,
-	extract::{Path, State, WebSocketUpgrade},
-	http::StatusCode,
-	response::{Response, Result},
+	body::{Body, Bytes},
+	extract::{Multipart, Path, Query, State, WebSocketUpgrade},
+	http::{HeaderMap, StatusCode, header},
+	response::{IntoResponse, Response, Result},
 };
+use chrono::{DateTime, Utc};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use sea_orm::DbErr;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+	collections::{BTreeSet, HashMap},
+	net::IpAddr,
+};
+
+const DEFAULT_THREAD_PAGE_SIZE: u64 = 50;
+const MAX_THREAD_PAGE_SIZE: u64 = 200;
+
+const DEFAULT_SEARCH_PAGE_SIZE: u64 = 20;
+const MAX_SEARCH_PAGE_SIZE: u64 = 100;
+
+const DEFAULT_MENTIONS_PAGE_SIZE: u64 = 30;
+const MAX_MENTIONS_PAGE_SIZE: u64 = 100;
+
+/// Largest number of participants, including the creator, in a group DM.
+const MAX_DM_PARTICIPANTS: usize = 10;
+
+/// At most one of `before`, `after` and `around` may be given; with none, the latest messages are
+/// returned.
+#[derive(Deserialize)]
+pub struct ThreadQuery {
+	pub before: Option<i32>,
+	pub after: Option<i32>,
+	pub around: Option<i32>,
+	pub limit: Option<u64>,
+}
+
+/// `directory_id` limits the search to that node's subtree, and `from`/`to` bound `created_at`
+/// as a half-open range.
+#[derive(Deserialize)]
+pub struct SearchQuery {
+	pub q: String,
+	pub author: Option<String>,
+	pub directory_id: Option<i32>,
+	pub from: Option<DateTime<Utc>>,
+	pub to: Option<DateTime<Utc>>,
+	pub has_replies: Option<bool>,
+	pub limit: Option<u64>,
+	pub offset: Option<u64>,
+}
+
+/// `unread` leaves out the mentions already read, and `before` is the id of the last mention of
+/// the previous page.
+#[derive(Deserialize)]
+pub struct MentionsQuery {
+	#[serde(default)]
+	pub unread: bool,
+	pub before: Option<i32>,
+	pub limit: Option<u64>,
+}
+
+/// `ids` are the mentions to mark read; without it, every mention of the user is.
+#[derive(Deserialize)]
+pub struct MarkMentionsReadRequest {
+	pub ids: Option<Vec<i32>>,
+}
+
+/// `resume_from` is the `seq` of the last event the client received before reconnecting.
+#[derive(Deserialize)]
+pub struct WsQuery {
+	pub resume_from: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct DmRequest {
+	pub participants: Vec<String>,
+	#[serde(default)]
+	pub name: String,
+}
+
+/// Every field is optional, and a `parent_id` of null makes the node a root.
+#[derive(Deserialize)]
+pub struct UpdateDirectoryRequest {
+	pub name: Option<String>,
+	#[serde(default, deserialize_with = "deserialize_present")]
+	pub parent_id: Option<Option<i32>>,
+	/// Where to place the node among its (new) siblings
+	pub position: Option<i32>,
+}
+
+/// `depth` limits how many levels below the node are returned, and `nested` returns the nodes as
+/// a tree rather than a list.
+#[derive(Deserialize)]
+pub struct DirectoryQuery {
+	pub depth: Option<u32>,
+	#[serde(default)]
+	pub nested: bool,
+}
+
+#[derive(Serialize)]
+pub struct DirectoryTree {
+	#[serde(flatten)]
+	pub node: DirectoryWithUnread,
+	pub children: Vec<DirectoryTree>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum DirectoryResponse {
+	Flat(Vec<DirectoryWithUnread>),
+	Nested(DirectoryTree),
+}
+
+#[derive(Deserialize)]
+pub struct DeleteDirectoryQuery {
+	#[serde(default)]
+	pub mode: DeleteMode,
+}
+
+#[derive(Deserialize)]
+pub struct MessageEdit {
+	pub content: String,
+}
+
+#[derive(Deserialize)]
+pub struct MarkReadRequest {
+	pub message_id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct ReactionRequest {
+	pub emoji: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteMessageQuery {
+	#[serde(default)]
+	pub purge: bool,
+}
+
+#[derive(Serialize)]
+pub struct DmConversation {
+	#[serde(flatten)]
+	pub conversation: Directory,
+	pub participants: Vec<String>,
+}
+
+/// Tells a field given as null, which becomes `Some(None)`, apart from a missing one.
+fn deserialize_present<'de, T, D>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+	T: Deserialize<'de>,
+	D: Deserializer<'de>,
+{
+	T::deserialize(deserializer).map(Some)
+}
+
+/// Responds with 404 rather than 403 for hidden nodes so their existence is not leaked.
+async fn require_view(
+	app_state: &AppState,
+	username: &str,
+	directory_id: i32,
+) -> Result<(), StatusCode> {
+	match can_view(&app_state.conn, username, directory_id).await {
+		Ok(true) => Ok(()),
+		Ok(false) | Err(DbErr::RecordNotFound(_)) => Err(StatusCode::NOT_FOUND),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR)
+		}
+	}
+}
+
+async fn require_permission(
+	app_state: &AppState,
+	username: &str,
+	directory_id: i32,
+	permission: Permission,
+) -> Result<(), StatusCode> {
+	require_view(app_state, username, directory_id).await?;
+
+	match has_permission(&app_state.conn, username, directory_id, permission).await {
+		Ok(true) => Ok(()),
+		Ok(false) => Err(StatusCode::FORBIDDEN),
+		Err(DbErr::RecordNotFound(_)) => Err(StatusCode::NOT_FOUND),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR)
+		}
+	}
+}
+
+pub async fn get_users(State(app_state): State<AppState>) -> Result<Json<Vec<User>>> {
+	match db::get_users(&app_state.conn).await {
+		Ok(users) => Ok(Json(users)),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+pub async fn get_user(
+	State(app_state): State<AppState>,
+	Path(path_username): Path<String>,
+) -> Result<Json<User>> {
+	match db::get_user(&app_state.conn, &path_username).await {
+		Ok(user) => Ok(Json(user)),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+/// Current status of every user, including those who are offline.
+pub async fn get_presence_snapshot(
+	State(app_state): State<AppState>,
+) -> Result<Json<Vec<PresencePayload>>> {
+	let presence = get_all_presence(&app_state.conn).await.map_err(|e| {
+		eprintln!("{e}");
+		StatusCode::INTERNAL_SERVER_ERROR
+	})?;
+
+	match db::get_users(&app_state.conn).await {
+		Ok(users) => Ok(Json(
+			users
+				.into_iter()
+				.map(|user| PresencePayload {
+					status: presence
+						.get(&user.username)
+						.copied()
+						.unwrap_or(PresenceStatus::Offline),
+					last_seen_at: user.last_seen_at.map(Into::into),
+					username: user.username,
+				})
+				.collect(),
+		)),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+/// Drops the nodes more than `depth` levels below the first one, from a subtree listed
+/// parents-first.
+fn limit_depth(nodes: Vec<DirectoryWithUnread>, depth: u32) -> Vec<DirectoryWithUnread> {
+	let mut depths: HashMap<i32, u32> = HashMap::new();
+
+	nodes
+		.into_iter()
+		.filter(|node| {
+			let node_depth = node
+				.node
+				.parent_id
+				.and_then(|parent_id| depths.get(&parent_id))
+				.map_or(0, |parent_depth| parent_depth + 1);
+			depths.insert(node.node.id, node_depth);
+			node_depth <= depth
+		})
+		.collect()
+}
+
+/// Nests a subtree listed parents-first under its first node.
+fn nest(nodes: Vec<DirectoryWithUnread>) -> Option<DirectoryTree> {
+	let mut children: HashMap<i32, Vec<DirectoryTree>> = HashMap::new();
+	let mut root = None;
+
+	// Walking backwards finishes every node's children before reaching the node itself
+	for (index, node) in nodes.into_iter().enumerate().rev() {
+		let tree = DirectoryTree {
+			children: children.remove(&node.node.id).unwrap_or_default(),
+			node,
+		};
+		match tree.node.node.parent_id {
+			Some(parent_id) if index > 0 => children.entry(parent_id).or_default().insert(0, tree),
+			_ => root = Some(tree),
+		}
+	}
+
+	root
+}
+
+/// Lists the roots of the trees and the DM conversations the user can see.
+pub async fn get_roots(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<Directory>>> {
+	let roots = get_visible_roots(&app_state.conn, &claims.sub)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+	Ok(Json(roots))
+}
+
+pub async fn get_directory(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+	Query(query): Query<DirectoryQuery>,
+) -> Result<Json<DirectoryResponse>> {
+	require_view(&app_state, &claims.sub, id).await?;
+
+	let directory = db::get_directory(&app_state.conn, id).await.map_err(|e| {
+		eprintln!("{e}");
+		StatusCode::INTERNAL_SERVER_ERROR
+	})?;
+
+	let directory = filter_visible_nodes(&app_state.conn, &claims.sub, directory)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	// Counted over the whole subtree, so that nodes at the depth limit still include what is
+	// below them
+	let mut directory = db::with_unread_counts(&app_state.conn, &claims.sub, directory)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	if let Some(depth) = query.depth {
+		directory = limit_depth(directory, depth);
+	}
+
+	if !query.nested {
+		return Ok(Json(DirectoryResponse::Flat(directory)));
+	}
+
+	match nest(directory) {
+		Some(tree) => Ok(Json(DirectoryResponse::Nested(tree))),
+		None => Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+	}
+}
+
+pub async fn create_directory(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Json(directory): Json<Directory>,
+) -> Result<Json<Directory>> {
+	if directory.r#type == "dm" {
+		return Err(StatusCode::BAD_REQUEST.into());
+	}
+
+	match directory.parent_id {
+		Some(parent_id) => {
+			require_permission(&app_state, &claims.sub, parent_id, Permission::CreateNodes).await?;
+
+			match db::get_directory_node(&app_state.conn, parent_id).await {
+				Ok(parent) if parent.r#type != "dm" => {}
+				Ok(_) => return Err(StatusCode::BAD_REQUEST.into()),
+				Err(err) => {
+					eprintln!("{err}");
+					return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+				}
+			}
+		}
+		None if !is_admin(&claims.sub) => return Err(StatusCode::FORBIDDEN.into()),
+		None => {}
+	}
+
+	let created_directory = db::create_directory(&app_state.conn, directory, &claims.sub)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	app_state
+		.ws_state
+		.broadcast_node_created(&app_state.conn, &created_directory)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	Ok(Json(created_directory))
+}
+
+/// Renames, moves and/or reorders a node. Moving needs the right to manage the node and to
+/// create nodes under its new parent, and only admins may turn a node into a root.
+pub async fn update_directory(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+	Json(request): Json<UpdateDirectoryRequest>,
+) -> Result<Json<Directory>> {
+	require_permission(&app_state, &claims.sub, id, Permission::Manage).await?;
+
+	let old_path = db::get_ancestors(&app_state.conn, id).await.map_err(|e| {
+		eprintln!("{e}");
+		StatusCode::INTERNAL_SERVER_ERROR
+	})?;
+
+	if old_path[0].r#type == "dm" || request.position.is_some_and(|position| position < 0) {
+		return Err(StatusCode::BAD_REQUEST.into());
+	}
+
+	match request.parent_id {
+		Some(parent_id) if parent_id == old_path[0].parent_id => {}
+		Some(Some(parent_id)) => {
+			require_permission(&app_state, &claims.sub, parent_id, Permission::CreateNodes).await?;
+
+			match db::get_directory_node(&app_state.conn, parent_id).await {
+				Ok(parent) if parent.r#type != "dm" => {}
+				Ok(_) => return Err(StatusCode::BAD_REQUEST.into()),
+				Err(err) => {
+					eprintln!("{err}");
+					return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+				}
+			}
+		}
+		Some(None) if !is_admin(&claims.sub) => return Err(StatusCode::FORBIDDEN.into()),
+		Some(None) | None => {}
+	}
+
+	let node = match db::update_directory_node(
+		&app_state.conn,
+		id,
+		request.name,
+		request.parent_id,
+		request.position,
+	)
+	.await
+	{
+		Ok(node) => node,
+		Err(DbErr::Custom(_)) => return Err(StatusCode::BAD_REQUEST.into()),
+		Err(DbErr::RecordNotFound(_)) => return Err(StatusCode::NOT_FOUND.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+		}
+	};
+
+	let old_parent_id = old_path[0].parent_id;
+
+	// Connections following where the node used to be need to hear that it left
+	let mut path: Vec<i32> = db::get_ancestors(&app_state.conn, id)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?
+		.iter()
+		.map(|node| node.id)
+		.collect();
+	for node in old_path {
+		if !path.contains(&node.id) {
+			path.push(node.id);
+		}
+	}
+
+	let broadcast = if node.parent_id == old_parent_id {
+		app_state
+			.ws_state
+			.broadcast_to_path(path, "directory", "node_updated", &node)
+			.await
+	} else {
+		let moved = NodeMovedPayload {
+			node: node.clone(),
+			old_parent_id,
+		};
+		let moved_away = NodeMovedAwayPayload {
+			id: node.id,
+			old_parent_id,
+		};
+		async {
+			let ws_state = &app_state.ws_state;
+			ws_state
+				.broadcast_to_path(path.clone(), "directory", "node_moved", &moved)
+				.await?;
+			ws_state
+				.broadcast_to_path(path, "directory", "node_moved_away", &moved_away)
+				.await
+		}
+		.await
+	};
+	broadcast.map_err(|e| {
+		eprintln!("{e}");
+		StatusCode::INTERNAL_SERVER_ERROR
+	})?;
+
+	Ok(Json(node))
+}
+
+/// Deletes a node along with its messages. What happens to its children depends on `mode`,
+/// and cascading is refused if part of the subtree is hidden from the user.
+pub async fn delete_directory(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+	Query(query): Query<DeleteDirectoryQuery>,
+) -> Result<Json<DeletedDirectory>> {
+	require_permission(&app_state, &claims.sub, id, Permission::Manage).await?;
+
+	let path = db::get_ancestors(&app_state.conn, id).await.map_err(|e| {
+		eprintln!("{e}");
+		StatusCode::INTERNAL_SERVER_ERROR
+	})?;
+
+	if path[0].r#type == "dm" {
+		return Err(StatusCode::BAD_REQUEST.into());
+	}
+
+	if let DeleteMode::Cascade = query.mode {
+		let subtree = db::get_directory(&app_state.conn, id).await.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+		let subtree_len = subtree.len();
+
+		let visible = filter_visible_nodes(&app_state.conn, &claims.sub, subtree)
+			.await
+			.map_err(|e| {
+				eprintln!("{e}");
+				StatusCode::INTERNAL_SERVER_ERROR
+			})?;
+
+		if visible.len() < subtree_len {
+			return Err(StatusCode::FORBIDDEN.into());
+		}
+	}
+
+	let (deleted, removed_attachments) =
+		match db::delete_directory_node(&app_state.conn, id, query.mode).await {
+			Ok(deleted) => deleted,
+			Err(DbErr::Custom(_)) => return Err(StatusCode::CONFLICT.into()),
+			Err(DbErr::RecordNotFound(_)) => return Err(StatusCode::NOT_FOUND.into()),
+			Err(err) => {
+				eprintln!("{err}");
+				return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+			}
+		};
+
+	app_state
+		.ws_state
+		.broadcast_to_path(
+			path.iter().map(|node| node.id).collect(),
+			"directory",
+			"node_deleted",
+			&deleted,
+		)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	attachments::remove_objects(app_state.storage.as_ref(), &removed_attachments).await;
+
+	Ok(Json(deleted))
+}
+
+pub async fn mark_read(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+	Json(request): Json<MarkReadRequest>,
+) -> Result<Json<ReadMarker>> {
+	require_view(&app_state, &claims.sub, id).await?;
+
+	match db::get_message(&app_state.conn, request.message_id).await {
+		Ok(message) if message.directory_id == id => {}
+		Ok(_) | Err(DbErr::RecordNotFound(_)) => return Err(StatusCode::BAD_REQUEST.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+		}
+	}
+
+	let marker = db::mark_read(&app_state.conn, &claims.sub, id, request.message_id)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	app_state
+		.ws_state
+		.broadcast("messages", "read_marker_updated", &marker)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	Ok(Json(marker))
+}
+
+pub async fn get_directory_members(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+) -> Result<Json<Vec<DirectoryMember>>> {
+	require_view(&app_state, &claims.sub, id).await?;
+
+	match db::get_directory_members(&app_state.conn, id).await {
+		Ok(members) => Ok(Json(members)),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+/// Only managers of a private node may add others to it.
+pub async fn add_directory_member(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+	Json(member): Json<DirectoryMember>,
+) -> Result<Json<DirectoryMember>> {
+	require_permission(&app_state, &claims.sub, id, Permission::Manage).await?;
+
+	match db::get_directory_node(&app_state.conn, id).await {
+		// DM participants are fixed, since conversations are looked up by participant set
+		Ok(node) if node.is_private && node.r#type != "dm" => {}
+		Ok(_) => return Err(StatusCode::BAD_REQUEST.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+		}
+	}
+
+	match db::get_user(&app_state.conn, &member.username).await {
+		Ok(_) => {}
+		Err(DbErr::RecordNotFound(_)) => return Err(StatusCode::BAD_REQUEST.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+		}
+	}
+
+	let member = db::add_directory_member(&app_state.conn, id, &member.username)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	broadcast_membership_changed(&app_state, id, member.username.clone()).await?;
+	Ok(Json(member))
+}
+
+/// Managers of a node may remove anyone from it, and every member may leave it.
+pub async fn remove_directory_member(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path((id, username)): Path<(i32, String)>,
+) -> Result<StatusCode> {
+	if username == claims.sub {
+		require_view(&app_state, &claims.sub, id).await?;
+	} else {
+		require_permission(&app_state, &claims.sub, id, Permission::Manage).await?;
+	}
+
+	match db::get_directory_node(&app_state.conn, id).await {
+		Ok(node) if node.r#type != "dm" => {}
+		Ok(_) => return Err(StatusCode::BAD_REQUEST.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+		}
+	}
+
+	match db::remove_directory_member(&app_state.conn, id, &username).await {
+		Ok(()) => {}
+		Err(DbErr::RecordNotFound(_)) => return Err(StatusCode::NOT_FOUND.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+		}
+	}
+
+	broadcast_membership_changed(&app_state, id, username).await?;
+	Ok(StatusCode::NO_CONTENT)
+}
+
+/// Tells `username` that what they can see changed. It goes out without a topic, since they
+/// may not follow the node yet, or no longer be able to.
+async fn broadcast_membership_changed(
+	app_state: &AppState,
+	directory_id: i32,
+	username: String,
+) -> Result<(), StatusCode> {
+	app_state
+		.ws_state
+		.broadcast(
+			"directory",
+			"membership_changed",
+			&MembershipChangedPayload {
+				directory_id,
+				username,
+			},
+		)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})
+}
+
+pub async fn get_dm_conversations(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<DmConversation>>> {
+	match db::get_dm_conversations(&app_state.conn, &claims.sub).await {
+		Ok(conversations) => Ok(Json(
+			conversations
+				.into_iter()
+				.map(|(conversation, participants)| DmConversation {
+					conversation,
+					participants,
+				})
+				.collect(),
+		)),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+/// Opens the DM conversation between the caller and `participants`, reusing an existing
+/// conversation with exactly the same participant set.
+pub async fn open_dm_conversation(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Json(request): Json<DmRequest>,
+) -> Result<Json<DmConversation>> {
+	let participants: Vec<String> = request
+		.participants
+		.into_iter()
+		.chain([claims.sub.clone()])
+		.collect::<BTreeSet<String>>()
+		.into_iter()
+		.collect();
+
+	if participants.len() < 2 || participants.len() > MAX_DM_PARTICIPANTS {
+		return Err(StatusCode::BAD_REQUEST.into());
+	}
+
+	for username in &participants {
+		match db::get_user(&app_state.conn, username).await {
+			Ok(_) => {}
+			Err(DbErr::RecordNotFound(_)) => return Err(StatusCode::BAD_REQUEST.into()),
+			Err(err) => {
+				eprintln!("{err}");
+				return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+			}
+		}
+	}
+
+	let (conversation, created) =
+		db::open_dm_conversation(&app_state.conn, request.name, &participants)
+			.await
+			.map_err(|e| {
+				eprintln!("{e}");
+				StatusCode::INTERNAL_SERVER_ERROR
+			})?;
+
+	if created {
+		app_state
+			.ws_state
+			.broadcast_node_created(&app_state.conn, &conversation)
+			.await
+			.map_err(|e| {
+				eprintln!("{e}");
+				StatusCode::INTERNAL_SERVER_ERROR
+			})?;
+	}
+
+	Ok(Json(DmConversation {
+		conversation,
+		participants,
+	}))
+}
+
+pub async fn get_roles(State(app_state): State<AppState>) -> Result<Json<Vec<Role>>> {
+	match db::get_roles(&app_state.conn).await {
+		Ok(roles) => Ok(Json(roles)),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+pub async fn get_directory_role(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+) -> Result<Json<Role>> {
+	require_view(&app_state, &claims.sub, id).await?;
+
+	match get_role(&app_state.conn, &claims.sub, id).await {
+		Ok(role) => Ok(Json(role)),
+		Err(DbErr::RecordNotFound(_)) => Err(StatusCode::NOT_FOUND.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+pub async fn get_directory_grants(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+) -> Result<Json<Vec<DirectoryGrant>>> {
+	require_permission(&app_state, &claims.sub, id, Permission::Manage).await?;
+
+	match db::get_directory_grants(&app_state.conn, id).await {
+		Ok(grants) => Ok(Json(grants)),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+pub async fn set_directory_grant(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+	Json(grant): Json<DirectoryGrant>,
+) -> Result<Json<DirectoryGrant>> {
+	require_permission(&app_state, &claims.sub, id, Permission::Manage).await?;
+
+	match db::get_role(&app_state.conn, &grant.role).await {
+		Ok(_) => {}
+		Err(DbErr::RecordNotFound(_)) => return Err(StatusCode::BAD_REQUEST.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+		}
+	}
+
+	if let Some(username) = &grant.username {
+		match db::get_user(&app_state.conn, username).await {
+			Ok(_) => {}
+			Err(DbErr::RecordNotFound(_)) => return Err(StatusCode::BAD_REQUEST.into()),
+			Err(err) => {
+				eprintln!("{err}");
+				return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+			}
+		}
+	}
+
+	match db::set_directory_grant(&app_state.conn, id, grant).await {
+		Ok(grant) => Ok(Json(grant)),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+pub async fn delete_directory_grant(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path((id, grant_id)): Path<(i32, i32)>,
+) -> Result<StatusCode> {
+	require_permission(&app_state, &claims.sub, id, Permission::Manage).await?;
+
+	match db::delete_directory_grant(&app_state.conn, id, grant_id).await {
+		Ok(()) => Ok(StatusCode::NO_CONTENT),
+		Err(DbErr::RecordNotFound(_)) => Err(StatusCode::NOT_FOUND.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+pub async fn get_message_thread(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+	Query(query): Query<ThreadQuery>,
+) -> Result<Json<ThreadPage>> {
+	require_view(&app_state, &claims.sub, id).await?;
+
+	let cursor = match (query.before, query.after, query.around) {
+		(None, None, None) => ThreadCursor::Latest,
+		(Some(before), None, None) => ThreadCursor::Before(before),
+		(None, Some(after), None) => ThreadCursor::After(after),
+		(None, None, Some(around)) => ThreadCursor::Around(around),
+		_ => return Err(StatusCode::BAD_REQUEST.into()),
+	};
+	let limit = query
+		.limit
+		.unwrap_or(DEFAULT_THREAD_PAGE_SIZE)
+		.clamp(1, MAX_THREAD_PAGE_SIZE);
+
+	match db::get_message_thread(&app_state.conn, id, cursor, limit).await {
+		Ok(page) => Ok(Json(page)),
+		Err(DbErr::RecordNotFound(_)) => Err(StatusCode::NOT_FOUND.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+pub async fn search_messages(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<SearchResult>>> {
+	if query.q.trim().is_empty() {
+		return Err(StatusCode::BAD_REQUEST.into());
+	}
+
+	if let Some(directory_id) = query.directory_id {
+		require_view(&app_state, &claims.sub, directory_id).await?;
+	}
+
+	let directory_ids = get_visible_directory_ids(&app_state.conn, &claims.sub, query.directory_id)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	let filters = SearchFilters {
+		query: query.q,
+		directory_ids,
+		author: query.author,
+		from: query.from,
+		to: query.to,
+		has_replies: query.has_replies,
+	};
+	let limit = query
+		.limit
+		.unwrap_or(DEFAULT_SEARCH_PAGE_SIZE)
+		.clamp(1, MAX_SEARCH_PAGE_SIZE);
+
+	match db::search_messages(&app_state.conn, filters, limit, query.offset.unwrap_or(0)).await {
+		Ok(results) => Ok(Json(results)),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+pub async fn get_mentions(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Query(query): Query<MentionsQuery>,
+) -> Result<Json<MentionsPage>> {
+	// Mentions stay hidden while the user cannot see their thread, such as after leaving it
+	let directory_ids = get_visible_directory_ids(&app_state.conn, &claims.sub, None)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+	let limit = query
+		.limit
+		.unwrap_or(DEFAULT_MENTIONS_PAGE_SIZE)
+		.clamp(1, MAX_MENTIONS_PAGE_SIZE);
+
+	match db::get_mentions(
+		&app_state.conn,
+		&claims.sub,
+		directory_ids,
+		query.unread,
+		query.before,
+		limit,
+	)
+	.await
+	{
+		Ok(page) => Ok(Json(page)),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+pub async fn mark_mentions_read(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Json(request): Json<MarkMentionsReadRequest>,
+) -> Result<Json<MentionsReadPayload>> {
+	let ids = db::mark_mentions_read(&app_state.conn, &claims.sub, request.ids.as_deref())
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+	let payload = MentionsReadPayload {
+		username: claims.sub,
+		ids,
+	};
+
+	app_state
+		.ws_state
+		.broadcast("mentions", "mentions_read", &payload)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	Ok(Json(payload))
+}
+
+async fn get_visible_message(
+	app_state: &AppState,
+	username: &str,
+	id: i32,
+) -> Result<Message, StatusCode> {
+	let message = match db::get_message(&app_state.conn, id).await {
+		Ok(message) => message,
+		Err(DbErr::RecordNotFound(_)) => return Err(StatusCode::NOT_FOUND),
+		Err(err) => {
+			eprintln!("{err}");
+			return Err(StatusCode::INTERNAL_SERVER_ERROR);
+		}
+	};
+
+	require_view(app_state, username, message.directory_id).await?;
+
+	Ok(message)
+}
+
+pub async fn get_message(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+) -> Result<Json<MessageWithReactions>> {
+	let message = get_visible_message(&app_state, &claims.sub, id).await?;
+
+	match db::with_reactions(&app_state.conn, vec![message]).await {
+		Ok(mut messages) => Ok(Json(messages.remove(0))),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+pub async fn edit_message(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+	Json(edit): Json<MessageEdit>,
+) -> Result<Json<Message>> {
+	if edit.content.trim().is_empty() {
+		return Err(StatusCode::BAD_REQUEST.into());
+	}
+
+	let message = get_visible_message(&app_state, &claims.sub, id).await?;
+
+	match can_edit_message(&app_state.conn, &claims.sub, &message).await {
+		Ok(true) => {}
+		Ok(false) => return Err(StatusCode::FORBIDDEN.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+		}
+	}
+
+	let edited_message = db::edit_message(&app_state.conn, id, &claims.sub, edit.content)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
 
-pub async fn get_users(State(app_state): State<AppState>) -> Result<Json<Vec<User>>> {
-	match db::get_users(&app_state.conn).await {
-		Ok(users) => Ok(Json(users)),
+	app_state
+		.ws_state
+		.broadcast_to_directory(
+			&app_state.conn,
+			edited_message.directory_id,
+			"messages",
+			"message_edited",
+			&edited_message,
+		)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	Ok(Json(edited_message))
+}
+
+pub async fn delete_message(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+	Query(query): Query<DeleteMessageQuery>,
+) -> Result<Json<Message>> {
+	let message = get_visible_message(&app_state, &claims.sub, id).await?;
+
+	match can_delete_message(&app_state.conn, &claims.sub, &message, query.purge).await {
+		Ok(true) => {}
+		Ok(false) => return Err(StatusCode::FORBIDDEN.into()),
 		Err(err) => {
 			eprintln!("{err}");
-			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
 		}
 	}
+
+	let (deleted_message, removed_attachments) =
+		db::delete_message(&app_state.conn, id, &claims.sub, query.purge)
+			.await
+			.map_err(|e| {
+				eprintln!("{e}");
+				StatusCode::INTERNAL_SERVER_ERROR
+			})?;
+	attachments::remove_objects(app_state.storage.as_ref(), &removed_attachments).await;
+
+	app_state
+		.ws_state
+		.broadcast_to_directory(
+			&app_state.conn,
+			deleted_message.directory_id,
+			"messages",
+			"message_deleted",
+			&deleted_message,
+		)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	Ok(Json(deleted_message))
 }
 
-pub async fn get_user(
+pub async fn get_message_revisions(
 	State(app_state): State<AppState>,
-	Path(path_username): Path<String>,
-) -> Result<Json<User>> {
-	match db::get_user(&app_state.conn, &path_username).await {
-		Ok(user) => Ok(Json(user)),
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+) -> Result<Json<Vec<MessageRevision>>> {
+	get_visible_message(&app_state, &claims.sub, id).await?;
+
+	match db::get_message_revisions(&app_state.conn, id).await {
+		Ok(revisions) => Ok(Json(revisions)),
 		Err(err) => {
 			eprintln!("{err}");
 			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
@@ -35,84 +1188,405 @@ pub async fn get_user(
 	}
 }
 
-pub async fn get_directory(
+async fn broadcast_reactions(
+	app_state: &AppState,
+	message: &Message,
+	reactions: &[ReactionSummary],
+) -> Result<(), StatusCode> {
+	app_state
+		.ws_state
+		.broadcast_to_directory(
+			&app_state.conn,
+			message.directory_id,
+			"messages",
+			"reactions_updated",
+			&ReactionsUpdatedPayload {
+				message_id: message.id,
+				directory_id: message.directory_id,
+				reactions: reactions.to_vec(),
+			},
+		)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})
+}
+
+pub async fn add_reaction(
 	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
 	Path(id): Path<i32>,
-) -> Result<Json<Vec<Directory>>> {
-	match db::get_directory(&app_state.conn, id).await {
-		Ok(directory) => Ok(Json(directory)),
+	Json(request): Json<ReactionRequest>,
+) -> Result<Json<Vec<ReactionSummary>>> {
+	let Some(emoji) = db::canonical_emoji(&request.emoji) else {
+		return Err(StatusCode::BAD_REQUEST.into());
+	};
+
+	let message = get_visible_message(&app_state, &claims.sub, id).await?;
+
+	match can_react_to_message(&app_state.conn, &claims.sub, &message).await {
+		Ok(true) => {}
+		Ok(false) => return Err(StatusCode::FORBIDDEN.into()),
 		Err(err) => {
 			eprintln!("{err}");
-			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
 		}
 	}
+
+	let reactions = db::add_reaction(&app_state.conn, id, &claims.sub, emoji)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	broadcast_reactions(&app_state, &message, &reactions).await?;
+
+	Ok(Json(reactions))
 }
 
-pub async fn create_directory(
+pub async fn remove_reaction(
 	State(app_state): State<AppState>,
-	Json(directory): Json<Directory>,
-) -> Result<Json<Directory>> {
-	match db::create_directory(&app_state.conn, directory).await {
-		Ok(created_directory) => Ok(Json(created_directory)),
+	Extension(claims): Extension<Claims>,
+	Path((id, emoji)): Path<(i32, String)>,
+) -> Result<Json<Vec<ReactionSummary>>> {
+	let message = get_visible_message(&app_state, &claims.sub, id).await?;
+	let emoji = db::canonical_emoji(&emoji).unwrap_or(&emoji);
+
+	let reactions = match db::remove_reaction(&app_state.conn, id, &claims.sub, emoji).await {
+		Ok(reactions) => reactions,
+		Err(DbErr::RecordNotFound(_)) => return Err(StatusCode::NOT_FOUND.into()),
 		Err(err) => {
 			eprintln!("{err}");
-			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
 		}
+	};
+
+	broadcast_reactions(&app_state, &message, &reactions).await?;
+
+	Ok(Json(reactions))
+}
+
+pub async fn create_message(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Json(NewMessage {
+		message,
+		attachment_ids,
+	}): Json<NewMessage>,
+) -> Result<Json<MessageWithReactions>> {
+	require_permission(
+		&app_state,
+		&claims.sub,
+		message.directory_id,
+		Permission::Post,
+	)
+	.await?;
+
+	if attachment_ids.len() > attachments::MAX_PER_MESSAGE {
+		return Err(StatusCode::BAD_REQUEST.into());
 	}
+
+	let recipients = mentions::resolve(
+		&app_state.conn,
+		&claims.sub,
+		message.directory_id,
+		&message.content,
+	)
+	.await
+	.map_err(|e| {
+		eprintln!("{e}");
+		StatusCode::INTERNAL_SERVER_ERROR
+	})?;
+
+	let (created_message, created_mentions) = match db::create_message(
+		&app_state.conn,
+		claims.sub,
+		message,
+		&attachment_ids,
+		&recipients,
+	)
+	.await
+	{
+		Ok(created) => created,
+		Err(DbErr::Custom(_)) => return Err(StatusCode::BAD_REQUEST.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+		}
+	};
+
+	let created_message = db::with_reactions(&app_state.conn, vec![created_message])
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?
+		.remove(0);
+
+	app_state
+		.ws_state
+		.broadcast_to_directory(
+			&app_state.conn,
+			created_message.message.directory_id,
+			"messages",
+			"message_created",
+			&created_message,
+		)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	mentions::notify(
+		&app_state.ws_state,
+		created_mentions,
+		&created_message.message,
+	)
+	.await
+	.map_err(|e| {
+		eprintln!("{e}");
+		StatusCode::INTERNAL_SERVER_ERROR
+	})?;
+
+	Ok(Json(created_message))
 }
 
-pub async fn get_message_thread(
+/// Stores the `file` field of a multipart form as an upload, which can then be sent with a
+/// message through its id.
+pub async fn upload_attachment(
 	State(app_state): State<AppState>,
-	Path(id): Path<i32>,
-) -> Result<Json<Vec<Message>>> {
-	match db::get_message_thread(&app_state.conn, id).await {
-		Ok(thread) => Ok(Json(thread)),
+	Extension(claims): Extension<Claims>,
+	mut multipart: Multipart,
+) -> Result<Json<Attachment>> {
+	let mut field = loop {
+		match multipart.next_field().await {
+			Ok(Some(field)) if field.name() == Some("file") => break field,
+			Ok(Some(_)) => {}
+			Ok(None) => return Err(StatusCode::BAD_REQUEST.into()),
+			Err(err) => return Err(err.status().into()),
+		}
+	};
+
+	let filename = attachments::sanitize_filename(field.file_name().unwrap_or_default());
+	let content_type = field
+		.content_type()
+		.map(attachments::essence)
+		.unwrap_or_else(|| "application/octet-stream".to_string());
+
+	let mut data = Vec::new();
+	loop {
+		match field.chunk().await {
+			Ok(Some(chunk)) if data.len() + chunk.len() > *attachments::MAX_SIZE => {
+				return Err(StatusCode::PAYLOAD_TOO_LARGE.into());
+			}
+			Ok(Some(chunk)) => data.extend_from_slice(&chunk),
+			Ok(None) => break,
+			Err(err) => return Err(err.status().into()),
+		}
+	}
+	let data = Bytes::from(data);
+
+	// Checked once the whole file is read, since clients still sending it would not see the
+	// response
+	if data.is_empty() {
+		return Err(StatusCode::BAD_REQUEST.into());
+	}
+	if !attachments::is_allowed_type(&content_type)
+		|| !attachments::matches_content(&content_type, &data)
+	{
+		return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into());
+	}
+
+	let thumbnail = {
+		let content_type = content_type.clone();
+		let data = data.clone();
+		tokio::task::spawn_blocking(move || attachments::thumbnail(&content_type, &data))
+			.await
+			.map_err(|e| {
+				eprintln!("{e}");
+				StatusCode::INTERNAL_SERVER_ERROR
+			})?
+	};
+
+	let storage_key = storage::new_key("attachments");
+	let size = data.len() as i64;
+	if let Err(err) = app_state
+		.storage
+		.put(&storage_key, data, &content_type)
+		.await
+	{
+		eprintln!("{err}");
+		return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+	}
+
+	// An upload is still usable without its preview
+	let mut thumbnail_key = None;
+	if let Some(thumbnail) = thumbnail {
+		let key = storage::new_key("thumbnails");
+		match app_state
+			.storage
+			.put(&key, thumbnail.into(), "image/png")
+			.await
+		{
+			Ok(()) => thumbnail_key = Some(key),
+			Err(err) => eprintln!("{err}"),
+		}
+	}
+
+	let attachment = Attachment {
+		id: 0,
+		message_id: None,
+		uploader_username: claims.sub,
+		filename,
+		content_type,
+		size,
+		storage_key,
+		thumbnail_key,
+		created_at: Utc::now().into(),
+	};
+	match db::create_attachment(&app_state.conn, attachment.clone()).await {
+		Ok(attachment) => Ok(Json(attachment)),
 		Err(err) => {
 			eprintln!("{err}");
+			attachments::remove_objects(app_state.storage.as_ref(), &[attachment]).await;
 			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
 		}
 	}
 }
 
-pub async fn get_message(
-	State(app_state): State<AppState>,
-	Path(id): Path<i32>,
-) -> Result<Json<Message>> {
-	match db::get_message(&app_state.conn, id).await {
-		Ok(message) => Ok(Json(message)),
+/// Attachments are visible along with their message, and unsent uploads only to the uploader.
+async fn get_visible_attachment(
+	app_state: &AppState,
+	username: &str,
+	id: i32,
+) -> Result<Attachment, StatusCode> {
+	let attachment = match db::get_attachment(&app_state.conn, id).await {
+		Ok(attachment) => attachment,
+		Err(DbErr::RecordNotFound(_)) => return Err(StatusCode::NOT_FOUND),
 		Err(err) => {
 			eprintln!("{err}");
-			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+			return Err(StatusCode::INTERNAL_SERVER_ERROR);
+		}
+	};
+
+	match attachment.message_id {
+		Some(message_id) => {
+			get_visible_message(app_state, username, message_id).await?;
 		}
+		None if attachment.uploader_username != username => return Err(StatusCode::NOT_FOUND),
+		None => {}
 	}
+
+	Ok(attachment)
 }
 
-pub async fn create_message(
+/// Streams an attachment, or the single byte range asked for through `Range`.
+pub async fn get_attachment(
 	State(app_state): State<AppState>,
-	Extension(username): Extension<String>,
-	Json(message): Json<Message>,
-) -> Result<Json<Message>> {
-	let created_message = db::create_message(&app_state.conn, username, message)
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+	headers: HeaderMap,
+) -> Result<Response> {
+	let attachment = get_visible_attachment(&app_state, &claims.sub, id).await?;
+	let size = attachment.size as u64;
+
+	let range = headers
+		.get(header::RANGE)
+		.and_then(|range| range.to_str().ok());
+	let (status, range) = match attachments::parse_range(range, size) {
+		RangeRequest::Full => (StatusCode::OK, None),
+		RangeRequest::Partial(range) => (StatusCode::PARTIAL_CONTENT, Some(range)),
+		RangeRequest::Unsatisfiable => {
+			return Ok((
+				StatusCode::RANGE_NOT_SATISFIABLE,
+				[(header::CONTENT_RANGE, format!("bytes */{size}"))],
+			)
+				.into_response());
+		}
+	};
+
+	let body = app_state
+		.storage
+		.get(&attachment.storage_key, range.clone())
 		.await
 		.map_err(|e| {
 			eprintln!("{e}");
 			StatusCode::INTERNAL_SERVER_ERROR
 		})?;
 
-	app_state
-		.ws_state
-		.broadcast("messages", "message_created", &created_message)
+	// Only images are shown in place; anything else is downloaded rather than rendered by the
+	// browser under this origin
+	let disposition = if attachment.content_type.starts_with("image/") {
+		"inline"
+	} else {
+		"attachment"
+	};
+
+	let mut response = Response::builder()
+		.status(status)
+		.header(header::CONTENT_TYPE, &attachment.content_type)
+		.header(header::ACCEPT_RANGES, "bytes")
+		.header(
+			header::CONTENT_DISPOSITION,
+			format!(
+				"{disposition}; filename*=UTF-8''{}",
+				utf8_percent_encode(&attachment.filename, NON_ALPHANUMERIC)
+			),
+		)
+		.header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+		.header(header::CONTENT_SECURITY_POLICY, "sandbox");
+	response = match range {
+		Some(range) => response
+			.header(header::CONTENT_LENGTH, range.end() - range.start() + 1)
+			.header(
+				header::CONTENT_RANGE,
+				format!("bytes {}-{}/{size}", range.start(), range.end()),
+			),
+		None => response.header(header::CONTENT_LENGTH, size),
+	};
+
+	response.body(Body::from_stream(body)).map_err(|e| {
+		eprintln!("{e}");
+		StatusCode::INTERNAL_SERVER_ERROR.into()
+	})
+}
+
+pub async fn get_attachment_thumbnail(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+) -> Result<Response> {
+	let attachment = get_visible_attachment(&app_state, &claims.sub, id).await?;
+	let Some(thumbnail_key) = attachment.thumbnail_key else {
+		return Err(StatusCode::NOT_FOUND.into());
+	};
+
+	let body = app_state
+		.storage
+		.get(&thumbnail_key, None)
 		.await
 		.map_err(|e| {
 			eprintln!("{e}");
 			StatusCode::INTERNAL_SERVER_ERROR
 		})?;
 
-	Ok(Json(created_message))
+	Ok((
+		[
+			(header::CONTENT_TYPE, "image/png"),
+			(header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+		],
+		Body::from_stream(body),
+	)
+		.into_response())
 }
 
 pub async fn signup(
 	State(app_state): State<AppState>,
+	ClientIp(ip): ClientIp,
+	headers: HeaderMap,
 	Json(mut user): Json<User>,
 ) -> Result<Json<AuthResponse>> {
 	match hash_password(&user.password) {
@@ -137,42 +1611,316 @@ pub async fn signup(
 			StatusCode::INTERNAL_SERVER_ERROR
 		})?;
 
-	let token = generate_token(&created_user.username).map_err(|e| {
+	let tokens = create_session(
+		&app_state.conn,
+		&created_user.username,
+		device_name_from_headers(&headers),
+		Some(ip.to_string()),
+	)
+	.await
+	.map_err(|e| {
 		eprintln!("{e}");
 		StatusCode::INTERNAL_SERVER_ERROR
 	})?;
 
 	Ok(Json(AuthResponse {
 		user: created_user,
-		token,
+		tokens,
 	}))
 }
 
+/// Responds to a login attempt, creating a session once nothing is left to check.
+async fn finish_login(
+	app_state: &AppState,
+	ip: IpAddr,
+	headers: &HeaderMap,
+	outcome: anyhow::Result<LoginOutcome>,
+) -> Result<Json<LoginResponse>> {
+	let user = match outcome {
+		Ok(LoginOutcome::Authenticated(user)) => user,
+		Ok(LoginOutcome::MfaRequired { mfa_token }) => {
+			return Ok(Json(LoginResponse::MfaRequired { mfa_token }));
+		}
+		Ok(LoginOutcome::Rejected) => return Err(StatusCode::UNAUTHORIZED.into()),
+		Ok(LoginOutcome::LockedOut(retry_after)) => {
+			return Err(too_many_requests(retry_after).into());
+		}
+		Err(err) => {
+			eprintln!("{err}");
+			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+		}
+	};
+
+	let tokens = create_session(
+		&app_state.conn,
+		&user.username,
+		device_name_from_headers(headers),
+		Some(ip.to_string()),
+	)
+	.await
+	.map_err(|e| {
+		eprintln!("{e}");
+		StatusCode::INTERNAL_SERVER_ERROR
+	})?;
+
+	Ok(Json(LoginResponse::Authenticated(AuthResponse {
+		user,
+		tokens,
+	})))
+}
+
 pub async fn login(
 	State(app_state): State<AppState>,
+	ClientIp(ip): ClientIp,
+	headers: HeaderMap,
 	Json(credentials): Json<Credentials>,
-) -> Result<Json<AuthResponse>> {
-	let user = match authenticate_user(&app_state.conn, &credentials).await {
-		Ok(Some(user)) => user,
-		Ok(None) => return Err(StatusCode::UNAUTHORIZED.into()),
+) -> Result<Json<LoginResponse>> {
+	let outcome = authenticate_user(&app_state.conn, &credentials, &ip.to_string()).await;
+	finish_login(&app_state, ip, &headers, outcome).await
+}
+
+/// Second step of a login for users with TOTP enabled.
+pub async fn login_mfa(
+	State(app_state): State<AppState>,
+	ClientIp(ip): ClientIp,
+	headers: HeaderMap,
+	Json(request): Json<MfaLoginRequest>,
+) -> Result<Json<LoginResponse>> {
+	let outcome = complete_mfa_login(&app_state.conn, &request, &ip.to_string()).await;
+	finish_login(&app_state, ip, &headers, outcome).await
+}
+
+pub async fn get_mfa(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+) -> Result<Json<MfaStatus>> {
+	match get_mfa_status(&app_state.conn, &claims.sub).await {
+		Ok(status) => Ok(Json(status)),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+/// Returns a new TOTP secret to add to an authenticator app, which has to be confirmed with a
+/// code through `verify_totp` before logins ask for one.
+pub async fn enroll_totp(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+) -> Result<Json<TotpEnrollment>> {
+	match start_totp_enrollment(&app_state.conn, &claims.sub).await {
+		Ok(Some(enrollment)) => Ok(Json(enrollment)),
+		Ok(None) => Err(StatusCode::CONFLICT.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+pub async fn verify_totp(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Json(request): Json<MfaCodeRequest>,
+) -> Result<Json<RecoveryCodes>> {
+	match confirm_totp_enrollment(&app_state.conn, &claims.sub, &request.code).await {
+		Ok(Some(recovery_codes)) => Ok(Json(recovery_codes)),
+		Ok(None) => Err(StatusCode::BAD_REQUEST.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+/// Turns TOTP off, given either a current code or a recovery code.
+pub async fn disable_mfa(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	ClientIp(ip): ClientIp,
+	Json(request): Json<MfaCodeRequest>,
+) -> Result<StatusCode> {
+	match disable_totp(&app_state.conn, &claims.sub, &request.code, &ip.to_string()).await {
+		Ok(Some(CodeCheck::Accepted)) => Ok(StatusCode::NO_CONTENT),
+		Ok(Some(CodeCheck::Rejected)) => Err(StatusCode::BAD_REQUEST.into()),
+		Ok(Some(CodeCheck::LockedOut(retry_after))) => Err(too_many_requests(retry_after).into()),
+		Ok(None) => Err(StatusCode::NOT_FOUND.into()),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+/// Lets an admin lift the lockout of an account after repeated failed logins.
+pub async fn unlock_user(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(username): Path<String>,
+) -> Result<StatusCode> {
+	if !is_admin(&claims.sub) {
+		return Err(StatusCode::FORBIDDEN.into());
+	}
+
+	match unlock_login(&app_state.conn, &username, &claims.sub).await {
+		Ok(_) => Ok(StatusCode::NO_CONTENT),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+pub async fn refresh_token(
+	State(app_state): State<AppState>,
+	ClientIp(ip): ClientIp,
+	Json(request): Json<RefreshRequest>,
+) -> Result<Json<Tokens>> {
+	let outcome = refresh_session(
+		&app_state.conn,
+		&request.refresh_token,
+		Some(ip.to_string()),
+	)
+	.await
+	.map_err(|e| {
+		eprintln!("{e}");
+		StatusCode::INTERNAL_SERVER_ERROR
+	})?;
+
+	match outcome {
+		RefreshOutcome::Refreshed(tokens) => Ok(Json(tokens)),
+		RefreshOutcome::Rejected => Err(StatusCode::UNAUTHORIZED.into()),
+		// Whoever holds the session's connections may be the one who copied the token
+		RefreshOutcome::Reused {
+			username,
+			session_id,
+		} => {
+			app_state
+				.ws_state
+				.broadcast(
+					"sessions",
+					"sessions_revoked",
+					&SessionsRevokedPayload {
+						username,
+						session_ids: vec![session_id],
+					},
+				)
+				.await
+				.map_err(|e| {
+					eprintln!("{e}");
+					StatusCode::INTERNAL_SERVER_ERROR
+				})?;
+			Err(StatusCode::UNAUTHORIZED.into())
+		}
+	}
+}
+
+pub async fn get_sessions(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<SessionInfo>>> {
+	match db::get_active_sessions(&app_state.conn, &claims.sub).await {
+		Ok(sessions) => Ok(Json(
+			sessions
+				.into_iter()
+				.map(|session| SessionInfo {
+					current: session.id == claims.sid,
+					session,
+				})
+				.collect(),
+		)),
+		Err(err) => {
+			eprintln!("{err}");
+			Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+		}
+	}
+}
+
+pub async fn revoke_session(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+	Path(id): Path<i32>,
+) -> Result<StatusCode> {
+	match db::get_session(&app_state.conn, id).await {
+		Ok(session) if session.username == claims.sub => {}
+		Ok(_) | Err(DbErr::RecordNotFound(_)) => return Err(StatusCode::NOT_FOUND.into()),
 		Err(err) => {
 			eprintln!("{err}");
 			return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
 		}
-	};
+	}
 
-	let token = generate_token(&user.username).map_err(|e| {
+	db::revoke_session(&app_state.conn, id).await.map_err(|e| {
 		eprintln!("{e}");
 		StatusCode::INTERNAL_SERVER_ERROR
 	})?;
 
-	Ok(Json(AuthResponse { user, token }))
+	app_state
+		.ws_state
+		.broadcast(
+			"sessions",
+			"sessions_revoked",
+			&SessionsRevokedPayload {
+				username: claims.sub,
+				session_ids: vec![id],
+			},
+		)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn revoke_other_sessions(
+	State(app_state): State<AppState>,
+	Extension(claims): Extension<Claims>,
+) -> Result<StatusCode> {
+	let session_ids = db::revoke_other_sessions(&app_state.conn, &claims.sub, claims.sid)
+		.await
+		.map_err(|e| {
+			eprintln!("{e}");
+			StatusCode::INTERNAL_SERVER_ERROR
+		})?;
+
+	if !session_ids.is_empty() {
+		app_state
+			.ws_state
+			.broadcast(
+				"sessions",
+				"sessions_revoked",
+				&SessionsRevokedPayload {
+					username: claims.sub,
+					session_ids,
+				},
+			)
+			.await
+			.map_err(|e| {
+				eprintln!("{e}");
+				StatusCode::INTERNAL_SERVER_ERROR
+			})?;
+	}
+
+	Ok(StatusCode::NO_CONTENT)
 }
 
 pub async fn ws_handler(
 	ws: WebSocketUpgrade,
 	State(app_state): State<AppState>,
-	Extension(username): Extension<String>,
+	Extension(claims): Extension<Claims>,
+	Query(query): Query<WsQuery>,
 ) -> Response {
-	ws.on_upgrade(move |socket| handle_socket(socket, app_state.conn, app_state.ws_state, username))
+	ws.on_upgrade(move |socket| {
+		handle_socket(
+			socket,
+			app_state.conn,
+			app_state.ws_state,
+			app_state.storage,
+			claims,
+			query.resume_from,
+		)
+	})
 }
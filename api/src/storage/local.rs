@@ -0,0 +1,68 @@
+use crate::storage::{ByteStream, Storage};
+use anyhow::{Result, anyhow};
+use axum::body::Bytes;
+use std::{io::SeekFrom, ops::RangeInclusive, path::PathBuf};
+use tokio::{
+	fs::{self, File},
+	io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+/// Keeps each object in a file named after its key, below `root`.
+pub struct LocalStorage {
+	root: PathBuf,
+}
+
+impl LocalStorage {
+	pub fn new(root: impl Into<PathBuf>) -> Self {
+		Self { root: root.into() }
+	}
+
+	fn path(&self, key: &str) -> Result<PathBuf> {
+		// Keys are generated, but one leaving the root must never be followed
+		if key
+			.split('/')
+			.any(|part| part.is_empty() || part == "." || part == "..")
+		{
+			return Err(anyhow!("Invalid storage key '{key}'"));
+		}
+		Ok(self.root.join(key))
+	}
+}
+
+#[async_trait::async_trait]
+impl Storage for LocalStorage {
+	async fn put(&self, key: &str, data: Bytes, _content_type: &str) -> Result<()> {
+		let path = self.path(key)?;
+		if let Some(parent) = path.parent() {
+			fs::create_dir_all(parent).await?;
+		}
+
+		// Written aside first so that a failed write never leaves half an object under the key
+		let partial = path.with_extension("partial");
+		fs::write(&partial, &data).await?;
+		fs::rename(&partial, &path).await?;
+		Ok(())
+	}
+
+	async fn get(&self, key: &str, range: Option<RangeInclusive<u64>>) -> Result<ByteStream> {
+		let mut file = File::open(self.path(key)?).await?;
+
+		match range {
+			Some(range) => {
+				file.seek(SeekFrom::Start(*range.start())).await?;
+				let len = range.end() - range.start() + 1;
+				Ok(Box::pin(ReaderStream::new(file.take(len))))
+			}
+			None => Ok(Box::pin(ReaderStream::new(file))),
+		}
+	}
+
+	async fn delete(&self, key: &str) -> Result<()> {
+		match fs::remove_file(self.path(key)?).await {
+			Ok(()) => Ok(()),
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+			Err(err) => Err(err.into()),
+		}
+	}
+}
@@ -0,0 +1,79 @@
+mod local;
+mod s3;
+
+use anyhow::{Context, Result, anyhow};
+use axum::body::Bytes;
+use data_encoding::HEXLOWER;
+use futures_util::Stream;
+use local::LocalStorage;
+use rand::RngCore;
+use s3::S3Storage;
+use std::{env, io, ops::RangeInclusive, pin::Pin, str::FromStr, sync::Arc};
+
+/// Where uploads are kept when `STORAGE_PATH` is unset, relative to the working directory
+const DEFAULT_STORAGE_PATH: &str = "uploads";
+const DEFAULT_S3_REGION: &str = "us-east-1";
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// Where attachments are stored, chosen through `STORAGE_BACKEND`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StorageBackend {
+	/// Files in a directory on this node, for running a single node
+	#[default]
+	Local,
+	/// A bucket of an S3-compatible service, shared by every node
+	S3,
+}
+
+impl FromStr for StorageBackend {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s {
+			"local" => Ok(Self::Local),
+			"s3" => Ok(Self::S3),
+			other => Err(anyhow!("Unknown storage backend '{other}'")),
+		}
+	}
+}
+
+#[async_trait::async_trait]
+pub trait Storage: Send + Sync {
+	/// Stores an object under `key`, replacing any already there.
+	async fn put(&self, key: &str, data: Bytes, content_type: &str) -> Result<()>;
+
+	/// Streams the object under `key`, or only the bytes in `range`, which the caller has checked
+	/// against its size.
+	async fn get(&self, key: &str, range: Option<RangeInclusive<u64>>) -> Result<ByteStream>;
+
+	/// Removes the object under `key`, doing nothing if there is none.
+	async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Sets up the backend chosen through `STORAGE_BACKEND`, along with its own settings.
+pub fn from_env() -> Result<Arc<dyn Storage>> {
+	let backend = env::var("STORAGE_BACKEND")
+		.map(|backend| backend.parse::<StorageBackend>())
+		.unwrap_or(Ok(StorageBackend::default()))?;
+
+	Ok(match backend {
+		StorageBackend::Local => Arc::new(LocalStorage::new(
+			env::var("STORAGE_PATH").unwrap_or_else(|_| DEFAULT_STORAGE_PATH.to_string()),
+		)),
+		StorageBackend::S3 => Arc::new(S3Storage::new(
+			&env::var("S3_ENDPOINT").context("S3_ENDPOINT must be set")?,
+			env::var("S3_BUCKET").context("S3_BUCKET must be set")?,
+			env::var("S3_REGION").unwrap_or_else(|_| DEFAULT_S3_REGION.to_string()),
+			env::var("S3_ACCESS_KEY_ID").context("S3_ACCESS_KEY_ID must be set")?,
+			env::var("S3_SECRET_ACCESS_KEY").context("S3_SECRET_ACCESS_KEY must be set")?,
+		)?),
+	})
+}
+
+/// A random key under `prefix`, so that nothing about an object can be guessed from its key.
+pub fn new_key(prefix: &str) -> String {
+	let mut bytes = [0u8; 16];
+	rand::rng().fill_bytes(&mut bytes);
+	format!("{prefix}/{}", HEXLOWER.encode(&bytes))
+}
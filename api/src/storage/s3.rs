@@ -0,0 +1,153 @@
+use crate::storage::{ByteStream, Storage};
+use anyhow::{Result, anyhow};
+use axum::body::Bytes;
+use chrono::Utc;
+use data_encoding::HEXLOWER;
+use futures_util::TryStreamExt;
+use hmac::{Hmac, Mac};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::{Client, Method, RequestBuilder, Url, header};
+use sha2::{Digest, Sha256};
+use std::{io, ops::RangeInclusive};
+
+/// Characters SigV4 leaves unencoded in a path segment
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+	.remove(b'-')
+	.remove(b'_')
+	.remove(b'.')
+	.remove(b'~');
+
+/// Talks to an S3-compatible service, such as AWS S3 or MinIO, signing requests with AWS
+/// Signature Version 4. Buckets are addressed by path, which every such service supports.
+pub struct S3Storage {
+	client: Client,
+	endpoint: Url,
+	bucket: String,
+	region: String,
+	access_key_id: String,
+	secret_access_key: String,
+}
+
+impl S3Storage {
+	pub fn new(
+		endpoint: &str,
+		bucket: String,
+		region: String,
+		access_key_id: String,
+		secret_access_key: String,
+	) -> Result<Self> {
+		Ok(Self {
+			client: Client::new(),
+			endpoint: Url::parse(endpoint)?,
+			bucket,
+			region,
+			access_key_id,
+			secret_access_key,
+		})
+	}
+
+	/// Builds a signed request for the object under `key`.
+	fn request(&self, method: Method, key: &str, payload: &[u8]) -> RequestBuilder {
+		let path = format!(
+			"{}/{}/{}",
+			self.endpoint.path().trim_end_matches('/'),
+			utf8_percent_encode(&self.bucket, PATH_SEGMENT),
+			key.split('/')
+				.map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+				.collect::<Vec<_>>()
+				.join("/"),
+		);
+		let mut url = self.endpoint.clone();
+		url.set_path(&path);
+
+		let host = match url.port() {
+			Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+			None => url.host_str().unwrap_or_default().to_string(),
+		};
+		let now = Utc::now();
+		let date = now.format("%Y%m%d").to_string();
+		let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+		let payload_hash = HEXLOWER.encode(&Sha256::digest(payload));
+
+		let canonical_request = format!(
+			"{method}\n{path}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\n\
+			 x-amz-date:{timestamp}\n\nhost;x-amz-content-sha256;x-amz-date\n{payload_hash}"
+		);
+		let scope = format!("{date}/{}/s3/aws4_request", self.region);
+		let string_to_sign = format!(
+			"AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+			HEXLOWER.encode(&Sha256::digest(canonical_request.as_bytes()))
+		);
+
+		let mut signing_key = hmac_sha256(
+			format!("AWS4{}", self.secret_access_key).as_bytes(),
+			date.as_bytes(),
+		);
+		for part in [self.region.as_str(), "s3", "aws4_request"] {
+			signing_key = hmac_sha256(&signing_key, part.as_bytes());
+		}
+		let signature = HEXLOWER.encode(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+		self.client
+			.request(method, url)
+			.header("x-amz-content-sha256", payload_hash)
+			.header("x-amz-date", timestamp)
+			.header(
+				header::AUTHORIZATION,
+				format!(
+					"AWS4-HMAC-SHA256 Credential={}/{scope}, \
+					 SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={signature}",
+					self.access_key_id
+				),
+			)
+	}
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+	let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+	mac.update(data);
+	mac.finalize().into_bytes().to_vec()
+}
+
+#[async_trait::async_trait]
+impl Storage for S3Storage {
+	async fn put(&self, key: &str, data: Bytes, content_type: &str) -> Result<()> {
+		let response = self
+			.request(Method::PUT, key, &data)
+			.header(header::CONTENT_TYPE, content_type)
+			.body(data)
+			.send()
+			.await?;
+
+		if !response.status().is_success() {
+			return Err(anyhow!("Failed to store '{key}': {}", response.status()));
+		}
+		Ok(())
+	}
+
+	async fn get(&self, key: &str, range: Option<RangeInclusive<u64>>) -> Result<ByteStream> {
+		let mut request = self.request(Method::GET, key, &[]);
+		if let Some(range) = range {
+			request = request.header(
+				header::RANGE,
+				format!("bytes={}-{}", range.start(), range.end()),
+			);
+		}
+
+		let response = request.send().await?;
+		if !response.status().is_success() {
+			return Err(anyhow!("Failed to fetch '{key}': {}", response.status()));
+		}
+		Ok(Box::pin(response.bytes_stream().map_err(io::Error::other)))
+	}
+
+	async fn delete(&self, key: &str) -> Result<()> {
+		let response = self.request(Method::DELETE, key, &[]).send().await?;
+
+		// S3 answers 204 whether or not the object existed
+		if !response.status().is_success() {
+			return Err(anyhow!("Failed to delete '{key}': {}", response.status()));
+		}
+		Ok(())
+	}
+}
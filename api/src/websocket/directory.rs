@@ -0,0 +1,169 @@
+use crate::db::{DeletedDirectory, create_directory, get_directory_node};
+use crate::entity::directory::Model as Directory;
+use crate::permissions::{Permission, has_permission, is_admin};
+use crate::websocket::{WsContext, WsError, WsModule, WsPayload};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize)]
+pub struct NodeMovedPayload {
+	#[serde(flatten)]
+	pub node: Directory,
+	pub old_parent_id: Option<i32>,
+}
+
+/// Tells those who could see where a node was, but cannot see it where it is now, only that it
+/// left.
+#[derive(Deserialize, Serialize)]
+pub struct NodeMovedAwayPayload {
+	pub id: i32,
+	pub old_parent_id: Option<i32>,
+}
+
+/// Tells a user they were added to or removed from a private node, which changes what they can
+/// see.
+#[derive(Deserialize, Serialize)]
+pub struct MembershipChangedPayload {
+	pub directory_id: i32,
+	pub username: String,
+}
+
+#[derive(Deserialize)]
+struct CreateNodePayload {
+	name: String,
+	r#type: String,
+	parent_id: Option<i32>,
+	#[serde(default)]
+	is_private: bool,
+}
+
+pub struct DirectoryModule;
+
+impl DirectoryModule {
+	/// Changes to the roots are announced to everyone, like the roots themselves are listed.
+	async fn can_view_parent(ctx: &WsContext, parent_id: Option<i32>) -> bool {
+		match parent_id {
+			Some(parent_id) => ctx.can_view(parent_id).await,
+			None => true,
+		}
+	}
+}
+
+#[async_trait::async_trait]
+impl WsModule for DirectoryModule {
+	fn name(&self) -> &'static str {
+		"directory"
+	}
+
+	async fn handle(
+		&self,
+		ctx: &WsContext,
+		r#type: &str,
+		payload: &WsPayload,
+	) -> Result<Option<WsPayload>> {
+		match r#type {
+			"create_node" => {
+				let CreateNodePayload {
+					name,
+					r#type,
+					parent_id,
+					is_private,
+				} = payload.get()?;
+				let node = Directory {
+					id: 0,
+					name,
+					r#type,
+					parent_id,
+					is_private,
+					position: 0,
+				};
+
+				if node.r#type == "dm" {
+					return Err(
+						WsError::bad_request("DM conversations cannot be created here").into(),
+					);
+				}
+
+				match node.parent_id {
+					Some(parent_id) => {
+						if !has_permission(
+							&ctx.conn,
+							&ctx.username,
+							parent_id,
+							Permission::CreateNodes,
+						)
+						.await?
+						{
+							return Err(WsError::forbidden(format!(
+								"User '{}' may not create nodes in directory {parent_id}",
+								ctx.username
+							))
+							.into());
+						}
+
+						if get_directory_node(&ctx.conn, parent_id).await?.r#type == "dm" {
+							return Err(WsError::bad_request(
+								"Nodes cannot be created in DM conversations",
+							)
+							.into());
+						}
+					}
+					None if !is_admin(&ctx.username) => {
+						return Err(WsError::forbidden(format!(
+							"User '{}' may not create root nodes",
+							ctx.username
+						))
+						.into());
+					}
+					None => {}
+				}
+
+				let created = create_directory(&ctx.conn, node, &ctx.username).await?;
+
+				ctx.state
+					.broadcast_node_created(&ctx.conn, &created)
+					.await?;
+				Ok(Some(WsPayload::new(created)?))
+			}
+
+			_ => Err(WsError::unknown_type(self.name(), r#type).into()),
+		}
+	}
+
+	async fn should_deliver(&self, ctx: &WsContext, r#type: &str, payload: &WsPayload) -> bool {
+		match r#type {
+			"node_created" | "node_updated" => match payload.get::<Directory>() {
+				Ok(node) => ctx.can_view(node.id).await,
+				Err(_) => false,
+			},
+			"node_moved" => match payload.get::<NodeMovedPayload>() {
+				Ok(moved) => ctx.can_view(moved.node.id).await,
+				Err(_) => false,
+			},
+			// Those who could see the node where it was need to hear that it left, but nothing
+			// about where it went
+			"node_moved_away" => match payload.get::<NodeMovedAwayPayload>() {
+				Ok(moved) => {
+					Self::can_view_parent(ctx, moved.old_parent_id).await
+						&& !ctx.can_view(moved.id).await
+				}
+				Err(_) => false,
+			},
+			"membership_changed" => match payload.get::<MembershipChangedPayload>() {
+				Ok(changed) => changed.username == ctx.username,
+				Err(_) => false,
+			},
+			// The node is gone, so go by the parent it was under and who it was private to
+			"node_deleted" => match payload.get::<DeletedDirectory>() {
+				Ok(deleted) => {
+					Self::can_view_parent(ctx, deleted.parent_id).await
+						&& deleted.members.is_none_or(|members| {
+							is_admin(&ctx.username) || members.contains(&ctx.username)
+						})
+				}
+				Err(_) => false,
+			},
+			_ => true,
+		}
+	}
+}
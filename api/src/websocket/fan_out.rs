@@ -0,0 +1,258 @@
+use crate::entity::ws_events;
+use crate::websocket::{LocalHub, WsEnvelope};
+use anyhow::{Result, anyhow};
+use chrono::{TimeDelta, Utc};
+use sea_orm::{
+	ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection, DbBackend, EntityTrait,
+	QueryFilter, Set, Statement, TransactionTrait, sqlx::postgres::PgListener,
+};
+use serde::{Deserialize, Serialize};
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+/// Channel every node listens on for the events published by any node
+const NOTIFY_CHANNEL: &str = "ws_events";
+/// Postgres only accepts notification payloads shorter than this many bytes
+const MAX_NOTIFY_PAYLOAD: usize = 8000;
+/// Arbitrary key of the advisory lock held while sequencing and publishing an event
+const PUBLISH_LOCK_KEY: i64 = 0x7773_6576_656e_7473;
+/// How long events too large for a notification are kept for nodes to fetch
+const STORED_EVENT_TTL_MINUTES: i64 = 5;
+const LISTEN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How events reach the connections on other nodes, chosen through `WS_FANOUT`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FanOutBackend {
+	/// Events stay within this process, for running a single node
+	#[default]
+	Memory,
+	/// Events go through Postgres LISTEN/NOTIFY to every node using the same database
+	Postgres,
+}
+
+impl FromStr for FanOutBackend {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s {
+			"memory" => Ok(Self::Memory),
+			"postgres" => Ok(Self::Postgres),
+			other => Err(anyhow!("Unknown WebSocket fan-out backend '{other}'")),
+		}
+	}
+}
+
+#[async_trait::async_trait]
+pub(super) trait FanOut: Send + Sync {
+	/// Sends an event to the connections on every node, this one included.
+	async fn publish(&self, env: WsEnvelope) -> Result<()>;
+
+	/// Sends an ephemeral event to the connections on every node without sequencing it.
+	async fn publish_ephemeral(&self, env: WsEnvelope) -> Result<()>;
+}
+
+pub(super) struct MemoryFanOut {
+	hub: Arc<LocalHub>,
+}
+
+impl MemoryFanOut {
+	pub(super) fn new(hub: Arc<LocalHub>) -> Self {
+		Self { hub }
+	}
+}
+
+#[async_trait::async_trait]
+impl FanOut for MemoryFanOut {
+	async fn publish(&self, env: WsEnvelope) -> Result<()> {
+		self.hub.deliver(env);
+		Ok(())
+	}
+
+	async fn publish_ephemeral(&self, env: WsEnvelope) -> Result<()> {
+		self.hub.deliver_ephemeral(env);
+		Ok(())
+	}
+}
+
+/// What is sent through a notification. The envelope is left out when it would make the payload
+/// too large, in which case it is stored in `ws_events` under its sequence instead. Ephemeral
+/// events have no sequence, and are never stored.
+#[derive(Serialize, Deserialize)]
+struct Notification {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	seq: Option<u64>,
+	topic: Option<Vec<i32>>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	envelope: Option<WsEnvelope>,
+}
+
+/// Sequences events in the database and delivers them to every node through LISTEN/NOTIFY, so
+/// all nodes see the same events in the same order. This node's own events also come back
+/// through its listener rather than being delivered directly.
+pub(super) struct PostgresFanOut {
+	conn: DatabaseConnection,
+}
+
+impl PostgresFanOut {
+	pub(super) async fn start(conn: &DatabaseConnection, hub: Arc<LocalHub>) -> Result<Self> {
+		let mut listener = PgListener::connect_with(conn.get_postgres_connection_pool()).await?;
+		listener.listen(NOTIFY_CHANNEL).await?;
+
+		// Events published before listening are never delivered here, so carry on after them
+		hub.start_from(current_seq(conn).await?);
+
+		tokio::spawn(listen(listener, conn.clone(), hub));
+
+		Ok(Self { conn: conn.clone() })
+	}
+}
+
+#[async_trait::async_trait]
+impl FanOut for PostgresFanOut {
+	async fn publish(&self, env: WsEnvelope) -> Result<()> {
+		let txn = self.conn.begin().await?;
+
+		// Holding the lock until commit makes notifications arrive in sequence order
+		txn.execute(Statement::from_sql_and_values(
+			DbBackend::Postgres,
+			"SELECT pg_advisory_xact_lock($1)",
+			[PUBLISH_LOCK_KEY.into()],
+		))
+		.await?;
+
+		let seq: i64 = txn
+			.query_one(Statement::from_string(
+				DbBackend::Postgres,
+				"SELECT nextval('ws_event_seq') AS seq",
+			))
+			.await?
+			.ok_or(anyhow!("Failed to sequence WebSocket event"))?
+			.try_get("", "seq")?;
+
+		let mut notification = Notification {
+			seq: Some(seq as u64),
+			topic: env.topic.clone(),
+			envelope: Some(env),
+		};
+		let mut payload = serde_json::to_string(&notification)?;
+
+		if payload.len() >= MAX_NOTIFY_PAYLOAD
+			&& let Some(env) = notification.envelope.take()
+		{
+			let now = Utc::now();
+
+			ws_events::ActiveModel {
+				seq: Set(seq),
+				envelope: Set(serde_json::to_string(&env)?),
+				created_at: Set(now.into()),
+			}
+			.insert(&txn)
+			.await?;
+
+			ws_events::Entity::delete_many()
+				.filter(
+					ws_events::Column::CreatedAt
+						.lt(now - TimeDelta::minutes(STORED_EVENT_TTL_MINUTES)),
+				)
+				.exec(&txn)
+				.await?;
+
+			payload = serde_json::to_string(&notification)?;
+		}
+
+		txn.execute(Statement::from_sql_and_values(
+			DbBackend::Postgres,
+			"SELECT pg_notify($1, $2)",
+			[NOTIFY_CHANNEL.into(), payload.into()],
+		))
+		.await?;
+
+		txn.commit().await?;
+		Ok(())
+	}
+
+	/// Skips the lock and the sequence, so that frequent events such as typing neither wait on
+	/// other events nor use up sequences.
+	async fn publish_ephemeral(&self, env: WsEnvelope) -> Result<()> {
+		let notification = Notification {
+			seq: None,
+			topic: env.topic.clone(),
+			envelope: Some(env),
+		};
+		let payload = serde_json::to_string(&notification)?;
+		if payload.len() >= MAX_NOTIFY_PAYLOAD {
+			return Err(anyhow!("Ephemeral WebSocket event too large to publish"));
+		}
+
+		self.conn
+			.execute(Statement::from_sql_and_values(
+				DbBackend::Postgres,
+				"SELECT pg_notify($1, $2)",
+				[NOTIFY_CHANNEL.into(), payload.into()],
+			))
+			.await?;
+		Ok(())
+	}
+}
+
+async fn current_seq(conn: &DatabaseConnection) -> Result<u64> {
+	let seq: i64 = conn
+		.query_one(Statement::from_string(
+			DbBackend::Postgres,
+			"SELECT CASE WHEN is_called THEN last_value ELSE 0 END AS seq FROM ws_event_seq",
+		))
+		.await?
+		.ok_or(anyhow!("Failed to read the WebSocket event sequence"))?
+		.try_get("", "seq")?;
+
+	Ok(seq as u64)
+}
+
+async fn listen(mut listener: PgListener, conn: DatabaseConnection, hub: Arc<LocalHub>) {
+	loop {
+		match listener.try_recv().await {
+			Ok(Some(notification)) => {
+				if let Err(err) = receive(&conn, &hub, notification.payload()).await {
+					eprintln!("Failed to receive WebSocket event: {err}");
+				}
+			}
+			// Events published until the listener reconnects are lost, which the hub notices from
+			// the gap in sequences once the next one arrives
+			Ok(None) => eprintln!("Lost the connection listening for WebSocket events"),
+			Err(err) => {
+				eprintln!("Failed to listen for WebSocket events: {err}");
+				tokio::time::sleep(LISTEN_RETRY_DELAY).await;
+			}
+		}
+	}
+}
+
+async fn receive(conn: &DatabaseConnection, hub: &LocalHub, payload: &str) -> Result<()> {
+	let Notification {
+		seq,
+		topic,
+		envelope,
+	} = serde_json::from_str(payload)?;
+
+	let Some(seq) = seq else {
+		let mut env = envelope.ok_or(anyhow!("Ephemeral WebSocket event without an envelope"))?;
+		env.topic = topic;
+		hub.deliver_ephemeral(env);
+		return Ok(());
+	};
+
+	let mut env = match envelope {
+		Some(env) => env,
+		None => {
+			let stored = ws_events::Entity::find_by_id(seq as i64)
+				.one(conn)
+				.await?
+				.ok_or(anyhow!("WebSocket event {seq} not found"))?;
+			serde_json::from_str(&stored.envelope)?
+		}
+	};
+
+	env.seq = Some(seq);
+	env.topic = topic;
+	hub.deliver(env);
+	Ok(())
+}
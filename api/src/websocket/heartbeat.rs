@@ -0,0 +1,33 @@
+use std::{env, sync::LazyLock, time::Duration};
+
+/// Close code sent when the token a connection authenticated with has expired, telling the
+/// client to refresh it before reconnecting.
+pub(super) const TOKEN_EXPIRED_CLOSE_CODE: u16 = 4001;
+
+/// How connections are kept alive and when they are given up on.
+pub(super) struct Heartbeat {
+	/// How long a connection may stay silent before the server pings it
+	pub ping_interval: Duration,
+	/// How long after a ping the connection is closed if nothing has come back
+	pub pong_timeout: Duration,
+	/// How long a connection may go without sending any envelope, if limited at all
+	pub idle_timeout: Option<Duration>,
+}
+
+pub(super) static HEARTBEAT: LazyLock<Heartbeat> = LazyLock::new(|| Heartbeat {
+	// An interval needs a period, so pinging cannot be turned off
+	ping_interval: Some(secs_from_env("WS_PING_INTERVAL_SECS", 30))
+		.filter(|interval| !interval.is_zero())
+		.unwrap_or(Duration::from_secs(30)),
+	pong_timeout: secs_from_env("WS_PONG_TIMEOUT_SECS", 10),
+	idle_timeout: Some(secs_from_env("WS_IDLE_TIMEOUT_SECS", 300))
+		.filter(|timeout| !timeout.is_zero()),
+});
+
+fn secs_from_env(name: &str, default: u64) -> Duration {
+	let secs = env::var(name)
+		.ok()
+		.and_then(|secs| secs.parse().ok())
+		.unwrap_or(default);
+	Duration::from_secs(secs)
+}
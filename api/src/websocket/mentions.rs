@@ -0,0 +1,65 @@
+use crate::db::{MentionWithMessage, mark_mentions_read};
+use crate::websocket::{WsContext, WsError, WsModule, WsPayload};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+struct MarkReadPayload {
+	/// The mentions to mark read, or all of the user's mentions when missing
+	ids: Option<Vec<i32>>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MentionsReadPayload {
+	pub username: String,
+	pub ids: Vec<i32>,
+}
+
+pub struct MentionsModule;
+
+#[async_trait::async_trait]
+impl WsModule for MentionsModule {
+	fn name(&self) -> &'static str {
+		"mentions"
+	}
+
+	async fn handle(
+		&self,
+		ctx: &WsContext,
+		r#type: &str,
+		payload: &WsPayload,
+	) -> Result<Option<WsPayload>> {
+		match r#type {
+			"mark_read" => {
+				let MarkReadPayload { ids } = payload.get()?;
+
+				let payload = MentionsReadPayload {
+					ids: mark_mentions_read(&ctx.conn, &ctx.username, ids.as_deref()).await?,
+					username: ctx.username.clone(),
+				};
+
+				ctx.state
+					.broadcast(self.name(), "mentions_read", &payload)
+					.await?;
+				Ok(Some(WsPayload::new(payload)?))
+			}
+
+			other => Err(WsError::unknown_type(self.name(), other).into()),
+		}
+	}
+
+	// Mentions are private to the user they notify
+	async fn should_deliver(&self, ctx: &WsContext, r#type: &str, payload: &WsPayload) -> bool {
+		match r#type {
+			"mention" => match payload.get::<MentionWithMessage>() {
+				Ok(p) => p.mention.username == ctx.username,
+				Err(_) => false,
+			},
+			"mentions_read" => match payload.get::<MentionsReadPayload>() {
+				Ok(p) => p.username == ctx.username,
+				Err(_) => false,
+			},
+			_ => true,
+		}
+	}
+}
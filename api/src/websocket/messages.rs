@@ -1,7 +1,17 @@
-use crate::db::create_message;
-use crate::entity::messages::Model as Message;
-use crate::websocket::{WsContext, WsModule, WsPayload};
-use anyhow::{Result, anyhow};
+use crate::attachments;
+use crate::db::{
+	NewMessage, ReactionSummary, add_reaction, canonical_emoji, create_message, delete_message,
+	edit_message, get_message, mark_read, remove_reaction, with_reactions,
+};
+use crate::entity::{messages::Model as Message, read_markers::Model as ReadMarker};
+use crate::mentions;
+use crate::permissions::{
+	Permission, can_delete_message, can_edit_message, can_react_to_message, can_view,
+	has_permission,
+};
+use crate::websocket::{WsContext, WsError, WsModule, WsPayload};
+use anyhow::Result;
+use sea_orm::DbErr;
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
@@ -19,6 +29,38 @@ struct UserTypingPayload {
 
 type UserStoppedTypingPayload = UserTypingPayload;
 
+#[derive(Deserialize)]
+struct EditMessagePayload {
+	id: i32,
+	content: String,
+}
+
+#[derive(Deserialize)]
+struct DeleteMessagePayload {
+	id: i32,
+	#[serde(default)]
+	purge: bool,
+}
+
+#[derive(Deserialize)]
+struct ReactionPayload {
+	message_id: i32,
+	emoji: String,
+}
+
+#[derive(Deserialize)]
+struct MarkReadPayload {
+	thread_id: i32,
+	message_id: i32,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ReactionsUpdatedPayload {
+	pub message_id: i32,
+	pub directory_id: i32,
+	pub reactions: Vec<ReactionSummary>,
+}
+
 pub struct MessagesModule;
 
 #[async_trait::async_trait]
@@ -27,59 +69,312 @@ impl WsModule for MessagesModule {
 		"messages"
 	}
 
-	async fn handle(&self, ctx: &WsContext, r#type: &str, payload: &WsPayload) -> Result<()> {
+	async fn handle(
+		&self,
+		ctx: &WsContext,
+		r#type: &str,
+		payload: &WsPayload,
+	) -> Result<Option<WsPayload>> {
 		match r#type {
 			"typing" => {
 				let TypingPayload { thread_id } = payload.get()?;
 
+				if !ctx.can_view(thread_id).await {
+					return Err(WsError::forbidden(format!(
+						"User '{}' may not view directory {thread_id}",
+						ctx.username
+					))
+					.into());
+				}
+
 				let payload = UserTypingPayload {
 					username: ctx.username.clone(),
 					thread_id,
 				};
 
 				ctx.state
-					.broadcast(self.name(), "user_typing", &payload)
-					.await
+					.broadcast_to_directory(
+						&ctx.conn,
+						thread_id,
+						self.name(),
+						"user_typing",
+						&payload,
+					)
+					.await?;
+				Ok(None)
 			}
 
 			"stop_typing" => {
 				let StopTypingPayload { thread_id } = payload.get()?;
 
+				if !ctx.can_view(thread_id).await {
+					return Err(WsError::forbidden(format!(
+						"User '{}' may not view directory {thread_id}",
+						ctx.username
+					))
+					.into());
+				}
+
 				let payload = UserStoppedTypingPayload {
 					username: ctx.username.clone(),
 					thread_id,
 				};
 
 				ctx.state
-					.broadcast(self.name(), "user_stopped_typing", &payload)
-					.await
+					.broadcast_to_directory(
+						&ctx.conn,
+						thread_id,
+						self.name(),
+						"user_stopped_typing",
+						&payload,
+					)
+					.await?;
+				Ok(None)
 			}
 
 			"create_message" => {
-				let msg = payload.get::<Message>()?;
+				let NewMessage {
+					message: msg,
+					attachment_ids,
+				} = payload.get()?;
+
+				if !has_permission(&ctx.conn, &ctx.username, msg.directory_id, Permission::Post)
+					.await?
+				{
+					return Err(WsError::forbidden(format!(
+						"User '{}' may not post in directory {}",
+						ctx.username, msg.directory_id
+					))
+					.into());
+				}
+
+				if attachment_ids.len() > attachments::MAX_PER_MESSAGE {
+					return Err(WsError::bad_request(format!(
+						"A message can have at most {} attachments",
+						attachments::MAX_PER_MESSAGE
+					))
+					.into());
+				}
+
+				let recipients =
+					mentions::resolve(&ctx.conn, &ctx.username, msg.directory_id, &msg.content)
+						.await?;
+				let (created, created_mentions) = match create_message(
+					&ctx.conn,
+					ctx.username.clone(),
+					msg,
+					&attachment_ids,
+					&recipients,
+				)
+				.await
+				{
+					Ok(created) => created,
+					Err(DbErr::Custom(err)) => return Err(WsError::bad_request(err).into()),
+					Err(err) => return Err(err.into()),
+				};
+				let created = with_reactions(&ctx.conn, vec![created]).await?.remove(0);
+
+				ctx.state
+					.broadcast_to_directory(
+						&ctx.conn,
+						created.message.directory_id,
+						self.name(),
+						"message_created",
+						&created,
+					)
+					.await?;
+				mentions::notify(&ctx.state, created_mentions, &created.message).await?;
+				Ok(Some(WsPayload::new(created)?))
+			}
+
+			"edit_message" => {
+				let EditMessagePayload { id, content } = payload.get()?;
+
+				if content.trim().is_empty() {
+					return Err(WsError::bad_request("Message content cannot be empty").into());
+				}
+
+				let message = get_message(&ctx.conn, id).await?;
+				if !can_edit_message(&ctx.conn, &ctx.username, &message).await? {
+					return Err(WsError::forbidden(format!(
+						"User '{}' may not edit message {id}",
+						ctx.username
+					))
+					.into());
+				}
+
+				let edited = edit_message(&ctx.conn, id, &ctx.username, content).await?;
+
+				ctx.state
+					.broadcast_to_directory(
+						&ctx.conn,
+						edited.directory_id,
+						self.name(),
+						"message_edited",
+						&edited,
+					)
+					.await?;
+				Ok(Some(WsPayload::new(edited)?))
+			}
+
+			"delete_message" => {
+				let DeleteMessagePayload { id, purge } = payload.get()?;
+
+				let message = get_message(&ctx.conn, id).await?;
+				if !can_delete_message(&ctx.conn, &ctx.username, &message, purge).await? {
+					return Err(WsError::forbidden(format!(
+						"User '{}' may not delete message {id}",
+						ctx.username
+					))
+					.into());
+				}
 
-				let created = create_message(&ctx.conn, ctx.username.clone(), msg).await?;
+				let (deleted, removed_attachments) =
+					delete_message(&ctx.conn, id, &ctx.username, purge).await?;
+				attachments::remove_objects(ctx.storage.as_ref(), &removed_attachments).await;
 
 				ctx.state
-					.broadcast(self.name(), "message_created", &created)
-					.await
+					.broadcast_to_directory(
+						&ctx.conn,
+						deleted.directory_id,
+						self.name(),
+						"message_deleted",
+						&deleted,
+					)
+					.await?;
+				Ok(Some(WsPayload::new(deleted)?))
 			}
 
-			other => Err(anyhow!(
-				"Invalid message type '{}' for module '{}'",
-				other,
-				self.name()
-			)),
+			"add_reaction" => {
+				let ReactionPayload { message_id, emoji } = payload.get()?;
+
+				let Some(emoji) = canonical_emoji(&emoji) else {
+					return Err(WsError::bad_request(format!("Invalid reaction '{emoji}'")).into());
+				};
+
+				let message = get_message(&ctx.conn, message_id).await?;
+				if !can_react_to_message(&ctx.conn, &ctx.username, &message).await? {
+					return Err(WsError::forbidden(format!(
+						"User '{}' may not react to message {message_id}",
+						ctx.username
+					))
+					.into());
+				}
+
+				let reactions = add_reaction(&ctx.conn, message_id, &ctx.username, emoji).await?;
+
+				let payload = ReactionsUpdatedPayload {
+					message_id,
+					directory_id: message.directory_id,
+					reactions,
+				};
+
+				ctx.state
+					.broadcast_to_directory(
+						&ctx.conn,
+						message.directory_id,
+						self.name(),
+						"reactions_updated",
+						&payload,
+					)
+					.await?;
+				Ok(Some(WsPayload::new(payload)?))
+			}
+
+			"remove_reaction" => {
+				let ReactionPayload { message_id, emoji } = payload.get()?;
+
+				let message = get_message(&ctx.conn, message_id).await?;
+				if !can_view(&ctx.conn, &ctx.username, message.directory_id).await? {
+					return Err(WsError::forbidden(format!(
+						"User '{}' may not view message {message_id}",
+						ctx.username
+					))
+					.into());
+				}
+
+				let emoji = canonical_emoji(&emoji).unwrap_or(&emoji);
+				let reactions =
+					remove_reaction(&ctx.conn, message_id, &ctx.username, emoji).await?;
+
+				let payload = ReactionsUpdatedPayload {
+					message_id,
+					directory_id: message.directory_id,
+					reactions,
+				};
+
+				ctx.state
+					.broadcast_to_directory(
+						&ctx.conn,
+						message.directory_id,
+						self.name(),
+						"reactions_updated",
+						&payload,
+					)
+					.await?;
+				Ok(Some(WsPayload::new(payload)?))
+			}
+
+			"mark_read" => {
+				let MarkReadPayload {
+					thread_id,
+					message_id,
+				} = payload.get()?;
+
+				if !can_view(&ctx.conn, &ctx.username, thread_id).await? {
+					return Err(WsError::forbidden(format!(
+						"User '{}' may not view directory {thread_id}",
+						ctx.username
+					))
+					.into());
+				}
+
+				let message = get_message(&ctx.conn, message_id).await?;
+				if message.directory_id != thread_id {
+					return Err(WsError::bad_request(format!(
+						"Message {message_id} does not belong to directory {thread_id}"
+					))
+					.into());
+				}
+
+				let marker = mark_read(&ctx.conn, &ctx.username, thread_id, message_id).await?;
+
+				ctx.state
+					.broadcast(self.name(), "read_marker_updated", &marker)
+					.await?;
+				Ok(Some(WsPayload::new(marker)?))
+			}
+
+			other => Err(WsError::unknown_type(self.name(), other).into()),
 		}
 	}
 
-	fn should_deliver(&self, ctx: &WsContext, r#type: &str, payload: &WsPayload) -> bool {
+	async fn should_deliver(&self, ctx: &WsContext, r#type: &str, payload: &WsPayload) -> bool {
 		match r#type {
 			"user_typing" | "user_stopped_typing" => match payload.get::<UserTypingPayload>() {
-				Ok(p) => p.username != ctx.username,
+				Ok(p) => p.username != ctx.username && ctx.can_view(p.thread_id).await,
+				Err(_) => false,
+			},
+			"message_created" | "message_edited" | "message_deleted" => {
+				match payload.get::<Message>() {
+					Ok(message) => ctx.can_view(message.directory_id).await,
+					Err(_) => false,
+				}
+			}
+			// Only the user's other connections care where they stopped reading
+			"read_marker_updated" => match payload.get::<ReadMarker>() {
+				Ok(marker) => marker.username == ctx.username,
+				Err(_) => false,
+			},
+			"reactions_updated" => match payload.get::<ReactionsUpdatedPayload>() {
+				Ok(p) => ctx.can_view(p.directory_id).await,
 				Err(_) => false,
 			},
 			_ => true,
 		}
 	}
+
+	fn is_ephemeral(&self, r#type: &str) -> bool {
+		matches!(r#type, "user_typing" | "user_stopped_typing")
+	}
 }
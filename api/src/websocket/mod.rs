@@ -1,26 +1,68 @@
+mod directory;
+mod fan_out;
+mod heartbeat;
+mod mentions;
 mod messages;
+mod presence;
+mod sessions;
+mod system;
 mod users;
 
+pub use directory::{MembershipChangedPayload, NodeMovedAwayPayload, NodeMovedPayload};
+pub use fan_out::FanOutBackend;
+pub use mentions::MentionsReadPayload;
+pub use messages::ReactionsUpdatedPayload;
+pub use presence::{PresencePayload, PresenceStatus, get_all_presence};
+pub use sessions::SessionsRevokedPayload;
+
+use crate::auth::Claims;
+use crate::db::{create_ws_node, get_ancestors};
+use crate::entity::directory::Model as Directory;
+use crate::permissions::can_view;
+use crate::rate_limit::check_ws;
+use crate::storage::Storage;
 use anyhow::{Result, anyhow};
-use axum::extract::ws::{Message as WsMessage, WebSocket};
+use axum::extract::ws::{CloseFrame, Message as WsMessage, WebSocket, close_code};
+use chrono::Utc;
 use futures_util::{
-	SinkExt, StreamExt,
+	SinkExt, StreamExt, future,
 	stream::{SplitSink, SplitStream},
 };
-use sea_orm::DatabaseConnection;
+use heartbeat::{HEARTBEAT, TOKEN_EXPIRED_CLOSE_CODE};
+use sea_orm::{DatabaseConnection, DbErr};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_json::Value;
 use std::{
-	collections::HashMap,
-	sync::{Arc, LazyLock},
+	collections::{HashMap, VecDeque},
+	fmt,
+	sync::{
+		Arc, LazyLock, Mutex as StdMutex,
+		atomic::{AtomicU64, AtomicUsize, Ordering},
+	},
+	time::Duration,
 };
 use tokio::sync::{
 	Mutex, broadcast,
-	broadcast::{Receiver, Sender},
+	broadcast::{Receiver, Sender, error::RecvError},
 };
+use tokio::time::{Instant, interval_at, sleep_until};
+
+/// Number of recent events kept for connections that fell behind or are resuming.
+const REPLAY_LOG_CAPACITY: usize = 10_000;
+
+static MODULE_LIST: LazyLock<Vec<&'static dyn WsModule>> = LazyLock::new(|| {
+	vec![
+		&directory::DirectoryModule,
+		&mentions::MentionsModule,
+		&messages::MessagesModule,
+		&presence::PresenceModule,
+		&sessions::SessionsModule,
+		&system::SystemModule,
+		&users::UsersModule,
+	]
+});
 
-static MODULE_LIST: LazyLock<Vec<&'static dyn WsModule>> =
-	LazyLock::new(|| vec![&messages::MessagesModule, &users::UsersModule]);
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WsPayload(Value);
@@ -37,70 +79,404 @@ impl WsPayload {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct WsEnvelope {
+	/// Set by the client on a request and echoed on the `ack` or `error` it gets back
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	id: Option<String>,
 	module: String,
 	#[serde(rename = "type")]
 	r#type: String,
 	payload: WsPayload,
+	/// Position of a broadcast event in the stream, which clients pass back as `resume_from`.
+	/// Ephemeral events have none.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	seq: Option<u64>,
+	/// Path from the directory node the event concerns up to its root, used to route the event
+	/// to subscribed connections only. Events without a topic go to every connection.
+	#[serde(skip)]
+	topic: Option<Vec<i32>>,
 }
 
 impl WsEnvelope {
+	fn is_ping(&self) -> bool {
+		self.module == "system" && self.r#type == "ping"
+	}
+
 	fn new<T: Serialize>(
 		module: impl Into<String>,
 		r#type: impl Into<String>,
 		payload: T,
 	) -> Result<Self, serde_json::Error> {
 		Ok(Self {
+			id: None,
 			module: module.into(),
 			r#type: r#type.into(),
 			payload: WsPayload::new(payload)?,
+			seq: None,
+			topic: None,
 		})
 	}
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WsErrorCode {
+	BadRequest,
+	Unauthorized,
+	Forbidden,
+	NotFound,
+	UnknownModule,
+	UnknownType,
+	RateLimited,
+	Internal,
+}
+
+/// An error returned to the client that sent the envelope which caused it.
+#[derive(Debug, Serialize)]
+pub struct WsError {
+	code: WsErrorCode,
+	message: String,
+	/// Seconds to wait before trying again, for `rate_limited` errors
+	#[serde(skip_serializing_if = "Option::is_none")]
+	retry_after: Option<f64>,
+}
+
+impl WsError {
+	pub fn new(code: WsErrorCode, message: impl Into<String>) -> Self {
+		Self {
+			code,
+			message: message.into(),
+			retry_after: None,
+		}
+	}
+
+	pub fn bad_request(message: impl Into<String>) -> Self {
+		Self::new(WsErrorCode::BadRequest, message)
+	}
+
+	pub fn forbidden(message: impl Into<String>) -> Self {
+		Self::new(WsErrorCode::Forbidden, message)
+	}
+
+	pub fn rate_limited(retry_after: Duration) -> Self {
+		Self {
+			retry_after: Some(retry_after.as_secs_f64()),
+			..Self::new(WsErrorCode::RateLimited, "Too many requests")
+		}
+	}
+
+	pub fn unknown_type(module: &str, r#type: &str) -> Self {
+		Self::new(
+			WsErrorCode::UnknownType,
+			format!("Invalid message type '{type}' for module '{module}'"),
+		)
+	}
+}
+
+impl fmt::Display for WsError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.message)
+	}
+}
+
+impl std::error::Error for WsError {}
+
+impl From<anyhow::Error> for WsError {
+	fn from(err: anyhow::Error) -> Self {
+		let err = match err.downcast::<WsError>() {
+			Ok(err) => return err,
+			Err(err) => err,
+		};
+
+		match err.downcast_ref::<DbErr>() {
+			Some(DbErr::RecordNotFound(msg)) => Self::new(WsErrorCode::NotFound, msg),
+			Some(DbErr::Custom(msg)) => Self::bad_request(msg),
+			_ if err.is::<serde_json::Error>() => {
+				Self::bad_request(format!("Invalid payload: {err}"))
+			}
+			// Anything else is unexpected and may contain details the client should not see
+			_ => Self::new(WsErrorCode::Internal, "Internal server error"),
+		}
+	}
+}
+
+#[derive(Serialize)]
+struct SeqPayload {
+	seq: u64,
+}
+
+struct ReplayLog {
+	last_seq: u64,
+	events: VecDeque<WsEnvelope>,
+}
+
 pub struct WsContext {
 	conn: DatabaseConnection,
 	state: WsState,
+	storage: Arc<dyn Storage>,
 	username: String,
+	session_id: i32,
+	/// Distinguishes the connections of a user with several tabs or devices open
+	connection_id: u64,
+	subscriptions: StdMutex<system::Subscriptions>,
+	/// Expiry of the token the connection last authenticated with, as a Unix timestamp
+	token_exp: AtomicUsize,
+	visibility: StdMutex<VisibilityCache>,
+}
+
+/// Whether the user of a connection can see each directory node asked about so far, as of
+/// `epoch` of the hub.
+#[derive(Default)]
+struct VisibilityCache {
+	epoch: u64,
+	nodes: HashMap<i32, bool>,
+}
+
+impl WsContext {
+	/// Whether the user can see a directory node, as `permissions::can_view` decides. Answers are
+	/// kept until the next directory event, since only what those announce can change them, so
+	/// that filtering an event for every connection does not walk the tree for each of them.
+	pub(super) async fn can_view(&self, directory_id: i32) -> bool {
+		let epoch = self.state.hub.visibility_epoch();
+		{
+			let mut cache = self.lock_visibility();
+			if cache.epoch != epoch {
+				*cache = VisibilityCache {
+					epoch,
+					nodes: HashMap::new(),
+				};
+			}
+			if let Some(visible) = cache.nodes.get(&directory_id) {
+				return *visible;
+			}
+		}
+
+		let visible = match can_view(&self.conn, &self.username, directory_id).await {
+			Ok(visible) => visible,
+			Err(err) => {
+				eprintln!("{err}");
+				return false;
+			}
+		};
+
+		// An answer worked out while a directory event arrived may already be stale
+		let mut cache = self.lock_visibility();
+		if cache.epoch == epoch {
+			cache.nodes.insert(directory_id, visible);
+		}
+		visible
+	}
+
+	fn lock_visibility(&self) -> std::sync::MutexGuard<'_, VisibilityCache> {
+		self.visibility
+			.lock()
+			.unwrap_or_else(|err| err.into_inner())
+	}
 }
 
 #[async_trait::async_trait]
 pub trait WsModule: Send + Sync + 'static {
 	fn name(&self) -> &'static str;
 
-	async fn handle(&self, _ctx: &WsContext, _type: &str, _payload: &WsPayload) -> Result<()> {
-		Ok(())
+	/// Handles an envelope sent by the client, returning what to acknowledge it with, such as the
+	/// resource it created. Errors are sent back as a `WsError`.
+	async fn handle(
+		&self,
+		_ctx: &WsContext,
+		r#type: &str,
+		_payload: &WsPayload,
+	) -> Result<Option<WsPayload>> {
+		Err(WsError::unknown_type(self.name(), r#type).into())
 	}
 
-	fn should_deliver(&self, _ctx: &WsContext, _type: &str, _payload: &WsPayload) -> bool {
+	async fn should_deliver(&self, _ctx: &WsContext, _type: &str, _payload: &WsPayload) -> bool {
 		true
 	}
+
+	/// Called once a connection is established, before any envelope is handled.
+	async fn on_connect(&self, _ctx: &WsContext) -> Result<()> {
+		Ok(())
+	}
+
+	/// Called once a connection has closed, whatever the reason.
+	async fn on_disconnect(&self, _ctx: &WsContext) -> Result<()> {
+		Ok(())
+	}
+
+	/// Whether the connection should be closed after this envelope has been delivered.
+	fn should_disconnect(&self, _ctx: &WsContext, _type: &str, _payload: &WsPayload) -> bool {
+		false
+	}
+
+	/// Whether events of this type are only of interest as they happen, such as typing. These
+	/// are neither sequenced nor kept for replay, so a client that missed them never hears of
+	/// them.
+	fn is_ephemeral(&self, _type: &str) -> bool {
+		false
+	}
 }
 
-#[derive(Clone)]
-pub struct WsState {
+/// Hands events to the connections on this node, keeping the most recent ones for replay.
+struct LocalHub {
 	tx: Sender<WsEnvelope>,
-	modules: Arc<HashMap<&'static str, &'static dyn WsModule>>,
+	log: StdMutex<ReplayLog>,
+	/// Counts the directory events delivered, each of which may change who can see what
+	visibility_epoch: AtomicU64,
 }
 
-impl WsState {
-	pub fn new(capacity: usize) -> Self {
+impl LocalHub {
+	fn new(capacity: usize) -> Self {
 		let (tx, _) = broadcast::channel::<WsEnvelope>(capacity);
 
-		let modules = Arc::new(MODULE_LIST.iter().map(|m| (m.name(), *m)).collect());
+		// Starting from the clock keeps sequences increasing across restarts, so a client resuming
+		// with a sequence from before a restart is told to resync instead of missing events
+		let log = StdMutex::new(ReplayLog {
+			last_seq: Utc::now().timestamp_micros().max(0) as u64,
+			events: VecDeque::new(),
+		});
+
+		Self {
+			tx,
+			log,
+			visibility_epoch: AtomicU64::new(0),
+		}
+	}
+
+	fn visibility_epoch(&self) -> u64 {
+		self.visibility_epoch.load(Ordering::Acquire)
+	}
+
+	/// Continues the sequence from `seq` instead, for events sequenced by a fan-out backend.
+	fn start_from(&self, seq: u64) {
+		self.lock_log().last_seq = seq;
+	}
+
+	fn lock_log(&self) -> std::sync::MutexGuard<'_, ReplayLog> {
+		self.log.lock().unwrap_or_else(|err| err.into_inner())
+	}
+
+	/// Subscribes to the channel, returning the sequence of the last event sent before it.
+	fn subscribe(&self) -> (Receiver<WsEnvelope>, u64) {
+		let log = self.lock_log();
+		(self.tx.subscribe(), log.last_seq)
+	}
+
+	fn latest_seq(&self) -> u64 {
+		self.lock_log().last_seq
+	}
+
+	/// Returns the events sent after `seq` up to `until`, or `None` if some of them are no longer
+	/// in the log.
+	fn replay(&self, seq: u64, until: u64) -> Option<Vec<WsEnvelope>> {
+		let log = self.lock_log();
+		let oldest = log
+			.events
+			.front()
+			.and_then(|env| env.seq)
+			.unwrap_or(log.last_seq + 1);
+
+		if seq > log.last_seq || seq + 1 < oldest {
+			return None;
+		}
 
-		Self { tx, modules }
+		Some(
+			log.events
+				.iter()
+				.filter(|env| {
+					env.seq
+						.is_some_and(|env_seq| env_seq > seq && env_seq <= until)
+				})
+				.cloned()
+				.collect(),
+		)
 	}
 
-	fn subscribe(&self) -> Receiver<WsEnvelope> {
-		self.tx.subscribe()
+	/// Hands an ephemeral event to the connections on this node, outside of the sequence.
+	fn deliver_ephemeral(&self, env: WsEnvelope) {
+		// Sending only fails when no socket is connected, in which case there is nobody to notify
+		let _ = self.tx.send(env);
+	}
+
+	/// Records an event and hands it to the connections on this node. Events without a sequence
+	/// are given the next one, while sequenced events are only accepted in order.
+	fn deliver(&self, mut env: WsEnvelope) {
+		// Sequencing and sending under the same lock keeps the channel in sequence order
+		let mut log = self.lock_log();
+
+		let seq = match env.seq {
+			None => log.last_seq + 1,
+			Some(seq) if seq <= log.last_seq => return,
+			Some(seq) => seq,
+		};
+
+		// Events in between were lost, e.g. while reconnecting to the fan-out backend, so tell
+		// connections to resync where those events would have been
+		if seq > log.last_seq + 1
+			&& let Ok(mut resync) =
+				WsEnvelope::new("system", "resync_required", SeqPayload { seq: seq - 1 })
+		{
+			resync.seq = Some(seq - 1);
+			log.push(&self.tx, resync);
+		}
+
+		// Before any connection sees the event, so that none of them judges it by what it knew
+		// before the change
+		if env.module == "directory" {
+			self.visibility_epoch.fetch_add(1, Ordering::AcqRel);
+		}
+
+		env.seq = Some(seq);
+		log.push(&self.tx, env);
 	}
+}
+
+impl ReplayLog {
+	fn push(&mut self, tx: &Sender<WsEnvelope>, env: WsEnvelope) {
+		self.last_seq = env.seq.unwrap_or(self.last_seq);
 
-	async fn receive(rx: &mut Receiver<WsEnvelope>) -> Option<WsEnvelope> {
-		match rx.recv().await {
-			Ok(env) => Some(env),
-			Err(broadcast::error::RecvError::Lagged(_)) => rx.recv().await.ok(),
-			Err(broadcast::error::RecvError::Closed) => None,
+		if self.events.len() == REPLAY_LOG_CAPACITY {
+			self.events.pop_front();
 		}
+		self.events.push_back(env.clone());
+
+		// Sending only fails when no socket is connected, in which case there is nobody to notify
+		let _ = tx.send(env);
+	}
+}
+
+#[derive(Clone)]
+pub struct WsState {
+	modules: Arc<HashMap<&'static str, &'static dyn WsModule>>,
+	hub: Arc<LocalHub>,
+	fan_out: Arc<dyn fan_out::FanOut>,
+	/// Identifies this process among those sharing the database, for keeping track of presence
+	node_id: i32,
+}
+
+impl WsState {
+	pub async fn new(
+		capacity: usize,
+		backend: FanOutBackend,
+		conn: &DatabaseConnection,
+	) -> Result<Self> {
+		let modules = Arc::new(MODULE_LIST.iter().map(|m| (m.name(), *m)).collect());
+
+		let hub = Arc::new(LocalHub::new(capacity));
+		let fan_out: Arc<dyn fan_out::FanOut> = match backend {
+			FanOutBackend::Memory => Arc::new(fan_out::MemoryFanOut::new(hub.clone())),
+			FanOutBackend::Postgres => {
+				Arc::new(fan_out::PostgresFanOut::start(conn, hub.clone()).await?)
+			}
+		};
+
+		let state = Self {
+			modules,
+			hub,
+			fan_out,
+			node_id: create_ws_node(conn, Utc::now()).await?,
+		};
+		tokio::spawn(presence::keep_node_alive(state.clone(), conn.clone()));
+
+		Ok(state)
 	}
 
 	pub async fn broadcast<T: Serialize>(
@@ -109,18 +485,76 @@ impl WsState {
 		r#type: &str,
 		payload: T,
 	) -> Result<()> {
-		if !self.modules.contains_key(module) {
-			return Err(anyhow!("Unknown module: {module}"));
+		let env = WsEnvelope::new(module, r#type, &payload)?;
+		self.publish(env).await
+	}
+
+	/// Broadcasts an event about a directory node to the connections subscribed to it.
+	pub async fn broadcast_to_directory<T: Serialize>(
+		&self,
+		conn: &DatabaseConnection,
+		directory_id: i32,
+		module: &str,
+		r#type: &str,
+		payload: T,
+	) -> Result<()> {
+		let path = get_ancestors(conn, directory_id)
+			.await?
+			.iter()
+			.map(|node| node.id)
+			.collect();
+
+		self.broadcast_to_path(path, module, r#type, payload).await
+	}
+
+	/// Announces a new directory node to the connections following where it was created. Nobody
+	/// can follow a new root yet, so those are announced to every connection instead, for the
+	/// ones that can see it to start following it.
+	pub async fn broadcast_node_created(
+		&self,
+		conn: &DatabaseConnection,
+		node: &Directory,
+	) -> Result<()> {
+		match node.parent_id {
+			Some(_) => {
+				self.broadcast_to_directory(conn, node.id, "directory", "node_created", node)
+					.await
+			}
+			None => self.broadcast("directory", "node_created", node).await,
 		}
+	}
 
-		let env = WsEnvelope::new(module, r#type, &payload)?;
-		self.tx.send(env)?;
-		Ok(())
+	/// Broadcasts an event to the connections subscribed to the first node of `path`, or to the
+	/// subtree of any node along it. Unlike `broadcast_to_directory`, this works for nodes that
+	/// no longer exist or whose ancestors changed, given the path from before.
+	pub async fn broadcast_to_path<T: Serialize>(
+		&self,
+		path: Vec<i32>,
+		module: &str,
+		r#type: &str,
+		payload: T,
+	) -> Result<()> {
+		let mut env = WsEnvelope::new(module, r#type, &payload)?;
+		env.topic = Some(path);
+		self.publish(env).await
+	}
+
+	async fn publish(&self, env: WsEnvelope) -> Result<()> {
+		let Some(module) = self.modules.get(env.module.as_str()) else {
+			return Err(anyhow!("Unknown module: {}", env.module));
+		};
+
+		if module.is_ephemeral(&env.r#type) {
+			self.fan_out.publish_ephemeral(env).await
+		} else {
+			self.fan_out.publish(env).await
+		}
 	}
 }
 
 enum ClientEvent {
 	Message(WsEnvelope),
+	Invalid(serde_json::Error),
 	Disconnect,
 	Continue,
 }
@@ -129,10 +563,7 @@ async fn receive_msg_from_client(receiver: &mut SplitStream<WebSocket>) -> Clien
 	match receiver.next().await {
 		Some(Ok(WsMessage::Text(text))) => match serde_json::from_str(&text) {
 			Ok(env) => ClientEvent::Message(env),
-			Err(err) => {
-				eprintln!("Invalid message from client: {err}");
-				ClientEvent::Continue
-			}
+			Err(err) => ClientEvent::Invalid(err),
 		},
 		Some(Ok(WsMessage::Close(_))) | None => ClientEvent::Disconnect,
 		Some(Ok(_)) => ClientEvent::Continue,
@@ -155,46 +586,196 @@ async fn send_msg_to_client(
 
 async fn send_error_to_client(
 	sender: &Arc<Mutex<SplitSink<WebSocket, WsMessage>>>,
-	msg: &str,
+	id: Option<String>,
+	err: &WsError,
+) -> Result<()> {
+	let mut env = WsEnvelope::new("system", "error", err)?;
+	env.id = id;
+	send_msg_to_client(sender, &env).await
+}
+
+async fn send_ack_to_client(
+	sender: &Arc<Mutex<SplitSink<WebSocket, WsMessage>>>,
+	id: String,
+	payload: Option<WsPayload>,
+) -> Result<()> {
+	let mut env = WsEnvelope::new("system", "ack", payload)?;
+	env.id = Some(id);
+	send_msg_to_client(sender, &env).await
+}
+
+/// Handles an envelope sent by the client and replies to it: with an `ack` if it succeeded and
+/// the client asked for one by setting an id, and with an `error` otherwise.
+async fn handle_client_envelope(
+	ctx: &WsContext,
+	sender: &Arc<Mutex<SplitSink<WebSocket, WsMessage>>>,
+	env: WsEnvelope,
+) -> Result<()> {
+	if let Err(retry_after) = check_ws(&ctx.username, &env.module, &env.r#type) {
+		return send_error_to_client(sender, env.id, &WsError::rate_limited(retry_after)).await;
+	}
+
+	// Heartbeats only concern this connection, so they are answered here rather than by a module
+	if env.module == "system" && env.r#type == "ping" {
+		let mut pong = WsEnvelope::new("system", "pong", ())?;
+		pong.id = env.id;
+		return send_msg_to_client(sender, &pong).await;
+	}
+
+	let result = match ctx.state.modules.get(env.module.as_str()) {
+		Some(module) => module
+			.handle(ctx, &env.r#type, &env.payload)
+			.await
+			.map_err(|err| {
+				eprintln!("{err}");
+				WsError::from(err)
+			}),
+		None => Err(WsError::new(
+			WsErrorCode::UnknownModule,
+			format!("Unknown module: {}", env.module),
+		)),
+	};
+
+	match (result, env.id) {
+		(Ok(payload), Some(id)) => send_ack_to_client(sender, id, payload).await,
+		(Ok(_), None) => Ok(()),
+		(Err(err), id) => send_error_to_client(sender, id, &err).await,
+	}
+}
+
+async fn close_client(
+	sender: &Arc<Mutex<SplitSink<WebSocket, WsMessage>>>,
+	code: u16,
+	reason: &'static str,
+) {
+	let frame = CloseFrame {
+		code,
+		reason: reason.into(),
+	};
+	let _ = sender
+		.lock()
+		.await
+		.send(WsMessage::Close(Some(frame)))
+		.await;
+}
+
+/// Tells the client it missed events that can no longer be replayed, so it should reload its
+/// state and carry on from `seq`.
+async fn send_resync_to_client(
+	sender: &Arc<Mutex<SplitSink<WebSocket, WsMessage>>>,
+	seq: u64,
 ) -> Result<()> {
-	let env = WsEnvelope::new("system", "error", msg)?;
+	let env = WsEnvelope::new("system", "resync_required", SeqPayload { seq })?;
 	send_msg_to_client(sender, &env).await
 }
 
+/// Sends a broadcast event to the client if it is meant for this connection, returning whether
+/// the connection should be closed afterwards.
+async fn deliver_to_client(
+	ctx: &WsContext,
+	sender: &Arc<Mutex<SplitSink<WebSocket, WsMessage>>>,
+	env: &WsEnvelope,
+	ignore_subscriptions: bool,
+) -> Result<bool> {
+	let Some(module) = ctx.state.modules.get(env.module.as_str()) else {
+		return Ok(false);
+	};
+
+	let should_send = (ignore_subscriptions || ctx.is_subscribed(env.topic.as_deref()))
+		&& module.should_deliver(ctx, &env.r#type, &env.payload).await;
+	if should_send {
+		send_msg_to_client(sender, env).await?;
+	}
+
+	Ok(module.should_disconnect(ctx, &env.r#type, &env.payload))
+}
+
 pub async fn handle_socket(
 	socket: WebSocket,
 	conn: DatabaseConnection,
 	state: WsState,
-	username: String,
+	storage: Arc<dyn Storage>,
+	claims: Claims,
+	resume_from: Option<u64>,
 ) {
 	let (sender, mut receiver) = socket.split();
 	let sender = Arc::new(Mutex::new(sender));
 
-	let mut rx = state.subscribe();
+	let (mut rx, mut last_seq) = state.hub.subscribe();
 
 	let ctx = WsContext {
 		conn,
 		state: state.clone(),
-		username,
+		storage,
+		username: claims.sub,
+		session_id: claims.sid,
+		connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+		subscriptions: Default::default(),
+		token_exp: AtomicUsize::new(claims.exp),
+		visibility: Default::default(),
 	};
 
+	for module in state.modules.values() {
+		if let Err(err) = module.on_connect(&ctx).await {
+			eprintln!("{err}");
+		}
+	}
+
+	if let Some(resume_from) = resume_from {
+		let result = match state.hub.replay(resume_from, last_seq) {
+			// The client has not subscribed to anything yet on this connection, so it gets every
+			// missed event it may see rather than none of them
+			Some(events) => {
+				async {
+					for env in &events {
+						deliver_to_client(&ctx, &sender, env, true).await?;
+					}
+					Ok(())
+				}
+				.await
+			}
+			None => send_resync_to_client(&sender, last_seq).await,
+		};
+
+		if let Err(err) = result {
+			eprintln!("{err}");
+		}
+	}
+
+	let mut ping_interval = interval_at(
+		Instant::now() + HEARTBEAT.ping_interval,
+		HEARTBEAT.ping_interval,
+	);
+	let mut last_received = Instant::now();
+	let mut last_envelope = Instant::now();
+	let mut pong_deadline: Option<Instant> = None;
+
 	loop {
+		let token_expiry = Instant::now() + ctx.token_expires_in();
+		let idle_deadline = HEARTBEAT
+			.idle_timeout
+			.map(|timeout| last_envelope + timeout);
+
 		tokio::select! {
 			msg = receive_msg_from_client(&mut receiver) => {
+				// Any frame shows the connection is alive, including pongs
+				last_received = Instant::now();
+				pong_deadline = None;
+
 				match msg {
 					ClientEvent::Message(env) => {
-						if let Some(module) = state.modules.get(env.module.as_str()) {
-							if let Err(err) = module.handle(&ctx, &env.r#type, &env.payload).await {
-								eprintln!("{err}");
-								if let Err(err) = send_error_to_client(&sender, "Internal server error").await {
-									eprintln!("{err}");
-									break;
-								}
-							}
-						} else if let Err(err) = send_error_to_client(
-							&sender,
-							&format!("Unknown module: {}", env.module)
-						).await {
+						// Heartbeats keep the connection open, but are no sign of anyone using it
+						if !env.is_ping() {
+							last_envelope = last_received;
+						}
+						if let Err(err) = handle_client_envelope(&ctx, &sender, env).await {
+							eprintln!("{err}");
+							break;
+						}
+					}
+					ClientEvent::Invalid(err) => {
+						let err = WsError::bad_request(format!("Invalid message: {err}"));
+						if let Err(err) = send_error_to_client(&sender, None, &err).await {
 							eprintln!("{err}");
 							break;
 						}
@@ -204,15 +785,221 @@ pub async fn handle_socket(
 				}
 			}
 
-			env = WsState::receive(&mut rx) => {
-				if let Some(env) = env && let Some(module) = state.modules.get(env.module.as_str()) {
-					let should_send = module.should_deliver(&ctx, &env.r#type, &env.payload);
-					if should_send && let Err(err) = send_msg_to_client(&sender, &env).await {
-						eprintln!("{err}");
-						break;
+			_ = ping_interval.tick() => {
+				// Clients sending their own heartbeats are never pinged, which keeps them connected
+				// behind proxies that drop control frames
+				if last_received.elapsed() < HEARTBEAT.ping_interval {
+					continue;
+				}
+
+				if let Err(err) = sender.lock().await.send(WsMessage::Ping(Default::default())).await {
+					eprintln!("{err}");
+					break;
+				}
+				pong_deadline.get_or_insert(Instant::now() + HEARTBEAT.pong_timeout);
+			}
+
+			_ = async {
+				match pong_deadline {
+					Some(deadline) => sleep_until(deadline).await,
+					None => future::pending().await,
+				}
+			} => {
+				close_client(&sender, close_code::AWAY, "Heartbeat timed out").await;
+				break;
+			}
+
+			_ = async {
+				match idle_deadline {
+					Some(deadline) => sleep_until(deadline).await,
+					None => future::pending().await,
+				}
+			} => {
+				close_client(&sender, close_code::NORMAL, "Idle timeout").await;
+				break;
+			}
+
+			_ = sleep_until(token_expiry) => {
+				close_client(&sender, TOKEN_EXPIRED_CLOSE_CODE, "Token expired").await;
+				break;
+			}
+
+			result = rx.recv() => {
+				let events = match result {
+					Ok(env) => vec![env],
+					// Catch up from the log instead of silently skipping what was dropped
+					Err(RecvError::Lagged(_)) => match state.hub.replay(last_seq, u64::MAX) {
+						Some(events) => events,
+						None => {
+							last_seq = state.hub.latest_seq();
+							if let Err(err) = send_resync_to_client(&sender, last_seq).await {
+								eprintln!("{err}");
+								break;
+							}
+							continue;
+						}
+					},
+					Err(RecvError::Closed) => break,
+				};
+
+				let mut disconnect = false;
+				for env in events {
+					// Events replayed after lagging may also still be queued on the channel
+					match env.seq {
+						Some(seq) if seq <= last_seq => continue,
+						Some(seq) => last_seq = seq,
+						None => {}
+					}
+
+					match deliver_to_client(&ctx, &sender, &env, false).await {
+						Ok(false) => {}
+						Ok(true) => {
+							let _ = sender.lock().await.send(WsMessage::Close(None)).await;
+							disconnect = true;
+							break;
+						}
+						Err(err) => {
+							eprintln!("{err}");
+							disconnect = true;
+							break;
+						}
 					}
 				}
+				if disconnect {
+					break;
+				}
 			}
 		}
 	}
+
+	for module in state.modules.values() {
+		if let Err(err) = module.on_disconnect(&ctx).await {
+			eprintln!("{err}");
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn event(module: &str, seq: Option<u64>) -> WsEnvelope {
+		let mut env = WsEnvelope::new(module, "test", ()).unwrap();
+		env.seq = seq;
+		env
+	}
+
+	fn seqs(events: &[WsEnvelope]) -> Vec<u64> {
+		events.iter().filter_map(|env| env.seq).collect()
+	}
+
+	#[test]
+	fn deliver_sequences_events_in_order() {
+		let hub = LocalHub::new(16);
+		let (mut rx, start) = hub.subscribe();
+
+		hub.deliver(event("messages", None));
+		hub.deliver(event("messages", None));
+
+		assert_eq!(hub.latest_seq(), start + 2);
+		assert_eq!(rx.try_recv().unwrap().seq, Some(start + 1));
+		assert_eq!(rx.try_recv().unwrap().seq, Some(start + 2));
+	}
+
+	#[test]
+	fn replay_returns_the_events_in_between() {
+		let hub = LocalHub::new(16);
+		let start = hub.latest_seq();
+		for _ in 0..5 {
+			hub.deliver(event("messages", None));
+		}
+
+		assert_eq!(
+			seqs(&hub.replay(start, start + 5).unwrap()),
+			[start + 1, start + 2, start + 3, start + 4, start + 5]
+		);
+		assert_eq!(
+			seqs(&hub.replay(start + 2, start + 4).unwrap()),
+			[start + 3, start + 4]
+		);
+		assert!(hub.replay(start + 5, start + 5).unwrap().is_empty());
+	}
+
+	#[test]
+	fn replay_refuses_sequences_it_cannot_account_for() {
+		let hub = LocalHub::new(16);
+		let start = hub.latest_seq();
+		hub.deliver(event("messages", None));
+
+		// From the future, e.g. from before a restart with the clock set back
+		assert!(hub.replay(start + 2, start + 2).is_none());
+
+		// Events that fell out of the log
+		for _ in 0..REPLAY_LOG_CAPACITY {
+			hub.deliver(event("messages", None));
+		}
+		let latest = hub.latest_seq();
+		assert!(hub.replay(start, latest).is_none());
+		assert!(hub.replay(start + 1, latest).is_some());
+	}
+
+	#[test]
+	fn deliver_ignores_events_already_sequenced() {
+		let hub = LocalHub::new(16);
+		hub.start_from(10);
+		let (mut rx, _) = hub.subscribe();
+
+		hub.deliver(event("messages", Some(11)));
+		hub.deliver(event("messages", Some(11)));
+		hub.deliver(event("messages", Some(9)));
+
+		assert_eq!(hub.latest_seq(), 11);
+		assert_eq!(rx.try_recv().unwrap().seq, Some(11));
+		assert!(rx.try_recv().is_err());
+	}
+
+	#[test]
+	fn deliver_asks_for_a_resync_over_gaps() {
+		let hub = LocalHub::new(16);
+		hub.start_from(10);
+		let (mut rx, _) = hub.subscribe();
+
+		hub.deliver(event("messages", Some(14)));
+
+		let resync = rx.try_recv().unwrap();
+		assert_eq!(
+			(resync.module.as_str(), resync.r#type.as_str()),
+			("system", "resync_required")
+		);
+		assert_eq!(resync.seq, Some(13));
+		assert_eq!(resync.payload.get::<Value>().unwrap()["seq"], 13);
+		assert_eq!(rx.try_recv().unwrap().seq, Some(14));
+
+		// The lost events cannot be replayed, but the resync can be to those past them
+		assert!(hub.replay(10, 14).is_none());
+		assert_eq!(seqs(&hub.replay(12, 14).unwrap()), [13, 14]);
+	}
+
+	#[test]
+	fn ephemeral_events_are_not_sequenced_or_kept() {
+		let hub = LocalHub::new(16);
+		let (mut rx, start) = hub.subscribe();
+
+		hub.deliver_ephemeral(event("messages", None));
+
+		assert_eq!(rx.try_recv().unwrap().seq, None);
+		assert_eq!(hub.latest_seq(), start);
+		assert!(hub.replay(start, start).unwrap().is_empty());
+	}
+
+	#[test]
+	fn directory_events_invalidate_visibility() {
+		let hub = LocalHub::new(16);
+
+		hub.deliver(event("messages", None));
+		assert_eq!(hub.visibility_epoch(), 0);
+
+		hub.deliver(event("directory", None));
+		assert_eq!(hub.visibility_epoch(), 1);
+	}
 }
@@ -0,0 +1,189 @@
+use crate::db::{self, WsConnectionChange, set_last_seen};
+use crate::entity::ws_connections::Model as WsConnection;
+use crate::websocket::{WsContext, WsError, WsModule, WsPayload, WsState};
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use sea_orm::{DatabaseConnection, DbErr};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+/// How often a node records that it is still alive
+const NODE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// How long a node may go without a heartbeat before its connections are given up on
+const NODE_TIMEOUT: TimeDelta = TimeDelta::seconds(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+	Online,
+	Idle,
+	Offline,
+}
+
+impl PresenceStatus {
+	/// A user is online if any of their connections is active, and idle if all of them are.
+	fn of(connections: &[WsConnection]) -> Self {
+		if connections.is_empty() {
+			PresenceStatus::Offline
+		} else if connections.iter().all(|connection| connection.idle) {
+			PresenceStatus::Idle
+		} else {
+			PresenceStatus::Online
+		}
+	}
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PresencePayload {
+	pub username: String,
+	pub status: PresenceStatus,
+	pub last_seen_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct SetIdlePayload {
+	idle: bool,
+}
+
+/// Status of every user with a connection open anywhere. Everyone else is offline.
+pub async fn get_all_presence(
+	db: &DatabaseConnection,
+) -> Result<HashMap<String, PresenceStatus>, DbErr> {
+	let mut connections: HashMap<String, Vec<WsConnection>> = HashMap::new();
+	for connection in db::get_all_ws_connections(db).await? {
+		connections
+			.entry(connection.username.clone())
+			.or_default()
+			.push(connection);
+	}
+
+	Ok(connections
+		.into_iter()
+		.map(|(username, connections)| (username, PresenceStatus::of(&connections)))
+		.collect())
+}
+
+/// Tells everyone when the status of `username` changed, recording when they were last seen if
+/// they went offline.
+async fn broadcast_change(
+	state: &WsState,
+	conn: &DatabaseConnection,
+	username: &str,
+	(before, after): (PresenceStatus, PresenceStatus),
+) -> Result<()> {
+	if before == after {
+		return Ok(());
+	}
+
+	let last_seen_at = match after {
+		PresenceStatus::Offline => {
+			let now = Utc::now();
+			set_last_seen(conn, username, now).await?;
+			Some(now)
+		}
+		_ => None,
+	};
+
+	let payload = PresencePayload {
+		username: username.to_string(),
+		status: after,
+		last_seen_at,
+	};
+
+	state
+		.broadcast("presence", "presence_changed", &payload)
+		.await
+}
+
+/// Applies `change` to the connection of `ctx` and tells everyone if the user's status changed.
+async fn update_connection(ctx: &WsContext, change: WsConnectionChange) -> Result<()> {
+	let (before, after) = db::update_ws_connection(
+		&ctx.conn,
+		ctx.state.node_id,
+		ctx.connection_id,
+		&ctx.username,
+		change,
+	)
+	.await?;
+
+	broadcast_change(
+		&ctx.state,
+		&ctx.conn,
+		&ctx.username,
+		(PresenceStatus::of(&before), PresenceStatus::of(&after)),
+	)
+	.await
+}
+
+/// Keeps the connections of this node counted for as long as it runs, and gives up on those of
+/// nodes that stopped without closing them, telling everyone about the users they took offline.
+pub(super) async fn keep_node_alive(state: WsState, conn: DatabaseConnection) {
+	let mut interval = tokio::time::interval(NODE_HEARTBEAT_INTERVAL);
+	loop {
+		interval.tick().await;
+		if let Err(err) = heartbeat(&state, &conn).await {
+			eprintln!("Failed to record WebSocket node heartbeat: {err}");
+		}
+	}
+}
+
+async fn heartbeat(state: &WsState, conn: &DatabaseConnection) -> Result<()> {
+	let now = Utc::now();
+	db::touch_ws_node(conn, state.node_id, now).await?;
+
+	let mut dropped: HashMap<String, Vec<WsConnection>> = HashMap::new();
+	for connection in db::delete_stale_ws_nodes(conn, now - NODE_TIMEOUT).await? {
+		dropped
+			.entry(connection.username.clone())
+			.or_default()
+			.push(connection);
+	}
+
+	for (username, dropped) in dropped {
+		let remaining = db::get_ws_connections(conn, &username).await?;
+		let before = PresenceStatus::of(&[remaining.as_slice(), dropped.as_slice()].concat());
+		let after = PresenceStatus::of(&remaining);
+		broadcast_change(state, conn, &username, (before, after)).await?;
+	}
+
+	Ok(())
+}
+
+pub struct PresenceModule;
+
+#[async_trait::async_trait]
+impl WsModule for PresenceModule {
+	fn name(&self) -> &'static str {
+		"presence"
+	}
+
+	async fn handle(
+		&self,
+		ctx: &WsContext,
+		r#type: &str,
+		payload: &WsPayload,
+	) -> Result<Option<WsPayload>> {
+		match r#type {
+			"set_idle" => {
+				let SetIdlePayload { idle } = payload.get()?;
+				update_connection(ctx, WsConnectionChange::SetIdle(idle)).await?;
+				Ok(None)
+			}
+
+			other => Err(WsError::unknown_type(self.name(), other).into()),
+		}
+	}
+
+	async fn on_connect(&self, ctx: &WsContext) -> Result<()> {
+		update_connection(ctx, WsConnectionChange::Open).await
+	}
+
+	async fn on_disconnect(&self, ctx: &WsContext) -> Result<()> {
+		update_connection(ctx, WsConnectionChange::Close).await
+	}
+
+	// Clients fetch everyone's status again whenever they reconnect, so nothing is lost for good
+	fn is_ephemeral(&self, r#type: &str) -> bool {
+		r#type == "presence_changed"
+	}
+}
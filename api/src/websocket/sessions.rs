@@ -0,0 +1,37 @@
+use crate::websocket::{WsContext, WsModule, WsPayload};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize)]
+pub struct SessionsRevokedPayload {
+	pub username: String,
+	pub session_ids: Vec<i32>,
+}
+
+pub struct SessionsModule;
+
+#[async_trait::async_trait]
+impl WsModule for SessionsModule {
+	fn name(&self) -> &'static str {
+		"sessions"
+	}
+
+	async fn should_deliver(&self, ctx: &WsContext, r#type: &str, payload: &WsPayload) -> bool {
+		match r#type {
+			"sessions_revoked" => match payload.get::<SessionsRevokedPayload>() {
+				Ok(p) => p.username == ctx.username,
+				Err(_) => false,
+			},
+			_ => true,
+		}
+	}
+
+	fn should_disconnect(&self, ctx: &WsContext, r#type: &str, payload: &WsPayload) -> bool {
+		match r#type {
+			"sessions_revoked" => match payload.get::<SessionsRevokedPayload>() {
+				Ok(p) => p.username == ctx.username && p.session_ids.contains(&ctx.session_id),
+				Err(_) => false,
+			},
+			_ => false,
+		}
+	}
+}
@@ -0,0 +1,188 @@
+use crate::auth::validate_token;
+use crate::db::get_session;
+use crate::permissions::can_view;
+use crate::websocket::{WsContext, WsError, WsErrorCode, WsModule, WsPayload};
+use anyhow::Result;
+use chrono::Utc;
+use sea_orm::DbErr;
+use serde::Deserialize;
+use std::{collections::HashSet, sync::atomic::Ordering, time::Duration};
+
+/// The directory nodes a connection receives events for.
+#[derive(Default)]
+pub struct Subscriptions {
+	/// Nodes followed on their own
+	directories: HashSet<i32>,
+	/// Nodes followed along with everything below them
+	subtrees: HashSet<i32>,
+}
+
+impl Subscriptions {
+	/// Whether an event on the node at the start of `topic`, which lists its ancestors after it,
+	/// matches these subscriptions.
+	fn matches(&self, topic: &[i32]) -> bool {
+		topic
+			.first()
+			.is_some_and(|id| self.directories.contains(id))
+			|| topic.iter().any(|id| self.subtrees.contains(id))
+	}
+}
+
+impl WsContext {
+	pub(super) fn is_subscribed(&self, topic: Option<&[i32]>) -> bool {
+		match topic {
+			None => true,
+			Some(topic) => self
+				.subscriptions
+				.lock()
+				.unwrap_or_else(|err| err.into_inner())
+				.matches(topic),
+		}
+	}
+
+	/// Time left until the token the connection last authenticated with expires.
+	pub(super) fn token_expires_in(&self) -> Duration {
+		let exp = self.token_exp.load(Ordering::Relaxed) as i64;
+		Duration::from_secs((exp - Utc::now().timestamp()).max(0) as u64)
+	}
+
+	fn update_subscriptions(&self, update: impl FnOnce(&mut Subscriptions)) {
+		update(
+			&mut self
+				.subscriptions
+				.lock()
+				.unwrap_or_else(|err| err.into_inner()),
+		);
+	}
+}
+
+#[derive(Deserialize)]
+struct SubscriptionPayload {
+	directory_id: i32,
+	#[serde(default)]
+	subtree: bool,
+}
+
+#[derive(Deserialize)]
+struct AuthenticatePayload {
+	token: String,
+}
+
+pub struct SystemModule;
+
+#[async_trait::async_trait]
+impl WsModule for SystemModule {
+	fn name(&self) -> &'static str {
+		"system"
+	}
+
+	async fn handle(
+		&self,
+		ctx: &WsContext,
+		r#type: &str,
+		payload: &WsPayload,
+	) -> Result<Option<WsPayload>> {
+		match r#type {
+			"subscribe" => {
+				let SubscriptionPayload {
+					directory_id,
+					subtree,
+				} = payload.get()?;
+
+				if !can_view(&ctx.conn, &ctx.username, directory_id).await? {
+					return Err(WsError::forbidden(format!(
+						"User '{}' may not view directory {directory_id}",
+						ctx.username
+					))
+					.into());
+				}
+
+				ctx.update_subscriptions(|subscriptions| {
+					if subtree {
+						subscriptions.subtrees.insert(directory_id);
+					} else {
+						subscriptions.directories.insert(directory_id);
+					}
+				});
+				Ok(None)
+			}
+
+			"unsubscribe" => {
+				let SubscriptionPayload {
+					directory_id,
+					subtree,
+				} = payload.get()?;
+
+				ctx.update_subscriptions(|subscriptions| {
+					if subtree {
+						subscriptions.subtrees.remove(&directory_id);
+					} else {
+						subscriptions.directories.remove(&directory_id);
+					}
+				});
+				Ok(None)
+			}
+
+			// Lets a client keep its connection open past the expiry of the token it connected with
+			"authenticate" => {
+				let AuthenticatePayload { token } = payload.get()?;
+
+				let unauthorized = |message| WsError::new(WsErrorCode::Unauthorized, message);
+
+				let claims = validate_token(&token).map_err(|_| unauthorized("Invalid token"))?;
+				if claims.sub != ctx.username || claims.sid != ctx.session_id {
+					return Err(unauthorized("Token belongs to another session").into());
+				}
+
+				match get_session(&ctx.conn, claims.sid).await {
+					Ok(session) if session.revoked_at.is_none() => {}
+					Ok(_) | Err(DbErr::RecordNotFound(_)) => {
+						return Err(unauthorized("Session has been revoked").into());
+					}
+					Err(err) => return Err(err.into()),
+				}
+
+				ctx.token_exp.store(claims.exp, Ordering::Relaxed);
+				Ok(None)
+			}
+
+			other => Err(WsError::unknown_type(self.name(), other).into()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn subscriptions(directories: &[i32], subtrees: &[i32]) -> Subscriptions {
+		Subscriptions {
+			directories: directories.iter().copied().collect(),
+			subtrees: subtrees.iter().copied().collect(),
+		}
+	}
+
+	#[test]
+	fn directory_subscriptions_match_only_the_node_itself() {
+		let subscriptions = subscriptions(&[2], &[]);
+		assert!(subscriptions.matches(&[2, 1]));
+		assert!(!subscriptions.matches(&[3, 2, 1]));
+		assert!(!subscriptions.matches(&[1]));
+	}
+
+	#[test]
+	fn subtree_subscriptions_match_everything_below() {
+		let subscriptions = subscriptions(&[], &[2]);
+		assert!(subscriptions.matches(&[2, 1]));
+		assert!(subscriptions.matches(&[3, 2, 1]));
+		assert!(subscriptions.matches(&[4, 3, 2, 1]));
+		assert!(!subscriptions.matches(&[1]));
+		assert!(!subscriptions.matches(&[5, 1]));
+	}
+
+	#[test]
+	fn empty_topics_match_nothing() {
+		assert!(!subscriptions(&[1], &[1]).matches(&[]));
+		assert!(!Subscriptions::default().matches(&[1]));
+	}
+}
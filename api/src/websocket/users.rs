@@ -9,7 +9,7 @@ impl WsModule for UsersModule {
 		"users"
 	}
 
-	fn should_deliver(&self, ctx: &WsContext, r#type: &str, payload: &WsPayload) -> bool {
+	async fn should_deliver(&self, ctx: &WsContext, r#type: &str, payload: &WsPayload) -> bool {
 		match r#type {
 			"user_created" => match payload.get::<User>() {
 				Ok(p) => p.username != ctx.username,
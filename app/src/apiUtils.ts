@@ -5,28 +5,107 @@ import { getStorageItem } from "./storageUtils";
 export interface User {
 	username: string;
 	name: string;
+	last_seen_at: string | null;
+}
+
+export type PresenceStatus = "online" | "idle" | "offline";
+
+export interface Presence {
+	username: string;
+	status: PresenceStatus;
+	last_seen_at: string | null;
 }
 
 export interface DirectoryNode {
 	id: number;
+	name: string;
+	type: "folder" | "thread" | "dm";
+	parent_id: number | null;
+	is_private: boolean;
+	position: number;
+}
+
+export interface CreateDirectoryNode {
 	name: string;
 	type: "folder" | "thread";
 	parent_id: number | null;
+	is_private?: boolean;
+}
+
+export interface DirectoryWithUnread extends DirectoryNode {
+	unread_count: number;
+	mention_count: number;
+}
+
+export interface ReadMarker {
+	username: string;
+	directory_id: number;
+	last_read_message_id: number;
+	updated_at: string;
 }
 
 export interface CreateMessage {
 	content: string;
 	directory_id: number;
 	parent_id: number | null;
+	attachment_ids?: number[];
+}
+
+export interface Attachment {
+	id: number;
+	message_id: number | null;
+	uploader_username: string;
+	filename: string;
+	content_type: string;
+	size: number;
+	created_at: string;
+}
+
+export interface ReactionSummary {
+	emoji: string;
+	count: number;
+	usernames: string[];
 }
 
 export interface Message extends CreateMessage {
 	id: number;
 	author_username: string;
 	created_at: string;
+	edited_at: string | null;
+	deleted_at: string | null;
+	deleted_by: string | null;
+	reactions?: ReactionSummary[];
+	attachments?: Attachment[];
+}
+
+export type MentionKind = "user" | "here" | "everyone";
+
+export interface Mention {
+	id: number;
+	message_id: number;
+	username: string;
+	kind: MentionKind;
+	created_at: string;
+	read_at: string | null;
 }
 
-export type WsClientMessage =
+export interface MentionWithMessage extends Mention {
+	message: Message;
+}
+
+export interface MentionsPage {
+	mentions: MentionWithMessage[];
+	unread_count: number;
+	has_more: boolean;
+}
+
+export interface ThreadPage {
+	messages: Message[];
+	has_more_before: boolean;
+	has_more_after: boolean;
+}
+
+type WsClientRequest =
 	| {
 			module: "messages";
 			type: "typing";
@@ -41,9 +120,80 @@ export type WsClientMessage =
 			module: "messages";
 			type: "create_message";
 			payload: CreateMessage;
+	  }
+	| {
+			module: "messages";
+			type: "edit_message";
+			payload: { id: number; content: string };
+	  }
+	| {
+			module: "messages";
+			type: "delete_message";
+			payload: { id: number; purge?: boolean };
+	  }
+	| {
+			module: "messages";
+			type: "add_reaction" | "remove_reaction";
+			payload: { message_id: number; emoji: string };
+	  }
+	| {
+			module: "messages";
+			type: "mark_read";
+			payload: { thread_id: number; message_id: number };
+	  }
+	| {
+			module: "mentions";
+			type: "mark_read";
+			payload: { ids?: number[] };
+	  }
+	| {
+			module: "directory";
+			type: "create_node";
+			payload: CreateDirectoryNode;
+	  }
+	| {
+			module: "presence";
+			type: "set_idle";
+			payload: { idle: boolean };
+	  }
+	| {
+			module: "system";
+			type: "subscribe" | "unsubscribe";
+			payload: { directory_id: number; subtree?: boolean };
+	  }
+	| {
+			module: "system";
+			type: "ping";
+			payload: null;
+	  }
+	| {
+			module: "system";
+			type: "authenticate";
+			payload: { token: string };
 	  };
 
-export type WsServerMessage =
+/** With `id` set, the server replies with a `system.ack` or `system.error` */
+export type WsClientMessage = WsClientRequest & { id?: string };
+
+/** Close code for an expired token, which should be refreshed to reconnect */
+export const WS_TOKEN_EXPIRED_CLOSE_CODE = 4001;
+
+export interface WsError {
+	code:
+		| "bad_request"
+		| "unauthorized"
+		| "forbidden"
+		| "not_found"
+		| "unknown_module"
+		| "unknown_type"
+		| "rate_limited"
+		| "internal";
+	message: string;
+	/** Seconds to wait before retrying, for rate_limited errors */
+	retry_after?: number;
+}
+
+type WsServerEvent =
 	| {
 			module: "messages";
 			type: "user_typing";
@@ -59,17 +209,115 @@ export type WsServerMessage =
 			type: "message_created";
 			payload: Message;
 	  }
+	| {
+			module: "messages";
+			type: "message_edited";
+			payload: Message;
+	  }
+	| {
+			module: "messages";
+			type: "message_deleted";
+			payload: Message;
+	  }
+	| {
+			module: "messages";
+			type: "reactions_updated";
+			payload: {
+				message_id: number;
+				directory_id: number;
+				reactions: ReactionSummary[];
+			};
+	  }
+	| {
+			module: "messages";
+			type: "read_marker_updated";
+			payload: ReadMarker;
+	  }
+	| {
+			module: "mentions";
+			type: "mention";
+			payload: MentionWithMessage;
+	  }
+	| {
+			module: "mentions";
+			type: "mentions_read";
+			payload: { username: string; ids: number[] };
+	  }
+	| {
+			module: "directory";
+			type: "node_created" | "node_updated";
+			payload: DirectoryNode;
+	  }
+	| {
+			module: "directory";
+			type: "node_moved";
+			payload: DirectoryNode & { old_parent_id: number | null };
+	  }
+	| {
+			module: "directory";
+			type: "node_moved_away";
+			payload: { id: number; old_parent_id: number | null };
+	  }
+	| {
+			module: "directory";
+			type: "membership_changed";
+			payload: { directory_id: number; username: string };
+	  }
+	| {
+			module: "directory";
+			type: "node_deleted";
+			payload: {
+				id: number;
+				parent_id: number | null;
+				deleted_ids: number[];
+				reparented_ids: number[];
+				members: string[] | null;
+			};
+	  }
 	| {
 			module: "users";
 			type: "user_created";
 			payload: User;
 	  }
+	| {
+			module: "presence";
+			type: "presence_changed";
+			payload: Presence;
+	  }
 	| {
 			module: "system";
 			type: "error";
-			payload: string;
+			payload: WsError;
+	  }
+	| {
+			module: "system";
+			type: "ack";
+			payload: unknown;
+	  }
+	| {
+			module: "system";
+			type: "pong";
+			payload: null;
+	  }
+	| {
+			module: "system";
+			type: "resync_required";
+			payload: { seq: number };
 	  };
 
+/** `seq` is set on broadcast events and passed back as `resume_from` */
+export type WsServerMessage = WsServerEvent & { id?: string; seq?: number };
+
+/** The `token` query parameter authenticates requests made by elements such as `<img>` */
+export const attachmentUrl = (
+	attachment: Attachment,
+	token: string | null,
+	thumbnail = false,
+) =>
+	`http://${resolveAddress()}/api/attachments/${attachment.id}${
+		thumbnail ? "/thumbnail" : ""
+	}?token=${token ?? ""}`;
+
 export const resolveAddress = () => {
 	if (isServer) {
 		const { API_INTERNAL_HOST, API_INTERNAL_PORT } = process.env;
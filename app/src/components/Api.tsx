@@ -5,10 +5,12 @@ import { useAuth } from "./Auth.tsx";
 
 type GetApi = <T>(url: string) => Promise<T>;
 type PostApi = <T>(url: string, body: unknown) => Promise<T>;
+type UploadApi = <T>(url: string, file: File) => Promise<T>;
 
 interface ApiContextType {
 	getApi: GetApi;
 	postApi: PostApi;
+	uploadApi: UploadApi;
 }
 
 const ApiContext = createContext<ApiContextType>();
@@ -22,7 +24,7 @@ export const useApi = () => {
 };
 
 const ApiProvider: Component<{ children: JSX.Element }> = (props) => {
-	const { token, logout } = useAuth();
+	const auth = useAuth();
 	const queryClient = new QueryClient();
 
 	const api = async <T,>(
@@ -33,20 +35,34 @@ const ApiProvider: Component<{ children: JSX.Element }> = (props) => {
 		const address = resolveAddress();
 		if (!address) throw new Error("API address not found");
 
-		const options: RequestInit = {
-			method,
-			headers: {
-				"Content-Type": "application/json",
-				Authorization: `Bearer ${token}`,
-			},
+		const send = () => {
+			// The browser sets the multipart boundary itself
+			const isForm = body instanceof FormData;
+			const options: RequestInit = {
+				method,
+				headers: {
+					...(isForm ? {} : { "Content-Type": "application/json" }),
+					Authorization: `Bearer ${auth.token}`,
+				},
+			};
+
+			if (method === "POST" && body !== undefined) {
+				options.body = isForm ? body : JSON.stringify(body);
+			}
+
+			return fetch(`http://${address}/api${url}`, options);
 		};
 
-		if (method === "POST" && body !== undefined) {
-			options.body = JSON.stringify(body);
+		let res = await send();
+		if (res.status === 401) {
+			if (await auth.refresh()) {
+				res = await send();
+			}
+			if (res.status === 401) auth.logout();
+		}
+		if (body instanceof FormData && !res.ok) {
+			throw new Error(`Upload failed with status ${res.status}`);
 		}
-
-		const res = await fetch(`http://${address}/api${url}`, options);
-		if (res.status === 401) logout();
 		return await res.json();
 	};
 
@@ -55,8 +71,14 @@ const ApiProvider: Component<{ children: JSX.Element }> = (props) => {
 	const postApi = <T,>(url: string, body: unknown) =>
 		api<T>("POST", url, body);
 
+	const uploadApi = <T,>(url: string, file: File) => {
+		const form = new FormData();
+		form.append("file", file);
+		return api<T>("POST", url, form);
+	};
+
 	return (
-		<ApiContext.Provider value={{ getApi, postApi }}>
+		<ApiContext.Provider value={{ getApi, postApi, uploadApi }}>
 			<QueryClientProvider client={queryClient}>
 				{props.children}
 			</QueryClientProvider>
@@ -23,12 +23,18 @@ interface SignUpCredentials extends LoginCredentials {
 
 interface AuthState {
 	token: string | null;
+	refresh_token: string | null;
 	user: User | null;
 }
 
+/** A login with TOTP enabled needs a code passed to `verifyMfa` to finish */
+type LoginResult = "success" | "mfa_required" | "failed";
+
 interface AuthContextType extends AuthState {
-	login: (credentials: LoginCredentials) => Promise<boolean>;
+	login: (credentials: LoginCredentials) => Promise<LoginResult>;
+	verifyMfa: (code: string) => Promise<boolean>;
 	signup: (credentials: SignUpCredentials) => Promise<boolean>;
+	refresh: () => Promise<boolean>;
 	logout: () => void;
 }
 
@@ -44,38 +50,91 @@ export const useAuth = () => {
 
 const AuthProvider: Component<{ children: JSX.Element }> = (props) => {
 	const [state, setState] = createSignal<AuthState>(
-		getStorageItem("auth") || { token: null, user: null },
+		getStorageItem("auth") || {
+			token: null,
+			refresh_token: null,
+			user: null,
+		},
 	);
 
+	// Stands in for the password until the second factor is given
+	let mfaToken: string | null = null;
+
 	const authenticate = async (
-		endpoint: "login" | "signup",
-		credentials: LoginCredentials | SignUpCredentials,
-	) => {
+		endpoint: "login" | "login/mfa" | "signup",
+		body: object,
+	): Promise<LoginResult> => {
 		const address = resolveAddress();
-		if (!address) return false;
+		if (!address) return "failed";
 
 		try {
 			const res = await fetch(`http://${address}/api/${endpoint}`, {
 				method: "POST",
 				headers: { "Content-Type": "application/json" },
-				body: JSON.stringify(credentials),
+				body: JSON.stringify(body),
 			});
 
 			if (res.ok) {
-				const data: AuthState = await res.json();
+				const data: AuthState | { mfa_token: string } =
+					await res.json();
+				if ("mfa_token" in data) {
+					mfaToken = data.mfa_token;
+					return "mfa_required";
+				}
+
+				mfaToken = null;
 				setState(data);
 				setStorageItem("auth", data);
-				return true;
+				return "success";
 			}
 
-			return false;
+			return "failed";
 		} catch {
-			return false;
+			return "failed";
 		}
 	};
 
+	const verifyMfa = async (code: string) =>
+		mfaToken !== null &&
+		(await authenticate("login/mfa", { mfa_token: mfaToken, code })) ===
+			"success";
+
+	let pendingRefresh: Promise<boolean> | null = null;
+
+	const refresh = () => {
+		pendingRefresh ??= (async () => {
+			const address = resolveAddress();
+			const refreshToken = state().refresh_token;
+			if (!address || !refreshToken) return false;
+
+			try {
+				const res = await fetch(
+					`http://${address}/api/token/refresh`,
+					{
+						method: "POST",
+						headers: { "Content-Type": "application/json" },
+						body: JSON.stringify({ refresh_token: refreshToken }),
+					},
+				);
+				if (!res.ok) return false;
+
+				const tokens: Pick<AuthState, "token" | "refresh_token"> =
+					await res.json();
+				const data = { ...state(), ...tokens };
+				setState(data);
+				setStorageItem("auth", data);
+				return true;
+			} catch {
+				return false;
+			} finally {
+				pendingRefresh = null;
+			}
+		})();
+		return pendingRefresh;
+	};
+
 	const logout = () => {
-		setState({ token: null, user: null });
+		setState({ token: null, refresh_token: null, user: null });
 		deleteStorageItem("auth");
 	};
 
@@ -83,11 +142,17 @@ const AuthProvider: Component<{ children: JSX.Element }> = (props) => {
 		get token() {
 			return state().token;
 		},
+		get refresh_token() {
+			return state().refresh_token;
+		},
 		get user() {
 			return state().user;
 		},
 		login: (credentials) => authenticate("login", credentials),
-		signup: (credentials) => authenticate("signup", credentials),
+		verifyMfa,
+		signup: async (credentials) =>
+			(await authenticate("signup", credentials)) === "success",
+		refresh,
 		logout,
 	};
 
@@ -1,20 +1,25 @@
 import { createTreeCollection, TreeView } from "@ark-ui/solid/tree-view";
 import { A } from "@solidjs/router";
-import { useQuery } from "@tanstack/solid-query";
-import { type Component, For, Show, Suspense } from "solid-js";
-import type { DirectoryNode } from "../apiUtils.ts";
+import { useQuery, useQueryClient } from "@tanstack/solid-query";
+import { type Component, For, onCleanup, Show, Suspense } from "solid-js";
+import type { DirectoryWithUnread, WsServerMessage } from "../apiUtils.ts";
 import ChevronRight from "../assets/chevron-right.svg";
 import MessageSquareText from "../assets/message-square-text.svg";
 import { useApi } from "./Api.tsx";
+import { useWebSocket } from "./WebSocket.tsx";
 
 interface TreeNode {
 	id: number;
 	name: string;
-	type: "folder" | "thread";
+	type: "folder" | "thread" | "dm";
+	unread_count: number;
+	mention_count: number;
 	children?: TreeNode[];
 }
 
-const buildDirectory = (nodes: DirectoryNode[]): TreeNode | undefined => {
+const buildDirectory = (
+	nodes: DirectoryWithUnread[],
+): TreeNode | undefined => {
 	const nodeMap = new Map<number, TreeNode>();
 
 	for (const node of nodes) {
@@ -22,6 +27,8 @@ const buildDirectory = (nodes: DirectoryNode[]): TreeNode | undefined => {
 			id: node.id,
 			name: node.name,
 			type: node.type,
+			unread_count: node.unread_count,
+			mention_count: node.mention_count,
 		});
 	}
 
@@ -43,6 +50,24 @@ const buildDirectory = (nodes: DirectoryNode[]): TreeNode | undefined => {
 	return nodeMap.get(root.id);
 };
 
+const UnreadBadge: Component<{ node: TreeNode }> = (props) => (
+	<Show when={props.node.mention_count || props.node.unread_count}>
+		{(count) => (
+			<span
+				class="ml-auto px-2 rounded-full text-sm font-bold"
+				classList={{
+					"bg-accent-500 text-background-50":
+						props.node.mention_count > 0,
+					"bg-background-200 dark:bg-background-800 text-background-500 dark:text-background-400":
+						props.node.mention_count === 0,
+				}}
+			>
+				{count()}
+			</span>
+		)}
+	</Show>
+);
+
 const DirectoryItem: Component<TreeView.NodeProviderProps<TreeNode>> = (
 	props,
 ) => {
@@ -59,9 +84,13 @@ const DirectoryItem: Component<TreeView.NodeProviderProps<TreeNode>> = (
 						class={nodeClass}
 						inactiveClass="hover:bg-background-200 dark:hover:bg-background-800"
 						activeClass="bg-accent-100 dark:bg-accent-800"
+						classList={{
+						"font-bold": props.node.unread_count > 0,
+					}}
 					>
 						<MessageSquareText />
 						{props.node.name}
+						<UnreadBadge node={props.node} />
 					</A>
 				}
 			>
@@ -74,6 +103,7 @@ const DirectoryItem: Component<TreeView.NodeProviderProps<TreeNode>> = (
 								<ChevronRight />
 							</span>
 							{props.node.name}
+							<UnreadBadge node={props.node} />
 						</TreeView.BranchText>
 					</TreeView.BranchControl>
 					<TreeView.BranchContent class="flex gap-3 overflow-hidden data-[state=closed]:animate-[slideUp_200ms] data-[state=open]:animate-[slideDown_200ms]">
@@ -100,12 +130,34 @@ const DirectoryItem: Component<TreeView.NodeProviderProps<TreeNode>> = (
 
 const Directory = () => {
 	const { getApi } = useApi();
+	const { onMessage } = useWebSocket();
+	const queryClient = useQueryClient();
 
 	const nodes = useQuery(() => ({
 		queryKey: ["directory", 1],
-		queryFn: () => getApi<DirectoryNode[]>("/directory/1"),
+		queryFn: () => getApi<DirectoryWithUnread[]>("/directory/1"),
 	}));
 
+	const removeHandler = onMessage((event) => {
+		const env: WsServerMessage = JSON.parse(event.data);
+		if (env.module !== "messages" && env.module !== "directory") return;
+
+		switch (env.type) {
+			case "message_created":
+			case "message_deleted":
+			case "read_marker_updated":
+			case "node_created":
+			case "node_updated":
+			case "node_moved":
+			case "node_moved_away":
+			case "node_deleted":
+			case "membership_changed":
+				queryClient.invalidateQueries({ queryKey: ["directory", 1] });
+				break;
+		}
+	});
+	onCleanup(removeHandler);
+
 	return (
 		<Suspense>
 			<Show when={nodes.data}>
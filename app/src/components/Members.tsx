@@ -1,15 +1,49 @@
 import { useQuery, useQueryClient } from "@tanstack/solid-query";
-import { type Component, For, onCleanup, Suspense } from "solid-js";
-import type { User, WsServerMessage } from "../apiUtils.ts";
+import { type Component, For, onCleanup, Show, Suspense } from "solid-js";
+import type {
+	Presence,
+	PresenceStatus,
+	User,
+	WsServerMessage,
+} from "../apiUtils.ts";
 import { useApi } from "./Api.tsx";
 import Avatar from "./Avatar.tsx";
 import { useWebSocket } from "./WebSocket.tsx";
 
-const UserCard: Component<{ user: User }> = (props) => {
+const UserCard: Component<{
+	user: User;
+	presence: Presence | undefined;
+}> = (props) => {
+	const status = (): PresenceStatus => props.presence?.status ?? "offline";
+	const lastSeen = () => props.presence?.last_seen_at;
+
 	return (
-		<div class="flex gap-2 items-center">
-			<Avatar fallback={props.user.username[0]} className="w-10" />
-			{props.user.username}
+		<div
+			class="flex gap-2 items-center"
+			classList={{ "opacity-50": status() === "offline" }}
+		>
+			<div class="relative">
+				<Avatar fallback={props.user.username[0]} className="w-10" />
+				<Show when={status() !== "offline"}>
+					<span
+						class="absolute bottom-0 right-0 size-3 rounded-full outline-2 outline-background-50 dark:outline-background-900"
+						classList={{
+							"bg-green-500": status() === "online",
+							"bg-yellow-500": status() === "idle",
+						}}
+					/>
+				</Show>
+			</div>
+			<div class="flex flex-col">
+				{props.user.username}
+				<Show when={status() === "offline" && lastSeen()}>
+					{(seen) => (
+						<p class="text-xs text-background-400 dark:text-background-500">
+							Last seen {new Date(seen()).toLocaleString()}
+						</p>
+					)}
+				</Show>
+			</div>
 		</div>
 	);
 };
@@ -24,6 +58,16 @@ const Members: Component = () => {
 		queryFn: () => getApi<User[]>("/users"),
 	}));
 
+	const presence = useQuery(() => ({
+		queryKey: ["presence"],
+		queryFn: async () => {
+			const data = await getApi<Presence[]>("/presence");
+			return Object.fromEntries(
+				data.map((presence) => [presence.username, presence]),
+			) as Record<string, Presence>;
+		},
+	}));
+
 	const removeHandler = onMessage((event) => {
 		const env: WsServerMessage = JSON.parse(event.data);
 
@@ -33,6 +77,23 @@ const Members: Component = () => {
 				env.payload,
 			]);
 		}
+
+		if (env.module === "presence" && env.type === "presence_changed") {
+			const payload = env.payload;
+			queryClient.setQueryData<Record<string, Presence>>(
+				["presence"],
+				(prev) => ({
+					...prev,
+					[payload.username]: {
+						...payload,
+						last_seen_at:
+							payload.last_seen_at ??
+							prev?.[payload.username]?.last_seen_at ??
+							null,
+					},
+				}),
+			);
+		}
 	});
 	onCleanup(removeHandler);
 
@@ -43,7 +104,12 @@ const Members: Component = () => {
 			</p>
 			<Suspense>
 				<For each={users.data}>
-					{(user) => <UserCard user={user} />}
+					{(user) => (
+						<UserCard
+							user={user}
+							presence={presence.data?.[user.username]}
+						/>
+					)}
 				</For>
 			</Suspense>
 		</div>
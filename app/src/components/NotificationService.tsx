@@ -1,20 +1,19 @@
 import { useParams } from "@solidjs/router";
-import { useQuery } from "@tanstack/solid-query";
+import { useQueryClient } from "@tanstack/solid-query";
 import {
 	isPermissionGranted,
 	requestPermission,
 	sendNotification,
 } from "@tauri-apps/plugin-notification";
-import { type Component, createEffect, onCleanup } from "solid-js";
-import type { DirectoryNode, Message, WsServerMessage } from "../apiUtils.ts";
+import { type Component, onCleanup } from "solid-js";
+import type { DirectoryNode, WsServerMessage } from "../apiUtils.ts";
 import { useApi } from "./Api.tsx";
-import { useAuth } from "./Auth.tsx";
 import { useWebSocket } from "./WebSocket.tsx";
 
 const NotificationService: Component = () => {
 	const { onMessage } = useWebSocket();
-	const { user } = useAuth();
 	const { getApi } = useApi();
+	const queryClient = useQueryClient();
 	const params = useParams<{ id: string }>();
 
 	const permissionGranted = async () => {
@@ -28,32 +27,26 @@ const NotificationService: Component = () => {
 
 	const removeHandler = onMessage(async (event) => {
 		const env: WsServerMessage = JSON.parse(event.data);
-		if (env.module !== "messages" || env.type !== "message_created") return;
+		if (env.module !== "mentions" || env.type !== "mention") return;
 
-		const message = env.payload as Message;
+		const { message } = env.payload;
 
 		if (
 			(await permissionGranted()) &&
-			(message.directory_id !== Number(params.id) ||
-				!document.hasFocus()) &&
-			message.author_username !== user?.username
+			(message.directory_id !== Number(params.id) || !document.hasFocus())
 		) {
-			const thread = useQuery(() => ({
+			const thread = await queryClient.fetchQuery({
 				queryKey: ["directory", message.directory_id],
 				queryFn: () =>
 					getApi<DirectoryNode[]>(
 						`/directory/${message.directory_id}`,
 					),
-			}));
+			});
 
-			createEffect(() => {
-				const data = thread.data;
-				if (!data) return;
-				sendNotification({
-					title: `#${data[0]?.name ?? "Unknown"} — ${message.author_username}`,
-					body: message.content,
-					group: message.directory_id.toString(),
-				});
+			sendNotification({
+				title: `#${thread[0]?.name ?? "Unknown"} — ${message.author_username}`,
+				body: message.content,
+				group: message.directory_id.toString(),
 			});
 		}
 	});
@@ -1,20 +1,59 @@
+import { useQuery, useQueryClient } from "@tanstack/solid-query";
 import {
 	type Component,
 	createContext,
+	createEffect,
 	createSignal,
 	type JSX,
+	on,
 	onCleanup,
 	onMount,
 	useContext,
 } from "solid-js";
-import { resolveAddress, type WsClientMessage } from "../apiUtils.ts";
+import {
+	type DirectoryNode,
+	resolveAddress,
+	WS_TOKEN_EXPIRED_CLOSE_CODE,
+	type WsClientMessage,
+	type WsError,
+	type WsServerMessage,
+} from "../apiUtils.ts";
+import { useApi } from "./Api.tsx";
 import { useAuth } from "./Auth.tsx";
 
+const IDLE_TIMEOUT_MS = 5 * 60 * 1000;
+// Often enough that the server never pings, since proxies may drop pings
+const HEARTBEAT_INTERVAL_MS = 20 * 1000;
+const RECONNECT_MAX_DELAY_MS = 30 * 1000;
+
 interface WebSocketContextType {
 	onMessage: (handler: (event: MessageEvent) => void) => () => void;
 	sendMessage: (message: WsClientMessage) => void;
+	/** Sends a message and resolves with what the server acknowledges it with */
+	request: <T>(message: WsClientMessage) => Promise<T>;
 }
 
+/** Whether a directory event adds or removes a root the user can see */
+const changesRoots = (env: WsServerMessage) => {
+	if (env.module !== "directory") return false;
+	switch (env.type) {
+		case "node_created":
+		case "node_deleted":
+			return env.payload.parent_id === null;
+		case "node_moved":
+			return (
+				env.payload.parent_id === null ||
+				env.payload.old_parent_id === null
+			);
+		case "node_moved_away":
+			return env.payload.old_parent_id === null;
+		case "membership_changed":
+			return true;
+		default:
+			return false;
+	}
+};
+
 const WebSocketContext = createContext<WebSocketContextType>();
 
 export const useWebSocket = () => {
@@ -26,32 +65,206 @@ export const useWebSocket = () => {
 };
 
 const WebSocketProvider: Component<{ children: JSX.Element }> = (props) => {
-	const { token } = useAuth();
+	const auth = useAuth();
+	const { getApi } = useApi();
+	const queryClient = useQueryClient();
 	const [socket, setSocket] = createSignal<WebSocket | null>(null);
 	const messageHandlers = new Set<(event: MessageEvent) => void>();
+	const pendingRequests = new Map<
+		string,
+		{
+			resolve: (payload: unknown) => void;
+			reject: (error: WsError) => void;
+		}
+	>();
+
+	// Last broadcast event received, so a reconnect can resume after it
+	let lastSeq: number | undefined;
+	let closed = false;
+	let reconnectAttempts = 0;
+	let reconnectTimeout: ReturnType<typeof setTimeout> | undefined;
+	let heartbeatInterval: ReturnType<typeof setInterval> | undefined;
 
 	const connect = () => {
 		const address = resolveAddress();
 		if (!address) throw new Error("API address not found");
-		const ws = new WebSocket(`ws://${address}/api/ws?token=${token}`);
+		const resume = lastSeq === undefined ? "" : `&resume_from=${lastSeq}`;
+		const ws = new WebSocket(
+			`ws://${address}/api/ws?token=${auth.token}${resume}`,
+		);
+		let opened = false;
+
+		ws.onopen = () => {
+			opened = true;
+			reconnectAttempts = 0;
+			setSocket(ws);
+			heartbeatInterval = setInterval(sendHeartbeat, HEARTBEAT_INTERVAL_MS);
+			// Presence changes are not replayed, so catch up on any missed while
+			// disconnected
+			queryClient.invalidateQueries({ queryKey: ["presence"] });
+			// Every new connection starts out active on the server
+			isIdle = false;
+			resetIdleTimer();
+		};
+		ws.onclose = async (event) => {
+			setSocket(null);
+			clearInterval(heartbeatInterval);
+			for (const { reject } of pendingRequests.values()) {
+				reject({ code: "internal", message: "Connection closed" });
+			}
+			pendingRequests.clear();
+			if (closed) return;
+
+			// An expired token is also why a connection would fail to open
+			if (event.code === WS_TOKEN_EXPIRED_CLOSE_CODE || !opened) {
+				await auth.refresh();
+			}
 
-		ws.onopen = () => setSocket(ws);
-		ws.onclose = () => setSocket(null);
+			const delay = Math.min(
+				1000 * 2 ** reconnectAttempts,
+				RECONNECT_MAX_DELAY_MS,
+			);
+			reconnectAttempts++;
+			reconnectTimeout = setTimeout(connect, delay);
+		};
 		ws.onerror = (error) => console.error("WebSocket error:", error);
 		ws.onmessage = (event) => {
+			const env: WsServerMessage = JSON.parse(event.data);
+			if (env.seq !== undefined) lastSeq = env.seq;
+			if (env.module === "system" && env.type === "resync_required") {
+				// Missed events are gone, so reload everything from the API
+				lastSeq = env.payload.seq;
+				queryClient.invalidateQueries();
+			}
+			if (changesRoots(env)) {
+				queryClient.invalidateQueries({ queryKey: ["roots"] });
+			}
+
+			if (env.module === "system" && env.id) {
+				const pending = pendingRequests.get(env.id);
+				pendingRequests.delete(env.id);
+				if (env.type === "ack") pending?.resolve(env.payload);
+				else if (env.type === "error") pending?.reject(env.payload);
+			}
+
 			messageHandlers.forEach((handler) => {
 				handler(event);
 			});
 		};
 	};
 
+	// The directory tree shows unread counts for every thread, so follow every
+	// tree and DM conversation the user can see
+	const roots = useQuery(() => ({
+		queryKey: ["roots"],
+		queryFn: () => getApi<DirectoryNode[]>("/directory"),
+	}));
+
+	// Roots followed on the current connection, since a new one starts out
+	// following nothing
+	let subscribedSocket: WebSocket | undefined;
+	let subscribedRoots = new Set<number>();
+	createEffect(() => {
+		const ws = socket();
+		const data = roots.data;
+		if (!ws || !data) return;
+		if (ws !== subscribedSocket) {
+			subscribedSocket = ws;
+			subscribedRoots = new Set();
+		}
+
+		const ids = new Set(data.map((root) => root.id));
+		for (const id of ids) {
+			if (subscribedRoots.has(id)) continue;
+			sendMessage({
+				module: "system",
+				type: "subscribe",
+				payload: { directory_id: id, subtree: true },
+			});
+		}
+		for (const id of subscribedRoots) {
+			if (ids.has(id)) continue;
+			sendMessage({
+				module: "system",
+				type: "unsubscribe",
+				payload: { directory_id: id, subtree: true },
+			});
+		}
+		subscribedRoots = ids;
+	});
+
+	const sendHeartbeat = () =>
+		sendMessage({ module: "system", type: "ping", payload: null });
+
 	const disconnect = () => {
+		closed = true;
+		clearTimeout(reconnectTimeout);
 		const ws = socket();
 		if (ws) ws.close();
 	};
 
-	onMount(() => connect());
-	onCleanup(() => disconnect());
+	// Keep the connection open past the expiry of the token it was opened with
+	createEffect(
+		on(
+			() => auth.token,
+			(token) => {
+				if (!token) return;
+				sendMessage({
+					module: "system",
+					type: "authenticate",
+					payload: { token },
+				});
+			},
+			{ defer: true },
+		),
+	);
+
+	let isIdle = false;
+	let idleTimeout: ReturnType<typeof setTimeout> | undefined;
+
+	const setIdle = (idle: boolean) => {
+		if (idle === isIdle) return;
+		isIdle = idle;
+		sendMessage({
+			module: "presence",
+			type: "set_idle",
+			payload: { idle },
+		});
+	};
+
+	const resetIdleTimer = () => {
+		if (idleTimeout) clearTimeout(idleTimeout);
+		idleTimeout = setTimeout(() => setIdle(true), IDLE_TIMEOUT_MS);
+	};
+
+	const handleActivity = () => {
+		setIdle(false);
+		resetIdleTimer();
+	};
+
+	const handleVisibilityChange = () => {
+		if (document.hidden) setIdle(true);
+		else handleActivity();
+	};
+
+	onMount(() => {
+		connect();
+		document.addEventListener("visibilitychange", handleVisibilityChange);
+		window.addEventListener("pointermove", handleActivity);
+		window.addEventListener("keydown", handleActivity);
+		onCleanup(() => {
+			document.removeEventListener(
+				"visibilitychange",
+				handleVisibilityChange,
+			);
+			window.removeEventListener("pointermove", handleActivity);
+			window.removeEventListener("keydown", handleActivity);
+		});
+	});
+	onCleanup(() => {
+		disconnect();
+		if (idleTimeout) clearTimeout(idleTimeout);
+	});
 
 	const onMessage = (handler: (event: MessageEvent) => void) => {
 		messageHandlers.add(handler);
@@ -63,11 +276,28 @@ const WebSocketProvider: Component<{ children: JSX.Element }> = (props) => {
 		if (ws) ws.send(JSON.stringify(message));
 	};
 
+	const request = <T,>(message: WsClientMessage) =>
+		new Promise<T>((resolve, reject) => {
+			const ws = socket();
+			if (!ws) {
+				reject({ code: "internal", message: "Not connected" });
+				return;
+			}
+
+			const id = crypto.randomUUID();
+			pendingRequests.set(id, {
+				resolve: (payload) => resolve(payload as T),
+				reject,
+			});
+			ws.send(JSON.stringify({ ...message, id }));
+		});
+
 	return (
 		<WebSocketContext.Provider
 			value={{
 				onMessage,
 				sendMessage,
+				request,
 			}}
 		>
 			{props.children}
@@ -27,27 +27,45 @@ const AddressField: Component = () => {
 
 const Login: Component = () => {
 	const navigate = useNavigate();
-	const { login, signup } = useAuth();
+	const { login, verifyMfa, signup } = useAuth();
 
 	const [segment, setSegment] = createSignal<"Login" | "Sign Up">("Login");
+	const [mfaRequired, setMfaRequired] = createSignal(false);
 
-	const [state, setState] = createStore({ username: "", password: "" });
+	const [state, setState] = createStore({
+		username: "",
+		password: "",
+		code: "",
+	});
 
 	const handleSubmit = async (e: Event) => {
 		e.preventDefault();
 		switch (segment()) {
-			case "Login":
-				if (
+			case "Login": {
+				if (mfaRequired()) {
+					if (state.code && (await verifyMfa(state.code))) {
+						navigate("/");
+					} else {
+						alert("Invalid code. Please try again.");
+					}
+					break;
+				}
+
+				const result =
 					state.username &&
 					state.password &&
-					(!isTauri() || getStorageItem<string>("address")) &&
-					(await login(state))
-				) {
+					(!isTauri() || getStorageItem<string>("address"))
+						? await login(state)
+						: "failed";
+				if (result === "success") {
 					navigate("/");
+				} else if (result === "mfa_required") {
+					setMfaRequired(true);
 				} else {
 					alert("Login failed. Please try again.");
 				}
 				break;
+			}
 			case "Sign Up":
 				if (
 					state.username &&
@@ -88,6 +106,13 @@ const Login: Component = () => {
 					value={state.password}
 					onInput={(e) => setState("password", e.currentTarget.value)}
 				/>
+				<Show when={mfaRequired() && segment() === "Login"}>
+					<Input
+						placeholder="Authentication or Recovery Code"
+						value={state.code}
+						onInput={(e) => setState("code", e.currentTarget.value)}
+					/>
+				</Show>
 				<Button type="submit" variant="suggested" text={segment()} />
 			</form>
 		</div>
@@ -13,17 +13,23 @@ import {
 	Suspense,
 } from "solid-js";
 import { createStore } from "solid-js/store";
-import type {
-	CreateMessage,
-	DirectoryNode,
-	Message,
-	WsServerMessage,
+import {
+	type Attachment,
+	attachmentUrl,
+	type CreateMessage,
+	type DirectoryNode,
+	type Message,
+	type ThreadPage,
+	type WsError,
+	type WsServerMessage,
 } from "../apiUtils.ts";
 import MessageSquareText from "../assets/message-square-text.svg";
+import Paperclip from "../assets/paperclip.svg";
 import Reply from "../assets/reply.svg";
 import SendHorizontal from "../assets/send-horizontal.svg";
 import X from "../assets/x.svg";
 import { useApi } from "../components/Api.tsx";
+import { useAuth } from "../components/Auth.tsx";
 import Avatar from "../components/Avatar.tsx";
 import Button from "../components/Button.tsx";
 import { useWebSocket } from "../components/WebSocket.tsx";
@@ -31,6 +37,7 @@ import { setStorageItem } from "../storageUtils.ts";
 
 const MESSAGE_GROUP_WINDOW_MS = 60 * 1000;
 const TYPING_TIMEOUT_MS = 3000;
+const ACKNOWLEDGE_EMOJI = "👍";
 
 interface MessagesState {
 	byId: Record<number, Message>;
@@ -127,10 +134,56 @@ const appendMessageToState = (
 	return { byId, groups: newGroups, messageCount };
 };
 
+const formatSize = (bytes: number) => {
+	if (bytes < 1024) return `${bytes} B`;
+	if (bytes < 1024 * 1024) return `${(bytes / 1024).toFixed(1)} KB`;
+	return `${(bytes / 1024 / 1024).toFixed(1)} MB`;
+};
+
+const AttachmentPreview: Component<{
+	attachment: Attachment;
+	token: string | null;
+}> = (props) => (
+	<a
+		href={attachmentUrl(props.attachment, props.token)}
+		target="_blank"
+		rel="noreferrer"
+	>
+		<Show
+			when={props.attachment.content_type.startsWith("image/")}
+			fallback={
+				<div class="flex gap-2 items-center px-3 py-2 rounded-xl bg-background-100 dark:bg-background-800">
+					<Paperclip />
+					<p class="truncate max-w-60">{props.attachment.filename}</p>
+					<p class="text-sm text-background-400 dark:text-background-500">
+						{formatSize(props.attachment.size)}
+					</p>
+				</div>
+			}
+		>
+			<img
+				class="max-h-60 rounded-xl"
+				src={attachmentUrl(props.attachment, props.token, true)}
+				alt={props.attachment.filename}
+				// Images that could not be previewed have no thumbnail
+				onError={(e) => {
+					e.currentTarget.src = attachmentUrl(
+						props.attachment,
+						props.token,
+					);
+				}}
+			/>
+		</Show>
+	</a>
+);
+
 const MessageGroup: Component<{
 	messagesById: Record<number, Message>;
 	groupIds: number[];
+	username: string | undefined;
+	token: string | null;
 	onMessageClick: (id: number) => void;
+	onReactionClick: (message: Message, emoji: string) => void;
 }> = (props) => {
 	const group = createMemo(() =>
 		props.groupIds.reduce((acc, id) => {
@@ -181,20 +234,82 @@ const MessageGroup: Component<{
 					<For each={group()}>
 						{(message) => (
 							<div class="group flex justify-between items-start gap-1 hover:bg-background-100 dark:hover:bg-background-800 rounded-sm">
-								<div
-									class="wrap-anywhere"
-									classList={mdClasses}
-									innerHTML={md.render(message.content)}
-								/>
-								<button
-									type="button"
-									class="hidden group-hover:block text-background-500 hover:text-background-600 dark:text-background-400 dark:hover:text-background-300 transition-colors duration-200 cursor-pointer"
-									onClick={() =>
-										props.onMessageClick(message.id)
-									}
-								>
-									<Reply />
-								</button>
+								<div class="flex flex-col gap-1">
+									<div
+										class="wrap-anywhere"
+										classList={mdClasses}
+										innerHTML={md.render(message.content)}
+									/>
+									<Show when={message.attachments?.length}>
+										<div class="flex flex-wrap gap-2 mb-1">
+											<For each={message.attachments}>
+												{(attachment) => (
+													<AttachmentPreview
+														attachment={attachment}
+														token={props.token}
+													/>
+												)}
+											</For>
+										</div>
+									</Show>
+									<Show when={message.reactions?.length}>
+										<div class="flex flex-wrap gap-1 mb-1">
+											<For each={message.reactions}>
+												{(reaction) => (
+													<button
+														type="button"
+														class="px-2 rounded-full text-sm bg-background-100 dark:bg-background-800 -outline-offset-1 outline-accent-500 cursor-pointer"
+														classList={{
+															"outline-1":
+																props.username !==
+																	undefined &&
+																reaction.usernames.includes(
+																	props.username,
+																),
+														}}
+														title={reaction.usernames.join(
+															", ",
+														)}
+														onClick={() =>
+															props.onReactionClick(
+																message,
+																reaction.emoji,
+															)
+														}
+													>
+														{reaction.emoji}{" "}
+														{reaction.count}
+													</button>
+												)}
+											</For>
+										</div>
+									</Show>
+								</div>
+								<div class="hidden group-hover:flex gap-1">
+									<Show when={message.deleted_at === null}>
+										<button
+											type="button"
+											class="cursor-pointer"
+											onClick={() =>
+												props.onReactionClick(
+													message,
+													ACKNOWLEDGE_EMOJI,
+												)
+											}
+										>
+											{ACKNOWLEDGE_EMOJI}
+										</button>
+									</Show>
+									<button
+										type="button"
+										class="text-background-500 hover:text-background-600 dark:text-background-400 dark:hover:text-background-300 transition-colors duration-200 cursor-pointer"
+										onClick={() =>
+											props.onMessageClick(message.id)
+										}
+									>
+										<Reply />
+									</button>
+								</div>
 							</div>
 						)}
 					</For>
@@ -249,8 +364,10 @@ const TypingIndicator: Component<{ users: string[] }> = (props) => {
 
 const Thread: Component = () => {
 	const params = useParams<{ id: string }>();
-	const { getApi } = useApi();
-	const { onMessage, sendMessage } = useWebSocket();
+	const { getApi, uploadApi } = useApi();
+	const auth = useAuth();
+	const { user } = auth;
+	const { onMessage, sendMessage, request } = useWebSocket();
 	const queryClient = useQueryClient();
 
 	const directoryNode = useQuery(() => ({
@@ -262,8 +379,8 @@ const Thread: Component = () => {
 	const messages = useQuery(() => ({
 		queryKey: ["thread", Number(params.id)],
 		queryFn: async () => {
-			const data = await getApi<Message[]>(`/thread/${params.id}`);
-			return buildMessagesState(data);
+			const data = await getApi<ThreadPage>(`/thread/${params.id}`);
+			return buildMessagesState(data.messages);
 		},
 		staleTime: Infinity,
 		gcTime: 1000 * 60 * 30,
@@ -288,6 +405,57 @@ const Thread: Component = () => {
 					);
 					break;
 				}
+				case "message_edited":
+				case "message_deleted": {
+					const message = env.payload;
+					// Deleting a message also removes its reactions and attachments
+					const keepReactions = env.type === "message_edited";
+
+					queryClient.setQueryData<MessagesState>(
+						["thread", message.directory_id],
+						(prev) => {
+							const existing = prev?.byId[message.id];
+							if (!prev || !existing) return prev;
+							return {
+								...prev,
+								byId: {
+									...prev.byId,
+									[message.id]: keepReactions
+										? {
+												...message,
+												reactions: existing.reactions,
+												attachments:
+													existing.attachments,
+											}
+										: message,
+								},
+							};
+						},
+					);
+					break;
+				}
+				case "reactions_updated": {
+					const payload = env.payload;
+
+					queryClient.setQueryData<MessagesState>(
+						["thread", payload.directory_id],
+						(prev) => {
+							const existing = prev?.byId[payload.message_id];
+							if (!prev || !existing) return prev;
+							return {
+								...prev,
+								byId: {
+									...prev.byId,
+									[payload.message_id]: {
+										...existing,
+										reactions: payload.reactions,
+									},
+								},
+							};
+						},
+					);
+					break;
+				}
 				case "user_typing": {
 					const payload = env.payload;
 					if (payload.thread_id !== Number(params.id)) return;
@@ -312,9 +480,14 @@ const Thread: Component = () => {
 
 	onCleanup(removeHandler);
 
+	let lastReadMessageId = 0;
+
 	createEffect((prevId: number | undefined) => {
 		const id = Number(params.id);
-		if (prevId && prevId !== id) setTypingUsers([]);
+		if (prevId && prevId !== id) {
+			setTypingUsers([]);
+			lastReadMessageId = 0;
+		}
 		setStorageItem("lastThread", id);
 		return id;
 	});
@@ -363,12 +536,34 @@ const Thread: Component = () => {
 		parent_id: null,
 	});
 
-	createEffect(() =>
+	const [pendingAttachments, setPendingAttachments] = createSignal<
+		Attachment[]
+	>([]);
+
+	createEffect(() => {
 		setNewMessage({
 			directory_id: Number(params.id),
 			parent_id: null,
-		}),
-	);
+		});
+		setPendingAttachments([]);
+	});
+
+	let fileInputRef: HTMLInputElement | undefined;
+	const handleFiles = (files: FileList | null) => {
+		for (const file of files ?? []) {
+			uploadApi<Attachment>("/attachments", file)
+				.then((attachment) =>
+					setPendingAttachments((prev) => [...prev, attachment]),
+				)
+				.catch((error: Error) =>
+					console.error(
+						`Failed to upload ${file.name}:`,
+						error.message,
+					),
+				);
+		}
+		if (fileInputRef) fileInputRef.value = "";
+	};
 
 	const replyTarget = () => {
 		const parentId = newMessage.parent_id;
@@ -376,21 +571,51 @@ const Thread: Component = () => {
 		return messages.data?.byId[parentId] ?? null;
 	};
 
+	const toggleReaction = (message: Message, emoji: string) => {
+		const username = user?.username;
+		if (username === undefined) return;
+
+		const reacted = message.reactions?.some(
+			(reaction) =>
+				reaction.emoji === emoji &&
+				reaction.usernames.includes(username),
+		);
+
+		sendMessage({
+			module: "messages",
+			type: reacted ? "remove_reaction" : "add_reaction",
+			payload: { message_id: message.id, emoji },
+		});
+	};
+
 	const handleSend = () => {
-		if (!newMessage.content.trim()) return;
+		const draftAttachments = pendingAttachments();
+		if (!newMessage.content.trim() && !draftAttachments.length) return;
 
 		stopTyping();
 
-		sendMessage({
+		const draft = {
+			...newMessage,
+			attachment_ids: draftAttachments.map((attachment) => attachment.id),
+		};
+		request<Message>({
 			module: "messages",
 			type: "create_message",
-			payload: newMessage,
+			payload: draft,
+		}).catch((error: WsError) => {
+			console.error("Failed to send message:", error.message);
+			// Give the draft back unless something new has been typed since
+			if (!newMessage.content) setNewMessage(draft);
+			if (!pendingAttachments().length) {
+				setPendingAttachments(draftAttachments);
+			}
 		});
 
 		setNewMessage({
 			content: "",
 			parent_id: null,
 		});
+		setPendingAttachments([]);
 
 		if (inputRef) inputRef.style.height = "auto";
 	};
@@ -431,6 +656,26 @@ const Thread: Component = () => {
 		}
 	});
 
+	const markRead = () => {
+		const latestId = messages.data?.groups.at(-1)?.at(-1);
+		if (
+			latestId === undefined ||
+			latestId <= lastReadMessageId ||
+			!scrollState.isBottom ||
+			!document.hasFocus()
+		)
+			return;
+
+		lastReadMessageId = latestId;
+		sendMessage({
+			module: "messages",
+			type: "mark_read",
+			payload: { thread_id: Number(params.id), message_id: latestId },
+		});
+	};
+
+	createEffect(markRead);
+
 	let inputRef: HTMLTextAreaElement | undefined;
 	const handleGlobalKeydown = (e: KeyboardEvent) => {
 		if (document.activeElement === inputRef) return;
@@ -453,9 +698,11 @@ const Thread: Component = () => {
 
 	onMount(() => {
 		document.addEventListener("keydown", handleGlobalKeydown);
-		onCleanup(() =>
-			document.removeEventListener("keydown", handleGlobalKeydown),
-		);
+		window.addEventListener("focus", markRead);
+		onCleanup(() => {
+			document.removeEventListener("keydown", handleGlobalKeydown);
+			window.removeEventListener("focus", markRead);
+		});
 	});
 
 	return (
@@ -494,10 +741,13 @@ const Thread: Component = () => {
 									<MessageGroup
 										messagesById={messages.data?.byId ?? {}}
 										groupIds={groupIds}
+										username={user?.username}
+										token={auth.token}
 										onMessageClick={(id) => {
 											setNewMessage("parent_id", id);
 											if (inputRef) inputRef.focus();
 										}}
+										onReactionClick={toggleReaction}
 									/>
 								)}
 							</For>
@@ -522,9 +772,54 @@ const Thread: Component = () => {
 									</button>
 								</div>
 							</Show>
+							<Show when={pendingAttachments().length}>
+								<div class="flex flex-wrap gap-1 m-1 mb-0">
+									<For each={pendingAttachments()}>
+										{(attachment) => (
+											<div class="flex gap-1 items-center pl-3 pr-1 py-1 bg-background-50 dark:bg-background-700 rounded-xl">
+												<p class="truncate max-w-40 text-sm">
+													{attachment.filename}
+												</p>
+												<button
+													type="button"
+													class="text-background-500 hover:text-background-600 dark:text-background-400 dark:hover:text-background-300 transition-colors duration-200 cursor-pointer"
+													onClick={() =>
+														setPendingAttachments(
+															(prev) =>
+																prev.filter(
+																	(pending) =>
+																		pending.id !==
+																		attachment.id,
+																),
+														)
+													}
+												>
+													<X />
+												</button>
+											</div>
+										)}
+									</For>
+								</div>
+							</Show>
 							<div class="flex items-end">
+								<input
+									class="hidden"
+									type="file"
+									multiple
+									ref={fileInputRef}
+									onChange={(e) =>
+										handleFiles(e.currentTarget.files)
+									}
+								/>
+								<Button
+									className="m-2 mr-0"
+									variant="flat"
+									title="Attach files"
+									icon={<Paperclip />}
+									onClick={() => fileInputRef?.click()}
+								/>
 								<textarea
-									class="grow p-4 rounded-l-2xl outline-0 placeholder-background-400 dark:placeholder-background-500 resize-none max-h-48"
+									class="grow p-4 outline-0 placeholder-background-400 dark:placeholder-background-500 resize-none max-h-48"
 									rows={1}
 									ref={inputRef}
 									placeholder={`Message ${threadNode()?.name}`}